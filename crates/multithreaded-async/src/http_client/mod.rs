@@ -73,7 +73,7 @@ impl HttpClient {
             host
         );
 
-        tls_conn.write(request.as_bytes()).await?;
+        tls_conn.write_all(request.as_bytes()).await?;
         let mut body: String = String::default();
         if tls_conn.read_to_string(&mut body).await? == 0 { 
             return Err(Box::new(EmptyBody));
@@ -85,12 +85,9 @@ impl HttpClient {
 
 
 pub mod errors {
-    use std::cell::RefCell;
     use std::fmt::{self};
     use std::error::Error;
 
-    use super::Response;
-
     #[derive(Debug)]
     pub struct EmptyBody;
 
@@ -49,6 +49,7 @@ async fn main() {
 This code below is just to learn
  */
 #[cfg(test)]
+#[allow(dead_code)]
 #[tokio::main]
 async fn test_future(){
     use std::{sync::Arc, future::Future, net::TcpStream, pin::Pin, task::{Context, Poll::{self, Pending}}};
@@ -1,6 +1,9 @@
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
-use syn::{parse::ParseStream, ItemStruct};
+use syn::{parse::ParseStream, DeriveInput, ItemStruct};
+
+mod template;
+mod whois_record;
 
 struct Attrs {
     args: syn::punctuated::Punctuated<syn::MetaNameValue, syn::Token![,]>,
@@ -35,7 +38,7 @@ pub fn scrape_website(args: TokenStream, item: TokenStream) -> TokenStream {
         ..
     } = syn::parse::<ItemStruct>(item).unwrap();
 
-    let fields_iter = fields.iter().map(|field| field);
+    let fields_iter = fields.iter();
     let attrs_iter = attrs.iter().filter(|a| !a.meta.path().is_ident("scrape_website"));
     quote! {
         #[derive(Default)]
@@ -56,3 +59,46 @@ pub fn scrape_website(args: TokenStream, item: TokenStream) -> TokenStream {
         
     }.into()
 }
+
+/// Generates a `webapp::parser::WhoisRecord` implementation, so the struct can be filled by the WHOIS parser.
+///
+/// Every field is matched (case insensitive) on its name with underscores replaced by spaces,
+/// unless configured otherwise:
+/// - `#[whois(key = "Registrar IANA ID")]` matches on the given key
+/// - `#[whois(alias = "Registrar ID")]` matches on an additional key, can be repeated
+/// - `#[whois(skip)]` never fills the field
+///
+/// The value is converted based on the field type through `webapp::parser::WhoisField`.
+#[proc_macro_derive(WhoisRecord, attributes(whois))]
+pub fn derive_whois_record(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as DeriveInput);
+    whois_record::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Renders a minijinja template, checked at compile time.
+///
+/// ```ignore
+/// let page: Result<String, AppError> = template!(env, "error.html", {
+///     text => "not found",
+///     correlation_id => id,
+/// }, error = AppError);
+/// ```
+///
+/// The template is looked up in the `views` directory of the calling crate and compilation fails when
+/// it doesn't exist, has a syntax error, or uses a variable that isn't supplied. Templates it pulls
+/// in through `{% extends %}` or `{% include %}` are checked as well. Trailing options:
+/// - `dir = "templates"` looks up templates relative to another directory of the crate
+/// - `error = Type` converts the `minijinja::Error` with `From`, otherwise it is returned as is
+/// - `globals = [t, asset]` names functions and globals registered on the environment
+///
+/// The first argument is anything with a minijinja `get_template` method, usually an `Environment`.
+/// The calling crate has to depend on `minijinja`.
+#[proc_macro]
+pub fn template(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as template::TemplateInput);
+    template::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,156 @@
+use std::{collections::{BTreeSet, HashSet}, path::{Path, PathBuf}};
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{
+    braced, bracketed,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Expr, Ident, LitStr, Token, Type,
+};
+
+/// Names minijinja provides itself, never supplied by the caller
+const BUILTINS: &[&str] = &["range", "dict", "namespace", "debug", "loop", "self", "super", "caller", "varargs", "kwargs", "true", "false", "none"];
+
+/// `key => value`
+struct Variable {
+    key: Ident,
+    value: Expr,
+}
+
+impl Parse for Variable {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<Token![=>]>()?;
+        Ok(Variable { key, value: input.parse()? })
+    }
+}
+
+pub struct TemplateInput {
+    env: Expr,
+    name: LitStr,
+    variables: Punctuated<Variable, Token![,]>,
+    variables_span: Span,
+    dir: Option<LitStr>,
+    error: Option<Type>,
+    globals: Vec<Ident>,
+}
+
+impl Parse for TemplateInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let env = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+
+        let content;
+        let brace = braced!(content in input);
+        let variables = content.parse_terminated(Variable::parse, Token![,])?;
+
+        let mut parsed = TemplateInput { env, name, variables, variables_span: brace.span.join(), dir: None, error: None, globals: Vec::new() };
+        while input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let option: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match option.to_string().as_str() {
+                "dir" => parsed.dir = Some(input.parse()?),
+                "error" => parsed.error = Some(input.parse()?),
+                "globals" => {
+                    let content;
+                    bracketed!(content in input);
+                    parsed.globals.extend(Punctuated::<Ident, Token![,]>::parse_terminated(&content)?);
+                }
+                _ => return Err(syn::Error::new(option.span(), "expected `dir`, `error` or `globals`")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Templates another template pulls in with `{% extends %}` or `{% include %}`, only string literals are followed
+fn referenced_templates(source: &str) -> Vec<String> {
+    source.split("{%").skip(1)
+        .filter_map(|tag| {
+            let tag = tag.split("%}").next()?.trim_matches(['-', '+']).trim();
+            let (keyword, rest) = tag.split_once(char::is_whitespace)?;
+            if !matches!(keyword, "extends" | "include") {
+                return None;
+            }
+            let rest = rest.trim_start();
+            let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+            Some(rest[1..].split(quote).next()?.to_owned())
+        })
+        .collect()
+}
+
+/// Reads the template and everything it pulls in, returns the files read and their undeclared variables
+fn inspect(dir: &Path, name: &str, span: Span) -> syn::Result<(Vec<PathBuf>, BTreeSet<String>)> {
+    let mut files = Vec::new();
+    let mut undeclared = BTreeSet::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![name.to_owned()];
+
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        let path = dir.join(&name);
+        let source = std::fs::read_to_string(&path)
+            .map_err(|err| syn::Error::new(span, format!("template `{name}` not found in {}: {err}", dir.display())))?;
+
+        let env = minijinja::Environment::new();
+        let template = env.template_from_named_str(&name, &source)
+            .map_err(|err| syn::Error::new(span, format!("template `{name}` is invalid: {err}")))?;
+
+        undeclared.extend(template.undeclared_variables(false));
+        pending.extend(referenced_templates(&source));
+        files.push(path);
+    }
+    Ok((files, undeclared))
+}
+
+pub fn expand(input: TemplateInput) -> syn::Result<TokenStream> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").map_err(|_| syn::Error::new(input.name.span(), "CARGO_MANIFEST_DIR is not set"))?;
+    let dir = Path::new(&manifest_dir).join(input.dir.as_ref().map_or("views".into(), LitStr::value));
+    let (files, undeclared) = inspect(&dir, &input.name.value(), input.name.span())?;
+
+    let supplied: HashSet<String> = input.variables.iter().map(|v| v.key.to_string())
+        .chain(input.globals.iter().map(Ident::to_string))
+        .collect();
+    let missing: Vec<String> = undeclared.into_iter()
+        .filter(|name| !supplied.contains(name) && !BUILTINS.contains(&name.as_str()))
+        .map(|name| format!("`{name}`"))
+        .collect();
+    if !missing.is_empty() {
+        let msg = format!("template `{}` uses {} which {} not supplied", input.name.value(), missing.join(", "), if missing.len() == 1 { "is" } else { "are" });
+        return Err(syn::Error::new(input.variables_span, msg));
+    }
+
+    // recompile when a template changes
+    let files = files.iter().map(|path| path.to_string_lossy().into_owned());
+    let TemplateInput { env, name, variables, error, .. } = input;
+    let keys = variables.iter().map(|v| &v.key);
+    let values = variables.iter().map(|v| &v.value);
+    let map_err = error.map(|error| quote! {
+        .map_err(<#error as ::core::convert::From<::minijinja::Error>>::from)
+    });
+
+    Ok(quote! {
+        {
+            #(const _: &[u8] = include_bytes!(#files);)*
+            let context = ::minijinja::context! { #(#keys => #values,)* };
+            (#env).get_template(#name)
+                .and_then(|template| template.render(context))
+                #map_err
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referenced_templates() {
+        let source = r#"{%- extends "layout.html" -%}{% block body %}{% include 'partials/nav.html' %}{% include name %}{% endblock %}"#;
+        assert_eq!(referenced_templates(source), ["layout.html", "partials/nav.html"]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_quote, spanned::Spanned, Data, DeriveInput, Fields, LitStr};
+
+/// Keys a single field is matched on
+struct FieldKeys {
+    keys: Vec<LitStr>,
+    skip: bool,
+}
+
+impl FieldKeys {
+    fn from_field(field: &syn::Field) -> syn::Result<FieldKeys> {
+        let ident = field.ident.as_ref().expect("named field");
+        let mut key = None;
+        let mut aliases = Vec::new();
+        let mut skip = false;
+
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("whois")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("key") {
+                    key = Some(meta.value()?.parse::<LitStr>()?);
+                } else if meta.path.is_ident("alias") {
+                    aliases.push(meta.value()?.parse::<LitStr>()?);
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else {
+                    return Err(meta.error("expected `key`, `alias` or `skip`"));
+                }
+                Ok(())
+            })?;
+        }
+
+        // without an explicit key, `registrar_iana_id` is matched on "registrar iana id"
+        let key = key.unwrap_or_else(|| LitStr::new(&ident.to_string().replace('_', " "), ident.span()));
+        let keys = std::iter::once(key).chain(aliases)
+            .map(|k| LitStr::new(&k.value().trim().to_lowercase(), k.span()))
+            .collect();
+
+        Ok(FieldKeys { keys, skip })
+    }
+}
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new(input.span(), "WhoisRecord requires named fields")),
+        },
+        _ => return Err(syn::Error::new(input.span(), "WhoisRecord can only be derived for structs")),
+    };
+
+    let mut seen: HashMap<String, &syn::Ident> = HashMap::new();
+    let mut arms = Vec::new();
+    let mut bounds = Vec::new();
+
+    for field in fields {
+        let keys = FieldKeys::from_field(field)?;
+        if keys.skip {
+            continue;
+        }
+
+        let ident = field.ident.as_ref().expect("named field");
+        for key in &keys.keys {
+            if let Some(other) = seen.insert(key.value(), ident) {
+                return Err(syn::Error::new(key.span(), format!("whois key \"{}\" is already used by `{other}`", key.value())));
+            }
+        }
+
+        let ty = &field.ty;
+        let patterns = &keys.keys;
+        bounds.push(quote!(#ty: ::webapp::parser::WhoisField<'__whois>));
+        arms.push(quote! {
+            #(#patterns)|* => ::webapp::parser::WhoisField::set_value(&mut self.#ident, value)?,
+        });
+    }
+
+    let ident = &input.ident;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+
+    let mut generics = input.generics.clone();
+    generics.params.insert(0, parse_quote!('__whois));
+    let where_clause = generics.make_where_clause();
+    where_clause.predicates.push(parse_quote!(Self: ::std::default::Default));
+    for bound in bounds {
+        where_clause.predicates.push(parse_quote!(#bound));
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::webapp::parser::WhoisRecord<'__whois> for #ident #ty_generics #where_clause {
+            fn apply_field(&mut self, key: &str, value: &'__whois str) -> ::std::result::Result<(), ::std::boxed::Box<dyn ::std::error::Error>> {
+                match key.trim().to_lowercase().as_str() {
+                    #(#arms)*
+                    _ => {}
+                }
+                ::std::result::Result::Ok(())
+            }
+        }
+    })
+}
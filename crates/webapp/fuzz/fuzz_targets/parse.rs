@@ -0,0 +1,18 @@
+//! cargo fuzz run parse -- -max_len=65536
+//!
+//! Seed the corpus with the registry fixtures: cp ../tests/fixtures/whois/*.txt corpus/parse/
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webapp::parser::{normalize::normalize, Parser};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(raw) = std::str::from_utf8(data) else { return };
+
+    let parser = Parser::new();
+    let _ = parser.parse_borrowed(raw);
+
+    let normalized = normalize(raw);
+    assert_eq!(normalize(&normalized.body).body, normalized.body);
+    let _ = parser.parse(normalized.body);
+});
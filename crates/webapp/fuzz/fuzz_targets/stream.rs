@@ -0,0 +1,19 @@
+//! cargo fuzz run stream
+//!
+//! The first byte picks the chunk size, so line splits across chunks get exercised.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webapp::parser::StreamParser;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((chunk, data)) = data.split_first() else { return };
+
+    let mut stream = StreamParser::new();
+    for chunk in data.chunks(usize::from(*chunk).max(1)) {
+        if stream.feed(chunk).is_err() {
+            return;
+        }
+    }
+    let _ = stream.finish();
+});
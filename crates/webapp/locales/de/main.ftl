@@ -0,0 +1,13 @@
+site-name = webapp
+footer-text = Ausgeliefert von webapp
+error-title = Fehler { $status }
+error-heading = Etwas ist schiefgelaufen
+error-reference = Referenz: { $id }
+login-title = Anmelden
+login-username = Benutzername
+login-password = Passwort
+login-submit = Anmelden
+login-failed = Unbekannter Benutzername oder falsches Passwort
+login-expired = Die Sitzung ist abgelaufen, bitte erneut versuchen
+login-signed-in = Angemeldet als { $name }
+logout-submit = Abmelden
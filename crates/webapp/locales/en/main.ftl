@@ -0,0 +1,13 @@
+site-name = webapp
+footer-text = Served by webapp
+error-title = Error { $status }
+error-heading = Something went wrong
+error-reference = Reference: { $id }
+login-title = Sign in
+login-username = Username
+login-password = Password
+login-submit = Sign in
+login-failed = Unknown username or password
+login-expired = Your session expired, please try again
+login-signed-in = Signed in as { $name }
+logout-submit = Sign out
@@ -0,0 +1,356 @@
+//! Bulk WHOIS archives
+//!
+//! An archive holds parsed [WhoisInformation] next to the raw response it was parsed from,
+//! either as JSON Lines (`.jsonl`, one record per line) or CSV (`.csv`, one flat row per record).
+//! [import_dir] turns a directory of raw `.txt` responses into archive records.
+//!
+//! ### Example
+//! ```no_run
+//! use std::fs::File;
+//! use webapp::archive::{import_dir_to, ArchiveFormat, ArchiveWriter};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut writer = ArchiveWriter::new(File::create("whois.jsonl").unwrap(), ArchiveFormat::JsonLines);
+//!     let report = import_dir_to("dumps/", 8, &mut writer).await.unwrap();
+//!
+//!     for failure in report.failures {
+//!         println!("{}: {}", failure.path.display(), failure.error);
+//!     }
+//! }
+//! ```
+use std::{io::{BufRead, BufReader, Lines, Read, Write}, path::{Path, PathBuf}, sync::Arc};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{mpsc, Semaphore};
+use crate::parser::{Dnssec, DsRecord, NameServer, NameServers, Parser, WhoisInformation};
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("archive I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid JSON Lines record: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("invalid CSV record: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("invalid {column} column: {err}")]
+    InvalidColumn{column: &'static str, err: String},
+
+    #[error("could not parse WHOIS response: {0}")]
+    Parse(String),
+
+    #[error("unknown archive format, expected a .jsonl or .csv file: {0}")]
+    UnknownFormat(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    JsonLines,
+    Csv,
+}
+
+impl ArchiveFormat {
+    /// Picks the format from the file extension
+    pub fn from_path(path: &Path) -> Result<ArchiveFormat, ArchiveError> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("ndjson") => Ok(ArchiveFormat::JsonLines),
+            Some("csv") => Ok(ArchiveFormat::Csv),
+            _ => Err(ArchiveError::UnknownFormat(path.to_owned())),
+        }
+    }
+}
+
+/// Parsed WHOIS information with the raw response it came from
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+    /// Where the response came from, the file name for imported responses
+    pub source: String,
+    pub information: WhoisInformation,
+    pub raw: String,
+}
+
+/// Flat CSV representation of an [ArchiveRecord].
+///
+/// Name servers are written as `host ip ip; host`, DS records as `key_tag algorithm digest_type digest; ...`
+/// and domain statuses as `status; status`.
+#[derive(Serialize, Deserialize)]
+struct CsvRow {
+    source: String,
+    domain_name: Option<String>,
+    registry_domain_id: Option<String>,
+    registrar_whois_server: Option<String>,
+    registrar_url: Option<String>,
+    updated_date: Option<DateTime<Utc>>,
+    creation_date: Option<DateTime<Utc>>,
+    registry_expiry_date: Option<DateTime<Utc>>,
+    registrar: Option<String>,
+    registrar_iana_id: Option<String>,
+    registrar_abuse_email_contact: Option<String>,
+    registrar_abuse_phone_contact: Option<String>,
+    domain_status: Option<String>,
+    registrant_organization: Option<String>,
+    name_servers: Option<String>,
+    dnssec: Option<String>,
+    ds_records: Option<String>,
+    raw: String,
+}
+
+impl CsvRow {
+    fn from_record(record: &ArchiveRecord) -> CsvRow {
+        let info = &record.information;
+        let name_servers = info.name_servers.as_ref().map(|name_servers| {
+            name_servers.iter()
+                .map(|ns| std::iter::once(ns.host.clone()).chain(ns.glue.iter().map(|ip| ip.to_string())).collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>()
+                .join("; ")
+        });
+        let dnssec = info.dnssec.as_ref().map(|dnssec| match dnssec.signed {
+            Some(true) => "signed".to_owned(),
+            Some(false) => "unsigned".to_owned(),
+            None => "unknown".to_owned(),
+        });
+        let ds_records = info.dnssec.as_ref().filter(|dnssec| !dnssec.ds_records.is_empty()).map(|dnssec| {
+            dnssec.ds_records.iter()
+                .map(|ds| format!("{} {} {} {}", ds.key_tag, ds.algorithm, ds.digest_type, ds.digest))
+                .collect::<Vec<_>>()
+                .join("; ")
+        });
+
+        CsvRow {
+            source: record.source.clone(),
+            domain_name: info.domain_name.clone(),
+            registry_domain_id: info.registry_domain_id.clone(),
+            registrar_whois_server: info.registrar_whois_server.clone(),
+            registrar_url: info.registrar_url.clone(),
+            updated_date: info.updated_date,
+            creation_date: info.creation_date,
+            registry_expiry_date: info.registry_expirity_date,
+            registrar: info.registrar.clone(),
+            registrar_iana_id: info.registrar_iana_id.clone(),
+            registrar_abuse_email_contact: info.registrar_abuse_email_contact.clone(),
+            registrar_abuse_phone_contact: info.registrar_abuse_phone_contact.clone(),
+            domain_status: info.domain_status.as_ref().map(|statuses| statuses.join("; ")),
+            registrant_organization: info.registrant_organization.clone(),
+            name_servers,
+            dnssec,
+            ds_records,
+            raw: record.raw.clone(),
+        }
+    }
+
+    fn into_record(self) -> Result<ArchiveRecord, ArchiveError> {
+        let name_servers = self.name_servers.map(|column| {
+            let mut name_servers = NameServers::default();
+            for ns in column.split(';').filter(|ns| !ns.trim().is_empty()) {
+                name_servers.push(NameServer::parse(ns).map_err(|err| ArchiveError::InvalidColumn { column: "name_servers", err: err.to_string() })?);
+            }
+            Ok::<_, ArchiveError>(name_servers)
+        }).transpose()?;
+
+        let ds_records = self.ds_records.unwrap_or_default().split(';')
+            .filter(|ds| !ds.trim().is_empty())
+            .map(|ds| DsRecord::parse(ds).map_err(|err| ArchiveError::InvalidColumn { column: "ds_records", err: err.to_string() }))
+            .collect::<Result<Vec<_>, _>>()?;
+        let dnssec = self.dnssec.map(|signed| Dnssec {
+            signed: match signed.as_str() {
+                "signed" => Some(true),
+                "unsigned" => Some(false),
+                _ => None,
+            },
+            ds_records,
+        });
+
+        Ok(ArchiveRecord {
+            source: self.source,
+            information: WhoisInformation {
+                domain_name: self.domain_name,
+                registry_domain_id: self.registry_domain_id,
+                registrar_whois_server: self.registrar_whois_server,
+                registrar_url: self.registrar_url,
+                updated_date: self.updated_date,
+                creation_date: self.creation_date,
+                registry_expirity_date: self.registry_expiry_date,
+                registrar: self.registrar,
+                registrar_iana_id: self.registrar_iana_id,
+                registrar_abuse_email_contact: self.registrar_abuse_email_contact,
+                registrar_abuse_phone_contact: self.registrar_abuse_phone_contact,
+                domain_status: self.domain_status.map(|column| column.split(';').map(|status| status.trim().to_owned()).filter(|status| !status.is_empty()).collect()),
+                registrant_organization: self.registrant_organization,
+                name_servers,
+                dnssec,
+            },
+            raw: self.raw,
+        })
+    }
+}
+
+enum Writer<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+/// Writes [ArchiveRecord]s one at a time
+pub struct ArchiveWriter<W: Write> {
+    inner: Writer<W>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W, format: ArchiveFormat) -> ArchiveWriter<W> {
+        let inner = match format {
+            ArchiveFormat::JsonLines => Writer::JsonLines(writer),
+            ArchiveFormat::Csv => Writer::Csv(Box::new(csv::Writer::from_writer(writer))),
+        };
+        ArchiveWriter { inner }
+    }
+
+    pub fn write(&mut self, record: &ArchiveRecord) -> Result<(), ArchiveError> {
+        match &mut self.inner {
+            Writer::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            Writer::Csv(writer) => writer.serialize(CsvRow::from_record(record))?,
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ArchiveError> {
+        match &mut self.inner {
+            Writer::JsonLines(writer) => writer.flush()?,
+            Writer::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+enum Reader<R: Read> {
+    JsonLines(Lines<BufReader<R>>),
+    Csv(csv::DeserializeRecordsIntoIter<R, CsvRow>),
+}
+
+/// Reads [ArchiveRecord]s one at a time, the archive is never loaded as a whole
+pub struct ArchiveReader<R: Read> {
+    inner: Reader<R>,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(reader: R, format: ArchiveFormat) -> ArchiveReader<R> {
+        let inner = match format {
+            ArchiveFormat::JsonLines => Reader::JsonLines(BufReader::new(reader).lines()),
+            ArchiveFormat::Csv => Reader::Csv(csv::Reader::from_reader(reader).into_deserialize()),
+        };
+        ArchiveReader { inner }
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = Result<ArchiveRecord, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            Reader::JsonLines(lines) => loop {
+                match lines.next()? {
+                    Ok(line) if line.trim().is_empty() => continue,
+                    Ok(line) => return Some(serde_json::from_str(&line).map_err(ArchiveError::from)),
+                    Err(err) => return Some(Err(err.into())),
+                }
+            },
+            Reader::Csv(rows) => Some(rows.next()?.map_err(ArchiveError::from).and_then(CsvRow::into_record)),
+        }
+    }
+}
+
+/// Result of importing a single raw response
+#[derive(Debug)]
+pub struct ImportFailure {
+    pub path: PathBuf,
+    pub error: ArchiveError,
+}
+
+/// Outcome of [import_dir_to]
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub failures: Vec<ImportFailure>,
+}
+
+/// Parses every `.txt` file in `dir` with up to `workers` files in flight.
+///
+/// Results are sent as soon as a file is parsed, so they arrive in no particular order.
+/// A file that can't be read or parsed is reported as an [ImportFailure] and doesn't stop the import.
+///
+/// # Panics
+///
+/// Outside of a Tokio runtime
+pub fn import_dir(dir: impl Into<PathBuf>, workers: usize) -> mpsc::Receiver<Result<ArchiveRecord, ImportFailure>> {
+    let dir = dir.into();
+    let (send, recv) = mpsc::channel(workers.max(1));
+    let permits = Arc::new(Semaphore::new(workers.max(1)));
+
+    tokio::spawn(async move {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                let _ = send.send(Err(ImportFailure { path: dir, error: err.into() })).await;
+                return;
+            }
+        };
+
+        loop {
+            let path = match entries.next_entry().await {
+                Ok(Some(entry)) => entry.path(),
+                Ok(None) => break,
+                Err(err) => {
+                    let _ = send.send(Err(ImportFailure { path: dir.clone(), error: err.into() })).await;
+                    break;
+                }
+            };
+            if path.extension().is_none_or(|ext| ext != "txt") {
+                continue;
+            }
+
+            let Ok(permit) = permits.clone().acquire_owned().await else { break };
+            let send = send.clone();
+            tokio::spawn(async move {
+                let result = import_file(&path).await.map_err(|error| ImportFailure { path, error });
+                let _ = send.send(result).await;
+                drop(permit);
+            });
+        }
+    });
+
+    recv
+}
+
+async fn import_file(path: &Path) -> Result<ArchiveRecord, ArchiveError> {
+    let raw = tokio::fs::read_to_string(path).await?;
+    let source = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+
+    // parsing is CPU bound, keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let information = Parser::new().parse(raw.clone()).map_err(|err| ArchiveError::Parse(err.to_string()))?;
+        Ok(ArchiveRecord { source, information, raw })
+    }).await.map_err(|err| ArchiveError::Parse(err.to_string()))?
+}
+
+/// Imports every `.txt` file in `dir` into the archive, see [import_dir]
+pub async fn import_dir_to<W: Write>(dir: impl Into<PathBuf>, workers: usize, writer: &mut ArchiveWriter<W>) -> Result<ImportReport, ArchiveError> {
+    let mut results = import_dir(dir, workers);
+    let mut report = ImportReport::default();
+
+    while let Some(result) = results.recv().await {
+        match result {
+            Ok(record) => {
+                writer.write(&record)?;
+                report.imported += 1;
+            }
+            Err(failure) => report.failures.push(failure),
+        }
+    }
+    writer.flush()?;
+    Ok(report)
+}
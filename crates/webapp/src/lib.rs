@@ -3,35 +3,45 @@
 //!
 //! Enable the 'parser' flag if you want to use the parser.
 //! Everything related to the parser can be found at [parser]
+//!
+//! The 'archive' flag adds reading and writing of bulk WHOIS archives, see [archive]
 use axum::async_trait;
+// lets the WhoisRecord derive refer to `::webapp` from inside this crate as well
+extern crate self as webapp;
 // use proc_macro::TokenStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use std::future::Future;
 
 #[cfg(feature = "parser")]
 pub mod parser;
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod macros;
+pub mod server;
+
+pub use macros::template;
 
 
 #[derive(Clone)]
 /// Configuration for your WHOIS instance
 pub struct WhoisOpt {
-    whois_server: &'static str,
-    domain2lookup: &'static str
+    pub whois_server: &'static str,
+    pub domain2lookup: &'static str
 }
 
 #[derive(Clone)]
 /// Whois instance, used for querying a domain to a specific WHOIS server for WHOIS data.
 ///
 /// ### Example
-/// ```rust
-/// use crate::{Whois, WhoisOpt, WhoisResolver};
+/// ```rust,no_run
+/// # use webapp::{Whois, WhoisOpt, WhoisResolver};
+/// # async fn run() {
 /// let client = Whois::new(WhoisOpt{
 /// whois_server: "whois.iana.org:43", 
 /// domain2lookup: "simpaix.net"
 /// });
 /// let res = client.query().await.expect("expected a response");
+/// # }
 /// ```
 pub struct Whois{
     target: WhoisOpt
@@ -44,7 +54,8 @@ pub trait WhoisResolver: Sized {
     /// Creates a new whois instance and configures the target
     /// 
     /// ### Example
-    /// ```
+    /// ```no_run
+    /// # use webapp::{Whois, WhoisOpt, WhoisResolver};
     /// #[tokio::main]
     /// async fn main() {
     ///     let client = Whois::new(WhoisOpt{
@@ -53,7 +64,7 @@ pub trait WhoisResolver: Sized {
     ///     });
     /// }```
     /// 
-    fn new(opt: WhoisOpt) -> Whois;
+    fn new(opt: WhoisOpt) -> Self;
     
     /// Queries the WHOIS server and retrieves domain information.
     /// Returns WHOIS information as a string.
@@ -61,7 +72,8 @@ pub trait WhoisResolver: Sized {
     /// So that you can use any arbitrary parser.
     /// 
     /// ### Example
-    /// ```
+    /// ```no_run
+    /// # use webapp::{parser, Whois, WhoisOpt, WhoisResolver};
     /// #[tokio::main]
     /// async fn main() {
     ///     let client = Whois::new(WhoisOpt{
@@ -72,7 +84,7 @@ pub trait WhoisResolver: Sized {
     ///
     ///     let parser = parser::Parser::new();
     ///     let info = parser.parse(res).unwrap();
-    ///     println!("{}{}", info.creation_date.unwrap().format("%d/%m/%Y %H:%M") ,info.domain_status.unwrap()); // info.registry_domain_id , etc etc
+    ///     println!("{}{}", info.creation_date.unwrap().format("%d/%m/%Y %H:%M") ,info.domain_status.unwrap().join(", ")); // info.registry_domain_id , etc etc
     /// }```
     async fn query(&self) -> Result<String, Self::Error>;
 }
@@ -81,39 +93,108 @@ pub trait WhoisResolver: Sized {
 impl WhoisResolver for Whois {
     type Error = Box<dyn std::error::Error>;
 
-    fn new(opt: WhoisOpt) -> Whois {
+    fn new(opt: WhoisOpt) -> Self {
         Whois{target: opt}
     }
     
     async fn query(&self) -> Result<String, Self::Error> {
+        let main_server = self.referral().await?;
+        Ok(Whois::lookup(&main_server, self.target.domain2lookup).await?)
+    }
+}
+
+impl Whois {
+    /// Same as [WhoisResolver::query], but parses the WHOIS information while it is being received
+    /// instead of buffering the whole response first.
+    #[cfg(feature = "parser")]
+    pub async fn query_parsed(&self) -> Result<parser::WhoisInformation, Box<dyn std::error::Error>> {
+        let main_server = self.referral().await?;
+        let conn = Whois::connect(&main_server, self.target.domain2lookup).await?;
+        parser::Parser::new().parse_reader(conn).await
+    }
+
+    /// Follows the referral chain down to the registrar and parses every reply on the way.
+    ///
+    /// The replies are ordered from registry to registrar, combine them with [parser::merge::MergePolicy::merge].
+    /// Only a failing registry is an error, a server further down that fails ends the chain and is
+    /// recorded in [parser::merge::WhoisChain::failed].
+    #[cfg(feature = "parser")]
+    pub async fn query_chain(&self) -> Result<parser::merge::WhoisChain, Box<dyn std::error::Error>> {
+        // registry -> registrar, guards against servers referring to each other
+        const MAX_HOPS: usize = 3;
+
+        let port = self.target.whois_server.split_once(":").map_or("43", |(_, port)| port);
+        let mut server = self.referral().await?;
+        let mut chain = parser::merge::WhoisChain::default();
+
+        while chain.sources.len() < MAX_HOPS && !chain.sources.iter().any(|source| source.server == server) {
+            let information = match self.query_server(&server).await {
+                Ok(information) => information,
+                Err(error) if !chain.sources.is_empty() => {
+                    chain.failed = Some(parser::merge::HopError { server, error });
+                    break;
+                }
+                Err(error) => return Err(error),
+            };
+
+            let next = information.registrar_whois_server.as_deref()
+                .map(|host| host.trim_start_matches("http://").trim_start_matches("https://").trim_end_matches('/'))
+                .filter(|host| !host.is_empty())
+                .map(|host| format!("{host}:{port}"));
+
+            chain.sources.push(parser::merge::WhoisSource::new(server, information));
+            match next {
+                Some(next) => server = next,
+                None => break,
+            }
+        }
+        Ok(chain)
+    }
+
+    /// private!
+    /// Queries a single server of the referral chain and parses its reply
+    #[cfg(feature = "parser")]
+    async fn query_server(&self, server: &str) -> Result<parser::WhoisInformation, Box<dyn std::error::Error>> {
+        let conn = Whois::connect(server, self.target.domain2lookup).await?;
+        parser::Parser::new().parse_reader(conn).await
+    }
+
+    /// private!
+    /// Asks the configured WHOIS server which WHOIS server is authoritative, returned in host:port format
+    async fn referral(&self) -> Result<String, Box<dyn std::error::Error>> {
         let q1 = Whois::lookup(self.target.whois_server, self.target.domain2lookup).await?;
         let main_server = 
         if let Some((_, b)) = q1.split_once("whois:") {
-            b.trim().split_once("\n").ok_or_else(|| errors::WhoisError::MissingNewline)?.0
+            b.trim().split_once("\n").ok_or(errors::WhoisError::MissingNewline)?.0
         } else { return Err(Box::new(errors::WhoisError::GeneralErr { ctx: "could not find whois server to lookup" }));};
 
         let port: &str = self.target.whois_server.split_once(":").ok_or_else(|| {
             Box::new(errors::WhoisError::GeneralErr{ ctx: "whois server should be in host:port format" })
         })?.1;
-        Ok(Whois::lookup(&format!("{main_server}:{port}"), self.target.domain2lookup).await?)
+        Ok(format!("{main_server}:{port}"))
     }
-}
 
-impl Whois {
     /// private!
     /// Sends a query request to the WHOIS server and returns a String that holds WHOIS information
     async fn lookup(whois_server: &str, domain2_lookup: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let mut conn = TcpStream::connect(whois_server).await?;
-        conn.write(format!("{domain2_lookup}\r\n").as_bytes()).await?;
+        let mut conn = Whois::connect(whois_server, domain2_lookup).await?;
     
         let mut data: Vec<u8> = vec![];
         conn.read_to_end(&mut data).await?;
         
-        if data.len() == 0 {
+        if data.is_empty() {
             return Err(Box::new(errors::WhoisError::WhoisServerIO { ctx: "Wrote to WHOIS server, but got no response" }));
         }
         Ok(String::from_utf8(data)?)
     }
+
+    /// private!
+    /// Connects to the WHOIS server and sends the query, the response can be read from the returned stream
+    async fn connect(whois_server: &str, domain2_lookup: &str) -> Result<TcpStream, Box<dyn std::error::Error>> {
+        let mut conn = TcpStream::connect(whois_server).await?;
+        conn.write_all(format!("{domain2_lookup}\r\n").as_bytes()).await?;
+        Ok(conn)
+    }
 }
 
 // Errors that may occur for parent module
@@ -129,19 +210,63 @@ pub mod errors {
         GeneralErr{ctx: &'static str},
 
         #[error("couldn't find newline seperator")]
-        MissingNewline
+        MissingNewline,
+
+        #[error("line exceeds the limit of {limit} bytes")]
+        LineTooLong{limit: usize},
+
+        #[error("invalid {kind}: {value}")]
+        InvalidValue{kind: &'static str, value: String},
     }
 }
 
-#[tokio::test]
-async fn test_client() {
-    let client = Whois::new(WhoisOpt{
-        whois_server: "whois.iana.org:43", 
-        domain2lookup: "simpaix.net"
-    });
-    let res = client.query().await.expect("expected a response");
-
-    let parser = parser::Parser::new();
-    let info = parser.parse(res).unwrap();
-    println!("creation date:{}\nexpire:{}", info.creation_date.unwrap().format("%d/%m/%Y %H:%M") ,info.domain_status.unwrap()); // info.registry_domain_id , etc etc
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "requires network access to whois.iana.org"]
+    async fn test_client() {
+        let client = Whois::new(WhoisOpt{
+            whois_server: "whois.iana.org:43", 
+            domain2lookup: "simpaix.net"
+        });
+        let res = client.query().await.expect("expected a response");
+
+        let parser = parser::Parser::new();
+        let info = parser.parse(res).unwrap();
+        println!("creation date:{}\nexpire:{}", info.creation_date.unwrap().format("%d/%m/%Y %H:%M") ,info.domain_status.unwrap().join(", ")); // info.registry_domain_id , etc etc
+    }
+
+    #[cfg(feature = "parser")]
+    #[tokio::test]
+    async fn test_query_chain_keeps_replies_before_a_failing_hop() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            // IANA refers to the registry, the registry to a registrar sending an oversized line
+            let replies = [
+                "whois: 127.0.0.1\nstatus: ACTIVE\n".to_owned(),
+                "Domain Name: SIMPAIX.NET\nRegistrar WHOIS Server: localhost\n".to_owned(),
+                "x".repeat(parser::StreamParser::MAX_LINE_LEN + 1),
+            ];
+            for reply in replies {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut query = [0; 64];
+                let _ = conn.read(&mut query).await.unwrap();
+                let _ = conn.write_all(reply.as_bytes()).await;
+            }
+        });
+
+        let client = Whois::new(WhoisOpt{
+            whois_server: Box::leak(format!("127.0.0.1:{port}").into_boxed_str()),
+            domain2lookup: "simpaix.net"
+        });
+        let chain = client.query_chain().await.expect("the registry answered");
+        assert_eq!(chain.sources.len(), 1);
+        assert_eq!(chain.sources[0].information.domain_name.as_deref(), Some("SIMPAIX.NET"));
+        let failed = chain.failed.expect("the registrar failed");
+        assert_eq!(failed.server, format!("localhost:{port}"));
+        assert!(failed.error.to_string().contains("exceeds the limit"), "{}", failed.error);
+    }
+}
@@ -1,19 +1,30 @@
-#[macro_export]
-macro_rules! template {
-    ($tmpl:expr, $name:expr, { $($key:ident => $value:expr),+ }) => {
-        {
-            let data = context! { 
-                $( $key => $value,)+
-            };
-            
-            Result::<String, AppError>::Ok($tmpl.get_template($name)
-                .map_err(|e| {
-                    AppError::OopsError{err: format!("{e}")}
-                })?
-                .render(data)
-                .map_err(|e| {
-                    AppError::OopsError{err: format!("{e}")}
-                })?)
-        }
-    };
-}
\ No newline at end of file
+//! Macros for building handlers
+//!
+//! [template!] renders a template from the crate's `views` directory and checks at compile time that
+//! the template exists and that every variable it uses is supplied.
+
+pub use sfmacro::template;
+
+#[cfg(test)]
+mod tests {
+    use crate::server::error::AppError;
+
+    #[test]
+    fn test_template() {
+        let mut env = minijinja::Environment::new();
+        env.set_loader(minijinja::path_loader(concat!(env!("CARGO_MANIFEST_DIR"), "/views")));
+
+        // `t` is registered along with the catalogs, so rendering fails and is converted
+        let page: Result<String, AppError> = crate::template!(env, "error.html", {
+            text => "gone", status => 404, correlation_id => "abc", locale => "en"
+        }, error = AppError, globals = [t]);
+        assert!(matches!(page, Err(AppError::OopsError { .. })));
+
+        env.add_function("t", |key: &str, _: minijinja::value::Kwargs| key.to_uppercase());
+        let page = crate::template!(env, "error.html", {
+            text => "gone", status => 404, correlation_id => "abc", locale => "en"
+        }, globals = [t]).unwrap();
+        assert!(page.contains("<title>ERROR-TITLE</title>"));
+        assert!(page.contains("<p>gone</p>"));
+    }
+}
@@ -1,138 +1,58 @@
-use std::{fmt::Display, future::Future, pin::Pin, sync::{atomic::{AtomicU64, Ordering}, Arc}, task::{Context, Poll}};
-use axum::{async_trait, body::Body, extract::{FromRequestParts, Request, State}, handler::Handler, http::{request::Parts, HeaderValue, StatusCode}, middleware::{self, Next}, response::{AppendHeaders, Html, IntoResponse, Response}, routing::get, Extension, RequestExt, Router};
-use minijinja::{context, Environment};
-use serde::{self, Serialize};
-use thiserror::Error;
-use tokio::fs::File;
-use tokio_util::io::ReaderStream;
+use std::{future::Future, net::SocketAddr, pin::Pin, task::{Context, Poll}, time::Duration};
+use axum::{extract::{Request, State}, handler::Handler, http::{HeaderMap, Method}, middleware, response::{Html, IntoResponse, Response}, routing::get, Extension, Router};
+use clap::Parser;
+use include_dir::{include_dir, Dir};
+use minijinja::Environment;
 use tower::{Layer, Service};
-use tower_http::{classify, services::ServeDir};
-use webapp::template;
+use tower_http::compression::{predicate::{DefaultPredicate, NotForContentType, Predicate}, CompressionLayer};
+use webapp::{server::{assets::Assets, auth::{hash_password, require_user_or_scopes, Auth, Users}, bearer::{BearerAuth, BearerLayer, RequiredScopes}, config::{Cli, Config, SessionStoreKind}, error::{problem_details, AppError}, headers::{typed_header, Header, TypedHeader}, i18n::{missing_keys_handler, negotiate_locale, Catalogs, Locale}, lifecycle::{healthz, readyz, shutdown_signal, Lifecycle}, media::MediaDir, metrics::{metrics_handler, Metrics}, ratelimit::{RateLimitLayer, RateLimiter}, session::{load_session, verify_csrf, FileStore, MemoryStore, Sessions}, telemetry::{self, trace_requests}, tls::{redirect_router, TlsServer}, upload::{UploadConfig, Uploads}, views::{TemplateMode, Views}}, template};
 
-#[derive(Serialize)]
-struct GlobalErrResponse {
-    message: String
-}
+static VIEWS: Dir = include_dir!("$CARGO_MANIFEST_DIR/views");
+static LOCALES: Dir = include_dir!("$CARGO_MANIFEST_DIR/locales");
 
-#[derive(Error, Debug)]
-pub enum AppError {
-    #[error("Oops, something went wrong!")]
-    Oops,
-    #[error("Request payload has not been satisfied")]
-    RequestPayload,
-    #[error("Oops something went wrong: {detail}")]
-    OopsWithDetails{code: StatusCode, detail: String},
-    #[error("Oops something went wrong: {err}")]
-    OopsError{err: String},
-}
+/// Data every request to `/` has to carry
+#[derive(Clone)]
+struct XData(String);
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let res = axum::Json(GlobalErrResponse{
-            message: format!("{self}")
-        });
-
-        match self {
-            AppError::Oops => (
-                StatusCode::INTERNAL_SERVER_ERROR, 
-                res
-            ).into_response(),
-
-            AppError::RequestPayload => (
-                StatusCode::BAD_REQUEST, 
-                res
-            ).into_response(),
-
-            AppError::OopsWithDetails{code, ..} => {
-                (
-                    code, 
-                    res
-                ).into_response()
-            },
-            AppError::OopsError{err} => {
-                (
-                    StatusCode::BAD_REQUEST, 
-                    err
-                ).into_response()
-            },
+impl Header for XData {
+    const NAME: &'static str = "x-data";
+
+    fn parse(value: &str) -> Result<Self, String> {
+        if value.is_empty() {
+            return Err("must not be empty".into());
         }
+        Ok(XData(value.to_owned()))
     }
 }
 
-async fn pass_some_data(mut req: Request, next: Next) -> axum::response::Result<Response> {
-    let data = req.headers().get("X-Data").ok_or(AppError::RequestPayload)?
-        .to_str()
-        .map_err(|err| {
-            println!("got err: {err}");
-            AppError::RequestPayload
-        })?
-    .to_owned();
-
-    req.extensions_mut().insert(data);
-    Ok(next.run(req).await)
-}
-
-// streams file
-async fn handler(data: Extension<String>) -> axum::response::Result<Response> {
-    println!("data from middleware {}", data.0);
-
-    let file = File::open("test.mp4").await.map_err(|err| {
-        println!("got err: {err}");
-        AppError::Oops
-    })?;
-    
-    let body = Body::from_stream(ReaderStream::new(file));
-    let res = Response::builder()
-        .header("Content-Type", "video/mp4")
-        .body(body).map_err(|err| {
-            println!("got err: {err}");
-            AppError::Oops
-        })?;
-        
-    Ok(res)
-}
+// streams file, seekable through range requests
+async fn handler(TypedHeader(data): TypedHeader<XData>, app_state: State<AppState>, method: Method, headers: HeaderMap) -> axum::response::Result<Response> {
+    tracing::debug!(data = data.0, "data from middleware");
 
-async fn handler_404(template: State<Arc<Environment<'static>>>) -> axum::response::Result<Response> {
-    let res = template!(template, "error.html", {
-        text => "hello world"
-    })?;
-    
-    Ok(Html(res).into_response())
+    Ok(app_state.media.serve("test.mp4", &method, &headers).await?)
 }
-struct ExtractXData(String);
-
-#[async_trait]
-impl<S> FromRequestParts<S> for ExtractXData
-where
-    S: Send + Sync,
-{
-    type Rejection = AppError;
-
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let extracted_part = parts.headers.get("X-Data")
-            .map_or(Ok(""), |v| v.to_str())
-            .map_err(|e| {
-                println!("got error from ExtractXData extractor: {e}");
-                AppError::Oops // dont expose exact error to client
-            })?;
 
-        Ok(ExtractXData(extracted_part.into()))
-    }
+async fn handler_404() -> AppError {
+    AppError::NotFound
 }
 
-async fn some_handler(app_state: State<AppState>) -> axum::response::Result<Response> {
-    let tmpl = template!(app_state.views_engine, "error.html", { 
+async fn some_handler(app_state: State<AppState>, Extension(locale): Extension<Locale>) -> axum::response::Result<Response> {
+    let tmpl = template!(app_state.views.env(), "error.html", {
         text => "yolo",
-        yo => ""
-    })?;
+        status => 200,
+        correlation_id => (),
+        locale => locale.to_string()
+    }, error = AppError, globals = [t])?;
 
     Ok(Html(tmpl).into_response())
 }
 
 #[derive(Clone)]
 struct AppState {
-    global_req_counter: Arc<AtomicU64>,
-    views_engine: Arc<Environment<'static>> 
+    metrics: Metrics,
+    lifecycle: Lifecycle,
+    views: Views,
+    media: MediaDir,
 }
 
 #[derive(Clone)]
@@ -172,15 +92,14 @@ where
     }
 
     fn call(&mut self, request: Request) -> Self::Future {
-        self.state.global_req_counter.fetch_add(1, Ordering::SeqCst);
-        let total_requests = self.state.global_req_counter.load(Ordering::SeqCst);
-
-        println!("reqs: {total_requests}");
+        let timer = self.state.metrics.track(&request);
+        let in_flight = self.state.lifecycle.track();
 
         let future = self.inner.call(request);
         Box::pin(async move {
             let response: Response = future.await?;
-            Ok(response)
+            timer.finish(response.status());
+            Ok(in_flight.attach(response))
         })
     }
 }
@@ -202,35 +121,143 @@ impl<'a, F> Closure<F>
 #[allow(unused)]
 fn do_it(data: &(u8, u16)) -> &u8 { &data.0 }
 
+fn configure_views(env: &mut Environment<'static>) {
+    env.add_filter("test", |a: u8| {
+        5 + a
+    });
+}
+
 #[tokio::main]
 async fn main() {
-    let clo = Closure { data: (0, 1), func: do_it };
-    println!("{}", clo.call());
-    
-    let mut templates = Environment::new();
-    templates.set_loader(minijinja::path_loader("crates/webapp/views"));
-    templates.add_filter("test", |a: u8| {
-        5 + a
+    let cli = Cli::parse();
+    if cli.hash_password {
+        let mut password = String::new();
+        if let Err(err) = std::io::stdin().read_line(&mut password) {
+            eprintln!("couldn't read the password: {err}");
+            std::process::exit(1);
+        }
+        println!("{}", hash_password(password.trim_end_matches(['\r', '\n'])).expect("hashing a password"));
+        return;
+    }
+
+    let config = Config::load_from(cli, std::env::vars_os()).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(2);
     });
+    let telemetry = telemetry::init(&config.log).unwrap_or_else(|err| panic!("couldn't set up logging: {err}"));
 
-    let engine: Arc<Environment<'_>> = templates.into();
-    let service_404 = handler_404.with_state(engine.clone());
-    let assets = ServeDir::new("crates/webapp/assets").not_found_service(service_404.clone());
+    let clo = Closure { data: (0, 1), func: do_it };
+    tracing::debug!("{}", clo.call());
+
+    let mode = config.templates.mode;
+    let fallback_locale = &config.templates.fallback_locale;
+    let catalogs = match mode {
+        TemplateMode::Development => Catalogs::load(&config.paths.locales, fallback_locale).map(|c| c.with_missing_report(true)),
+        TemplateMode::Embedded => Catalogs::embedded(&LOCALES, fallback_locale),
+    }.unwrap_or_else(|err| panic!("invalid translations: {err}"));
+
+    let assets = Assets::new(&config.paths.assets, "/static");
+    let translations = catalogs.clone();
+    let fingerprints = assets.clone();
+    let configure = move |env: &mut Environment<'static>| {
+        configure_views(env);
+        translations.register(env);
+        fingerprints.register(env);
+    };
+    let views = match mode {
+        TemplateMode::Development => Views::development(&config.paths.views, configure).unwrap(),
+        TemplateMode::Embedded => Views::embedded(&VIEWS, configure).unwrap_or_else(|err| panic!("invalid template: {err:#}")),
+    };
 
+    let media = MediaDir::new(&config.paths.assets);
     let app_state = AppState{
-        global_req_counter: Arc::new(AtomicU64::new(0)),
-        views_engine: engine
+        metrics: Metrics::new().with_nested("/static").with_nested("/media"),
+        lifecycle: Lifecycle::new(config.drain_timeout()).with_pre_stop_delay(config.pre_stop_delay()),
+        views: views.clone(),
+        media: media.clone(),
     };
 
-    let layered_handler = handler.layer(middleware::from_fn(pass_some_data));
-    let app = Router::new()
+    let session_key = config.session_key().unwrap_or_else(|| {
+        tracing::warn!("session.secret is not set, sessions won't survive a restart");
+        cookie::Key::generate()
+    });
+    let sessions = match config.session.store {
+        SessionStoreKind::Memory => Sessions::new(MemoryStore::default(), session_key),
+        SessionStoreKind::File => Sessions::new(
+            FileStore::new(&config.session.dir).unwrap_or_else(|err| panic!("couldn't create {}: {err}", config.session.dir.display())),
+            session_key,
+        ),
+    }.with_ttl(Duration::from_secs(config.session.ttl))
+        .with_anonymous_ttl(Duration::from_secs(config.session.anonymous_ttl))
+        .with_secure(config.session_secure());
+    sessions.spawn_sweep();
+    let auth = Auth::new(Users::new(config.auth.users.clone()), views.clone());
+    let bearer = BearerAuth::from_config(&config.bearer).unwrap_or_else(|err| panic!("invalid bearer auth: {err}"));
+    let rate_limiter = RateLimiter::from_config(&config.rate_limit).unwrap_or_else(|err| panic!("invalid rate limit: {err}"));
+
+    let mut upload_config = UploadConfig::new(&config.paths.assets, &config.paths.uploads);
+    upload_config.max_size = config.limits.upload_max_size;
+    let uploads = Uploads::new(upload_config);
+    // browsers upload as the signed in user, API clients with a token of the scope
+    let uploads_router = uploads.router()
+        .route_layer(middleware::from_fn_with_state(RequiredScopes::new(["uploads:write"]), require_user_or_scopes));
+
+    let layered_handler = handler.layer(middleware::from_fn(typed_header::<XData>));
+    let mut app = Router::new()
         .route("/", get(layered_handler))
         .route("/x-data", get(some_handler))
-        .nest_service("/static", assets)
-        .fallback_service(service_404)
+        .route("/metrics", get(metrics_handler).with_state(app_state.metrics.clone()))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz).with_state(app_state.lifecycle.clone()))
+        .nest("/static", assets.router())
+        .nest_service("/media", media)
+        .nest("/uploads", uploads_router)
+        .merge(auth.router());
+    if mode == TemplateMode::Development {
+        app = app.route("/_dev/i18n/missing", get(missing_keys_handler).with_state(catalogs.clone()));
+    }
+
+    let mut app = app
+        .fallback(handler_404)
         .layer(AppLayer{state: app_state.clone()})
-        .with_state(app_state);
+        .layer(RateLimitLayer::new(rate_limiter));
+    if bearer.is_enabled() {
+        app = app.layer(BearerLayer::new(bearer));
+    }
+    let app = app
+        .layer(middleware::from_fn(verify_csrf))
+        .layer(middleware::from_fn_with_state(sessions, load_session))
+        .layer(middleware::from_fn_with_state(views, problem_details))
+        .layer(middleware::from_fn_with_state(catalogs, negotiate_locale))
+        // media is already compressed and streamed in ranges
+        .layer(CompressionLayer::new().compress_when(DefaultPredicate::new()
+            .and(NotForContentType::const_new("video/"))
+            .and(NotForContentType::const_new("audio/"))))
+        .layer(middleware::from_fn(trace_requests))
+        .with_state(app_state.clone());
+
+    let listener = tokio::net::TcpListener::bind(config.listen).await
+        .unwrap_or_else(|err| panic!("couldn't listen on {}: {err}", config.listen));
+    let lifecycle = &app_state.lifecycle;
+    tracing::info!(listen = %config.listen, tls = config.tls.is_some(), "listening");
+    let Some(tls) = &config.tls else {
+        lifecycle.serve(axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()), shutdown_signal()).await.unwrap();
+        telemetry.shutdown();
+        return;
+    };
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let server = TlsServer::new(&tls.cert, &tls.key, tls.http2)
+        .unwrap_or_else(|err| panic!("couldn't set up tls: {err}"));
+    let redirect = async {
+        let Some(redirect_from) = tls.redirect_from else { return };
+        let redirect_listener = tokio::net::TcpListener::bind(redirect_from).await
+            .unwrap_or_else(|err| panic!("couldn't listen on {redirect_from}: {err}"));
+        let redirect = axum::serve(redirect_listener, redirect_router(config.listen));
+        if let Err(err) = lifecycle.serve(redirect, lifecycle.shutdown_requested()).await {
+            tracing::error!("http redirect stopped: {err}");
+        }
+    };
+    let (served, ()) = tokio::join!(server.serve(listener, app, lifecycle, shutdown_signal()), redirect);
+    served.unwrap();
+    telemetry.shutdown();
 }
\ No newline at end of file
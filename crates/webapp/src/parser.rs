@@ -1,25 +1,127 @@
 //! Parser for WHOIS data
-use std::{fmt::Debug, str::FromStr};
+//!
+//! Enable the 'serialize' flag to (de)serialize [WhoisInformation] with serde and to
+//! generate its JSON Schema through [WhoisInformation::schema].
+//!
+//! Custom record structs can be parsed by deriving [WhoisRecord](macro@WhoisRecord), see [record].
+//!
+//! Raw responses can be stripped from comments, notices and trailers with [normalize::normalize] first.
+use std::fmt::Debug;
 pub use chrono::{DateTime, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::errors::WhoisError;
+pub use dns::{Dnssec, DsRecord, NameServer, NameServers};
+pub use record::{WhoisField, WhoisRecord};
+pub use sfmacro::WhoisRecord;
 
-#[derive(Debug, Default)]
-pub struct WhoisInformation {
-    pub domain_name: Option<String>,
-    pub registry_domain_id: Option<String>,
-    pub registrar_whois_server: Option<String>,
-    pub registrar_url: Option<String>,
+pub mod dns;
+pub mod merge;
+pub mod normalize;
+pub mod record;
+pub mod risk;
+
+/// Declares [WhoisInformation] along with the merging of all of its fields, so a new field can't
+/// be forgotten by [merge::MergePolicy::merge]
+macro_rules! whois_information {
+    (
+        $(#[$attr:meta])*
+        pub struct WhoisInformation<S = String> {
+            $($(#[$field_attr:meta])* pub $field:ident: $ty:ty,)+
+        }
+    ) => {
+        $(#[$attr])*
+        pub struct WhoisInformation<S = String> {
+            $($(#[$field_attr])* pub $field: $ty,)+
+        }
+
+        impl merge::MergePolicy {
+            fn merge_fields(&self, chain: &[merge::WhoisSource], merged: &mut merge::MergedWhois) {
+                $(merged.information.$field = self.merge_field(stringify!($field), chain, merged, |info| &info.$field);)+
+            }
+        }
+    };
+}
+
+whois_information! {
+/// Parsed WHOIS record.
+///
+/// `S` is the string type the record holds. [Parser::parse] yields an owned record,
+/// [Parser::parse_borrowed] yields a [WhoisInformationRef] borrowing from the raw response.
+/// Name servers and DNSSEC details are always owned, as they are normalized while parsing.
+///
+/// With the 'serialize' flag the JSON shape is stable: every field is always present
+/// (`null` when the registry didn't publish it) and dates are RFC 3339 strings in UTC.
+#[derive(Debug, Default, WhoisRecord)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema))]
+#[cfg_attr(feature = "serialize", serde(default, bound(deserialize = "S: serde::Deserialize<'de> + Default")))]
+#[cfg_attr(feature = "serialize", schemars(
+    rename = "WhoisInformation",
+    description = "Parsed WHOIS record.\n\nWith the 'serialize' flag the JSON shape is stable: every field is always present (`null` when the registry didn't publish it) and dates are RFC 3339 strings in UTC.",
+    bound = "S: schemars::JsonSchema + serde::Serialize + Default",
+))]
+pub struct WhoisInformation<S = String> {
+    pub domain_name: Option<S>,
+    pub registry_domain_id: Option<S>,
+    pub registrar_whois_server: Option<S>,
+    pub registrar_url: Option<S>,
     pub updated_date: Option<DateTime<Utc>>, // whois datetimes are expressed in UTC
     pub creation_date: Option<DateTime<Utc>>,
+    #[cfg_attr(feature = "serialize", serde(rename = "registry_expiry_date"))]
+    #[whois(key = "Registry Expiry Date", alias = "Registrar Registration Expiration Date")]
     pub registry_expirity_date: Option<DateTime<Utc>>,
-    pub registrar: Option<String>,
-    pub registrar_iana_id: Option<String>,
-    pub registrar_abuse_email_contact: Option<String>,
-    pub registrar_abuse_phone_contact: Option<String>,
-    pub domain_status: Option<String>,
-    pub name_servers: Option<Vec<String>>,
-    pub dnssec: Option<String>,
+    pub registrar: Option<S>,
+    #[whois(key = "Registrar IANA ID")]
+    pub registrar_iana_id: Option<S>,
+    #[whois(key = "Registrar Abuse Contact Email")]
+    pub registrar_abuse_email_contact: Option<S>,
+    #[whois(key = "Registrar Abuse Contact Phone")]
+    pub registrar_abuse_phone_contact: Option<S>,
+    /// Every `Domain Status` line, registries publish one per EPP status
+    pub domain_status: Option<Vec<S>>,
+    pub registrant_organization: Option<S>,
+    #[whois(key = "Name Server", alias = "nserver")]
+    pub name_servers: Option<NameServers>,
+    #[whois(key = "DNSSEC", alias = "DNSSEC DS Data", alias = "ds-rdata")]
+    pub dnssec: Option<Dnssec>,
+}
 }
 
+/// Zero-copy [WhoisInformation], every field borrows from the raw WHOIS response
+pub type WhoisInformationRef<'a> = WhoisInformation<&'a str>;
+
+#[cfg(feature = "serialize")]
+impl WhoisInformation {
+    /// Generates the JSON Schema describing the serialized form of [WhoisInformation]
+    pub fn schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(WhoisInformation)
+    }
+}
+
+impl WhoisInformationRef<'_> {
+    /// Copies every borrowed field into an owned [WhoisInformation]
+    pub fn into_owned(self) -> WhoisInformation {
+        let owned = |v: Option<&str>| v.map(str::to_owned);
+        WhoisInformation {
+            domain_name: owned(self.domain_name),
+            registry_domain_id: owned(self.registry_domain_id),
+            registrar_whois_server: owned(self.registrar_whois_server),
+            registrar_url: owned(self.registrar_url),
+            updated_date: self.updated_date,
+            creation_date: self.creation_date,
+            registry_expirity_date: self.registry_expirity_date,
+            registrar: owned(self.registrar),
+            registrar_iana_id: owned(self.registrar_iana_id),
+            registrar_abuse_email_contact: owned(self.registrar_abuse_email_contact),
+            registrar_abuse_phone_contact: owned(self.registrar_abuse_phone_contact),
+            domain_status: self.domain_status.map(|statuses| statuses.into_iter().map(str::to_owned).collect()),
+            registrant_organization: owned(self.registrant_organization),
+            name_servers: self.name_servers,
+            dnssec: self.dnssec,
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Parser;
 
 impl Parser {
@@ -30,34 +132,200 @@ impl Parser {
     
     // Parses a WHOIS information from a String into a WhoisInformation struct
     pub fn parse(&self, content: String) -> Result<WhoisInformation, Box<dyn std::error::Error>> {
+        Ok(self.parse_borrowed(&content)?.into_owned())
+    }
+
+    // Parses a WHOIS information without copying, the result borrows from content
+    pub fn parse_borrowed<'a>(&self, content: &'a str) -> Result<WhoisInformationRef<'a>, Box<dyn std::error::Error>> {
+        self.parse_record(content)
+    }
+
+    // Parses a WHOIS information into any record deriving WhoisRecord
+    pub fn parse_record<'a, R: WhoisRecord<'a>>(&self, content: &'a str) -> Result<R, Box<dyn std::error::Error>> {
         let lines = content.split("\n").flat_map(|line| line.split_once(":"));
-        let mut whois_information = WhoisInformation::default();
+        let mut record = R::default();
         
         for (key, value) in lines {
-            let key = key.trim();
-            let value = value.trim();
-            
-            match key.to_lowercase().as_str() {
-                "domain name" => whois_information.domain_name = Some(value.to_owned()),
-                "registry domain id" => whois_information.registry_domain_id = Some(value.to_owned()),
-                "registrar whois server" => whois_information.registrar_whois_server = Some(value.to_owned()),
-                "registrar url" => whois_information.registrar_url = Some(value.to_owned()),
-                "updated date" => whois_information.updated_date = Some(DateTime::<Utc>::from_str(value)?),
-                "creation date" => whois_information.creation_date = Some(DateTime::<Utc>::from_str(value)?),
-                "registry expiry date" => whois_information.registry_expirity_date = Some(DateTime::<Utc>::from_str(value)?),
-                "registrar" => whois_information.registrar = Some(value.to_owned()),
-                "registrar iana id" => whois_information.registrar_iana_id = Some(value.to_owned()),
-                "registrar abuse contact email" => whois_information.registrar_abuse_email_contact = Some(value.to_owned()),
-                "registrar abuse contact phone" => whois_information.registrar_abuse_phone_contact = Some(value.to_owned()),
-                "domain status" => whois_information.domain_status = Some(value.to_owned()),
-                "name server" => match whois_information.name_servers.as_mut() {
-                    Some(name_servers) => name_servers.push(value.to_owned()),
-                    None => whois_information.name_servers = Some(Vec::new()),
-                },
-                "dnssec" => whois_information.dnssec = Some(value.to_owned()),
-                _ => {}
+            record.apply_field(key, value.trim())?;
+        }
+        Ok(record)
+    }
+
+    // Parses a WHOIS information line by line from a reader, such as the WHOIS server connection,
+    // only the current line is kept in memory
+    pub async fn parse_reader<R>(&self, reader: R) -> Result<WhoisInformation, Box<dyn std::error::Error>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut reader = reader;
+        let mut stream = StreamParser::new();
+        let mut chunk = [0u8; 8 * 1024];
+
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                return stream.finish();
             }
+            stream.feed(&chunk[..n])?;
         }
-        Ok(whois_information)
+    }
+}
+
+/// Incremental parser fed with arbitrary byte chunks.
+///
+/// Only an incomplete trailing line is buffered between calls to [StreamParser::feed],
+/// so the whole response never has to be held in memory. Lines longer than
+/// [StreamParser::MAX_LINE_LEN] are rejected instead of growing the buffer.
+///
+/// ### Example
+/// ```
+/// use webapp::parser::StreamParser;
+///
+/// let mut parser = StreamParser::new();
+/// parser.feed(b"Domain Name: SIMPAIX.NET\nRegis").unwrap();
+/// parser.feed(b"trar: Example Registrar\n").unwrap();
+///
+/// let info = parser.finish().unwrap();
+/// assert_eq!(info.registrar.as_deref(), Some("Example Registrar"));
+/// ```
+#[derive(Default)]
+pub struct StreamParser {
+    pending: Vec<u8>,
+    whois_information: WhoisInformation,
+}
+
+impl StreamParser {
+    /// Longest line the parser buffers, WHOIS lines are far shorter in practice
+    pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+    // Creates a new streaming parser
+    pub fn new() -> StreamParser {
+        StreamParser::default()
+    }
+
+    // Feeds the next chunk of the WHOIS response
+    pub fn feed(&mut self, mut chunk: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        while let Some(pos) = chunk.iter().position(|b| *b == b'\n') {
+            let (line, rest) = chunk.split_at(pos + 1);
+            chunk = rest;
+
+            if self.pending.is_empty() {
+                self.parse_line(line)?;
+            } else {
+                self.pending.extend_from_slice(line);
+                let line = std::mem::take(&mut self.pending);
+                self.parse_line(&line)?;
+            }
+        }
+
+        if self.pending.len() + chunk.len() > StreamParser::MAX_LINE_LEN {
+            return Err(Box::new(WhoisError::LineTooLong { limit: StreamParser::MAX_LINE_LEN }));
+        }
+        self.pending.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    // Parses the remaining buffered line and returns the WHOIS information
+    pub fn finish(mut self) -> Result<WhoisInformation, Box<dyn std::error::Error>> {
+        let line = std::mem::take(&mut self.pending);
+        self.parse_line(&line)?;
+        Ok(self.whois_information)
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((key, value)) = std::str::from_utf8(line)?.split_once(":") {
+            self.whois_information.apply_field(key, value.trim())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = "Domain Name: SIMPAIX.NET\r\nRegistrar: Example Registrar, LLC\r\nCreation Date: 2021-03-04T10:11:12Z\r\nName Server: NS1.EXAMPLE.COM\r\nName Server: NS2.EXAMPLE.COM\r\n";
+
+    #[cfg(feature = "serialize")]
+    const SCHEMA_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/schema/whois_information.json");
+
+    #[test]
+    fn test_parse_borrowed() {
+        let info = Parser::new().parse_borrowed(RESPONSE).unwrap();
+        assert_eq!(info.domain_name, Some("SIMPAIX.NET"));
+        assert_eq!(info.registrar, Some("Example Registrar, LLC"));
+        assert_eq!(info.name_servers.unwrap().hosts().collect::<Vec<_>>(), ["ns1.example.com", "ns2.example.com"]);
+    }
+
+    #[test]
+    fn test_parse_record() {
+        #[derive(Default, WhoisRecord)]
+        struct NameServers<'a> {
+            #[whois(key = "Name Server", alias = "nserver")]
+            hosts: Vec<&'a str>,
+            #[whois(skip)]
+            domain_name: Option<String>,
+        }
+
+        let content = format!("{RESPONSE}nserver: ns3.example.com\n");
+        let record: NameServers = Parser::new().parse_record(&content).unwrap();
+        assert_eq!(record.hosts, ["NS1.EXAMPLE.COM", "NS2.EXAMPLE.COM", "ns3.example.com"]);
+        assert_eq!(record.domain_name, None);
+    }
+
+    #[test]
+    fn test_parse_invalid_values() {
+        let info = Parser::new().parse(
+            "Creation Date: yesterday\nRegistry Expiry Date: 2030-03-04T10:11:12Z\nRegistry Expiry Date: someday\n".into()
+        ).unwrap();
+        assert_eq!(info.creation_date, None);
+        assert_eq!(info.registry_expirity_date, "2030-03-04T10:11:12Z".parse().ok());
+    }
+
+    #[tokio::test]
+    async fn test_stream_matches_parse() {
+        let expected = format!("{:?}", Parser::new().parse(RESPONSE.into()).unwrap());
+
+        for chunk_size in [1, 3, 7, RESPONSE.len()] {
+            let mut stream = StreamParser::new();
+            for chunk in RESPONSE.as_bytes().chunks(chunk_size) {
+                stream.feed(chunk).unwrap();
+            }
+            assert_eq!(format!("{:?}", stream.finish().unwrap()), expected);
+        }
+
+        let info = Parser::new().parse_reader(RESPONSE.as_bytes()).await.unwrap();
+        assert_eq!(format!("{info:?}"), expected);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_serialize_shape() {
+        let info = Parser::new().parse(
+            "Domain Name: SIMPAIX.NET\nCreation Date: 2021-03-04T10:11:12Z\nRegistry Expiry Date: 2030-03-04T10:11:12Z\n".into()
+        ).unwrap();
+
+        let value = serde_json::to_value(&info).unwrap();
+        assert_eq!(value["domain_name"], "SIMPAIX.NET");
+        assert_eq!(value["creation_date"], "2021-03-04T10:11:12Z");
+        assert_eq!(value["registry_expiry_date"], "2030-03-04T10:11:12Z");
+        assert!(value["registrar"].is_null());
+
+        let back: WhoisInformation = serde_json::from_value(value).unwrap();
+        assert_eq!(back.creation_date, info.creation_date);
+        assert_eq!(back.registry_expirity_date, info.registry_expirity_date);
+    }
+
+    // run with WHOIS_SCHEMA_UPDATE=1 to regenerate the checked in schema
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_schema_up_to_date() {
+        let generated = serde_json::to_string_pretty(&WhoisInformation::schema()).unwrap() + "\n";
+        if std::env::var_os("WHOIS_SCHEMA_UPDATE").is_some() {
+            std::fs::write(SCHEMA_PATH, &generated).unwrap();
+        }
+
+        let checked_in = std::fs::read_to_string(SCHEMA_PATH).unwrap_or_default();
+        assert_eq!(checked_in, generated, "schema is outdated, rerun the test with WHOIS_SCHEMA_UPDATE=1");
     }
 }
@@ -0,0 +1,217 @@
+//! Name server and DNSSEC details of a WHOIS record
+//!
+//! Both are filled through [WhoisField], so they can be used in any [WhoisRecord](super::WhoisRecord).
+use std::{error::Error, net::IpAddr, ops::Deref};
+use crate::errors::WhoisError;
+use super::WhoisField;
+
+/// Name server of a domain, with the glue records some registries publish next to it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema))]
+pub struct NameServer {
+    /// Lowercased hostname without trailing dot
+    pub host: String,
+    pub glue: Vec<IpAddr>,
+}
+
+impl NameServer {
+    /// Parses `host [ip ...]`, as found in `Name Server` and `nserver` lines. Anything after the
+    /// host that isn't an IP address, like a `(secondary)` note, is ignored
+    pub fn parse(value: &str) -> Result<NameServer, Box<dyn Error>> {
+        let mut parts = value.split_whitespace();
+        let host = parts.next().unwrap_or_default().trim_end_matches('.').to_ascii_lowercase();
+        if !is_hostname(&host) {
+            return Err(Box::new(WhoisError::InvalidValue { kind: "name server", value: value.to_owned() }));
+        }
+
+        let glue = parts.filter_map(|ip| ip.parse().ok()).collect();
+        Ok(NameServer { host, glue })
+    }
+}
+
+// name servers are fully qualified, so a single label like `not` in `not a host` is no host either
+fn is_hostname(host: &str) -> bool {
+    host.contains('.') && host.len() <= 253 && host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+/// Name servers of a domain, deduplicated on hostname in the order the registry lists them
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema))]
+#[cfg_attr(feature = "serialize", serde(transparent), schemars(transparent))]
+pub struct NameServers(Vec<NameServer>);
+
+impl NameServers {
+    /// Adds a name server, glue of an already known hostname is merged into it
+    pub fn push(&mut self, name_server: NameServer) {
+        match self.0.iter_mut().find(|ns| ns.host == name_server.host) {
+            Some(known) => {
+                for ip in name_server.glue {
+                    if !known.glue.contains(&ip) {
+                        known.glue.push(ip);
+                    }
+                }
+            }
+            None => self.0.push(name_server),
+        }
+    }
+
+    /// Hostnames of all name servers
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|ns| ns.host.as_str())
+    }
+}
+
+impl Deref for NameServers {
+    type Target = [NameServer];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<NameServers> for Vec<NameServer> {
+    fn from(name_servers: NameServers) -> Self {
+        name_servers.0
+    }
+}
+
+/// Lines that don't hold a valid name server, like an empty `Name Server:`, are rejected, an
+/// `Option<NameServers>` field skips them and stays `None` without any valid line
+impl WhoisField<'_> for NameServers {
+    fn set_value(&mut self, value: &str) -> Result<(), Box<dyn Error>> {
+        self.push(NameServer::parse(value)?);
+        Ok(())
+    }
+}
+
+/// Delegation Signer record, see RFC 4034 section 5
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema))]
+pub struct DsRecord {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    /// Lowercased hex digest
+    pub digest: String,
+}
+
+impl DsRecord {
+    /// Parses `key_tag algorithm digest_type digest`, the digest may be split by whitespace
+    pub fn parse(value: &str) -> Result<DsRecord, Box<dyn Error>> {
+        let invalid = || WhoisError::InvalidValue { kind: "DS record", value: value.to_owned() };
+        let mut parts = value.split_whitespace();
+
+        let key_tag = parts.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+        let algorithm = parts.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+        let digest_type = parts.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+        let digest = parts.collect::<String>().to_ascii_lowercase();
+
+        // SHA-1, SHA-256 and SHA-384 digests have a known length, others are only checked for being hex
+        let expected_len = match digest_type {
+            1 => Some(40),
+            2 => Some(64),
+            4 => Some(96),
+            _ => None,
+        };
+        let valid_digest = !digest.is_empty()
+            && digest.len() % 2 == 0
+            && digest.bytes().all(|b| b.is_ascii_hexdigit())
+            && expected_len.is_none_or(|len| digest.len() == len);
+        if !valid_digest {
+            return Err(Box::new(invalid()));
+        }
+
+        Ok(DsRecord { key_tag, algorithm, digest_type, digest })
+    }
+}
+
+/// DNSSEC state of a domain
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema))]
+pub struct Dnssec {
+    /// Whether the delegation is signed, `None` when the registry uses a notation we don't know
+    pub signed: Option<bool>,
+    pub ds_records: Vec<DsRecord>,
+}
+
+/// Accepts both the status (`DNSSEC: signedDelegation`) and DS data (`DNSSEC DS Data: 31589 8 2 ...`) lines,
+/// DS data that doesn't parse is skipped
+impl WhoisField<'_> for Dnssec {
+    fn set_value(&mut self, value: &str) -> Result<(), Box<dyn Error>> {
+        if value.starts_with(|c: char| c.is_ascii_digit()) {
+            if let Ok(ds_record) = DsRecord::parse(value) {
+                self.ds_records.push(ds_record);
+                self.signed = Some(true);
+            }
+            return Ok(());
+        }
+
+        let signed = match value.to_ascii_lowercase().as_str() {
+            "signed" | "signeddelegation" | "yes" | "active" => Some(true),
+            "unsigned" | "unsigneddelegation" | "no" | "inactive" => Some(false),
+            _ => None,
+        };
+        // DS data proves the delegation is signed, whatever the status line says
+        if self.ds_records.is_empty() || signed == Some(true) {
+            self.signed = signed;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_servers() {
+        let mut name_servers = NameServers::default();
+        for value in ["NS1.Example.COM.", "ns2.example.com 192.0.2.1", "ns1.example.com 2001:db8::1", "ns1.example.com 2001:db8::1"] {
+            name_servers.set_value(value).unwrap();
+        }
+
+        assert_eq!(name_servers.hosts().collect::<Vec<_>>(), ["ns1.example.com", "ns2.example.com"]);
+        assert_eq!(name_servers[0].glue, ["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        assert!(NameServer::parse("not a host").is_err());
+
+        assert!(name_servers.set_value("").is_err());
+        name_servers.set_value("ns3.example.com (vendor note) 192.0.2.3").unwrap();
+        assert_eq!(name_servers.hosts().collect::<Vec<_>>(), ["ns1.example.com", "ns2.example.com", "ns3.example.com"]);
+        assert_eq!(name_servers[2].glue, ["192.0.2.3".parse::<IpAddr>().unwrap()]);
+        assert!(NameServer::parse("-bad.example.com").is_err());
+
+        let mut field: Option<NameServers> = None;
+        field.set_value("").unwrap();
+        assert_eq!(field, None);
+    }
+
+    #[test]
+    fn test_dnssec() {
+        let mut dnssec = Dnssec::default();
+        dnssec.set_value("signedDelegation").unwrap();
+        dnssec.set_value("31589 8 2 A1B2C3D4E5F60718293A4B5C6D7E8F90 A1B2C3D4E5F60718293A4B5C6D7E8F90").unwrap();
+
+        assert_eq!(dnssec.signed, Some(true));
+        assert_eq!(dnssec.ds_records[0], DsRecord {
+            key_tag: 31589,
+            algorithm: 8,
+            digest_type: 2,
+            digest: "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90".into(),
+        });
+
+        let mut unsigned = Dnssec::default();
+        unsigned.set_value("unsigned").unwrap();
+        assert_eq!(unsigned.signed, Some(false));
+
+        assert!(DsRecord::parse("31589 8 2 abc").is_err());
+        unsigned.set_value("31589 8 2 abc").unwrap();
+        assert_eq!(unsigned, Dnssec { signed: Some(false), ds_records: Vec::new() });
+        assert!(DsRecord::parse("70000 8 2 a1").is_err());
+    }
+}
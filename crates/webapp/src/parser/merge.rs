@@ -0,0 +1,214 @@
+//! Merging of the WHOIS replies along a referral chain
+//!
+//! Thin registries only publish part of a record and refer to the registrar, whose thick reply
+//! overlaps with it. [MergePolicy::merge] combines the replies into one [WhoisInformation],
+//! remembering which server every field came from and which fields the servers disagree on.
+//!
+//! ### Example
+//! ```
+//! use webapp::parser::{merge::{MergePolicy, WhoisSource}, Parser};
+//!
+//! let parser = Parser::new();
+//! let chain = [
+//!     WhoisSource::new("whois.verisign-grs.com", parser.parse("Domain Name: SIMPAIX.NET\nRegistrar URL: http://registrar.example".into()).unwrap()),
+//!     WhoisSource::new("whois.registrar.example", parser.parse("Domain Name: simpaix.net\nRegistrar URL: https://registrar.example".into()).unwrap()),
+//! ];
+//!
+//! let merged = MergePolicy::default().merge(&chain);
+//! assert_eq!(merged.information.registrar_url.as_deref(), Some("https://registrar.example"));
+//! assert_eq!(merged.conflicts[0].field, "registrar_url");
+//! ```
+use std::{collections::{BTreeMap, HashMap}, fmt::Debug};
+use chrono::{DateTime, Utc};
+use super::{Dnssec, NameServers, WhoisInformation};
+
+/// Parsed reply of a single WHOIS server in the referral chain
+#[derive(Debug)]
+pub struct WhoisSource {
+    pub server: String,
+    pub information: WhoisInformation,
+}
+
+impl WhoisSource {
+    pub fn new(server: impl Into<String>, information: WhoisInformation) -> WhoisSource {
+        WhoisSource { server: server.into(), information }
+    }
+}
+
+/// Server of the referral chain that couldn't be queried or parsed
+#[derive(Debug)]
+pub struct HopError {
+    pub server: String,
+    pub error: Box<dyn std::error::Error>,
+}
+
+/// Replies of a referral chain, see [Whois::query_chain](crate::Whois::query_chain)
+#[derive(Debug, Default)]
+pub struct WhoisChain {
+    /// Ordered from registry to registrar
+    pub sources: Vec<WhoisSource>,
+    /// Server the chain broke off at, the replies before it are still in `sources`
+    pub failed: Option<HopError>,
+}
+
+/// Which reply wins when several servers publish the same field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precedence {
+    /// The first server of the chain that published the field, usually the registry
+    Registry,
+    /// The last server of the chain that published the field, usually the registrar
+    Registrar,
+}
+
+/// Precedence per field, fields are named after their [WhoisInformation] member
+#[derive(Debug, Clone)]
+pub struct MergePolicy {
+    pub default: Precedence,
+    pub fields: HashMap<&'static str, Precedence>,
+}
+
+/// Registries are authoritative for the registration itself, registrars for their own contact details
+impl Default for MergePolicy {
+    fn default() -> Self {
+        let registrar_fields = [
+            "registrar_whois_server",
+            "registrar_url",
+            "registrar_abuse_email_contact",
+            "registrar_abuse_phone_contact",
+            "registrant_organization",
+        ];
+
+        MergePolicy {
+            default: Precedence::Registry,
+            fields: registrar_fields.into_iter().map(|field| (field, Precedence::Registrar)).collect(),
+        }
+    }
+}
+
+/// Values of a field that differ between servers
+#[derive(Debug, Clone)]
+pub struct FieldConflict {
+    pub field: &'static str,
+    /// Server whose value was kept
+    pub chosen: String,
+    /// Every published value by server, formatted for display
+    pub values: Vec<(String, String)>,
+}
+
+/// Result of [MergePolicy::merge]
+#[derive(Debug, Default)]
+pub struct MergedWhois {
+    pub information: WhoisInformation,
+    /// Server each published field was taken from
+    pub provenance: BTreeMap<&'static str, String>,
+    pub conflicts: Vec<FieldConflict>,
+}
+
+/// Field value that can be compared between servers
+pub(super) trait MergeValue: Clone + Debug {
+    fn same(&self, other: &Self) -> bool;
+}
+
+impl MergeValue for String {
+    // registries and registrars don't agree on casing
+    fn same(&self, other: &Self) -> bool {
+        self.eq_ignore_ascii_case(other)
+    }
+}
+
+macro_rules! merge_value_eq {
+    ($($ty:ty),+) => {
+        $(
+            impl MergeValue for $ty {
+                fn same(&self, other: &Self) -> bool {
+                    self == other
+                }
+            }
+        )+
+    };
+}
+
+impl MergeValue for Vec<String> {
+    fn same(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().zip(other).all(|(a, b)| a.same(b))
+    }
+}
+
+merge_value_eq!(DateTime<Utc>, NameServers, Dnssec);
+
+impl MergePolicy {
+    /// Precedence of a single field
+    pub fn precedence(&self, field: &str) -> Precedence {
+        self.fields.get(field).copied().unwrap_or(self.default)
+    }
+
+    /// Merges the replies of a referral chain, ordered from the first queried server to the last
+    pub fn merge(&self, chain: &[WhoisSource]) -> MergedWhois {
+        let mut merged = MergedWhois::default();
+        // declared with WhoisInformation, every field is merged
+        self.merge_fields(chain, &mut merged);
+        merged
+    }
+
+    pub(super) fn merge_field<T: MergeValue>(
+        &self,
+        field: &'static str,
+        chain: &[WhoisSource],
+        merged: &mut MergedWhois,
+        get: impl Fn(&WhoisInformation) -> &Option<T>,
+    ) -> Option<T> {
+        let published: Vec<(&str, &T)> = chain.iter()
+            .filter_map(|source| get(&source.information).as_ref().map(|value| (source.server.as_str(), value)))
+            .collect();
+
+        let (server, value) = match self.precedence(field) {
+            Precedence::Registry => published.first(),
+            Precedence::Registrar => published.last(),
+        }.copied()?;
+
+        if published.iter().any(|(_, other)| !other.same(value)) {
+            merged.conflicts.push(FieldConflict {
+                field,
+                chosen: server.to_owned(),
+                values: published.iter().map(|(server, value)| (server.to_string(), format!("{value:?}"))).collect(),
+            });
+        }
+        merged.provenance.insert(field, server.to_owned());
+        Some(value.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_merge() {
+        let parser = Parser::new();
+        let registry = parser.parse("\
+Domain Name: SIMPAIX.NET
+Creation Date: 2021-03-04T10:11:12Z
+Registrar URL: http://registrar.example
+Name Server: NS1.EXAMPLE.COM".into()).unwrap();
+        let registrar = parser.parse("\
+Domain Name: simpaix.net
+Creation Date: 2021-03-05T10:11:12Z
+Registrar URL: https://registrar.example
+Registrant Organization: Example Org
+Name Server: ns1.example.com".into()).unwrap();
+
+        let merged = MergePolicy::default().merge(&[
+            WhoisSource::new("registry", registry),
+            WhoisSource::new("registrar", registrar),
+        ]);
+
+        assert_eq!(merged.information.domain_name.as_deref(), Some("SIMPAIX.NET"));
+        assert_eq!(merged.information.registrar_url.as_deref(), Some("https://registrar.example"));
+        assert_eq!(merged.provenance["registrant_organization"], "registrar");
+        assert_eq!(merged.provenance["creation_date"], "registry");
+
+        let conflicts: Vec<_> = merged.conflicts.iter().map(|c| (c.field, c.chosen.as_str())).collect();
+        assert_eq!(conflicts, [("registrar_url", "registrar"), ("creation_date", "registry")]);
+    }
+}
@@ -0,0 +1,117 @@
+//! Normalization of raw WHOIS responses
+//!
+//! Registries wrap the actual record in boilerplate: comment lines (`%`, `#`), legal notices,
+//! a `>>> Last update of WHOIS database: ... <<<` trailer and CRLF line endings.
+//! [normalize] separates the record body from all of that so it can be handed to the [Parser](super::Parser).
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+
+const LAST_UPDATE_PREFIX: &str = ">>> last update of whois database:";
+// keys longer than this are sentences of a notice that happen to contain a colon
+const MAX_KEY_LEN: usize = 64;
+
+/// WHOIS response split into the record body and its boilerplate
+#[derive(Debug, Default)]
+pub struct NormalizedResponse {
+    /// `key: value` lines of the record, `\n` separated
+    pub body: String,
+    /// Comments, legal notices and other text that isn't part of the record, `\n` separated
+    pub notice: String,
+    /// Timestamp of the `>>> Last update of WHOIS database` trailer
+    pub last_updated: Option<DateTime<Utc>>,
+}
+
+/// Normalizes a raw WHOIS response.
+///
+/// ### Example
+/// ```
+/// use webapp::parser::{normalize::normalize, Parser};
+///
+/// let raw = "% comment\r\nDomain Name: SIMPAIX.NET\r\n>>> Last update of WHOIS database: 2024-05-01T12:34:56Z <<<\r\n\r\nTERMS OF USE: ...\r\n";
+/// let normalized = normalize(raw);
+///
+/// assert_eq!(normalized.body, "Domain Name: SIMPAIX.NET");
+/// assert!(normalized.last_updated.is_some());
+///
+/// let info = Parser::new().parse(normalized.body).unwrap();
+/// assert_eq!(info.domain_name.as_deref(), Some("SIMPAIX.NET"));
+/// ```
+pub fn normalize(raw: &str) -> NormalizedResponse {
+    let mut normalized = NormalizedResponse::default();
+    let mut trailer_seen = false;
+
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix(['%', '#']) {
+            push_line(&mut normalized.notice, comment.trim());
+        } else if is_last_update(line) {
+            trailer_seen = true;
+            normalized.last_updated = parse_last_update(line);
+        } else if !trailer_seen && is_record_line(line) {
+            push_line(&mut normalized.body, line);
+        } else {
+            // everything after the trailer is legal text
+            push_line(&mut normalized.notice, line);
+        }
+    }
+    normalized
+}
+
+fn push_line(out: &mut String, line: &str) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(line);
+}
+
+fn is_last_update(line: &str) -> bool {
+    line.get(..LAST_UPDATE_PREFIX.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(LAST_UPDATE_PREFIX))
+}
+
+fn parse_last_update(line: &str) -> Option<DateTime<Utc>> {
+    let value = line[LAST_UPDATE_PREFIX.len()..].trim().trim_end_matches('<').trim();
+    DateTime::<Utc>::from_str(value).ok()
+}
+
+fn is_record_line(line: &str) -> bool {
+    match line.split_once(":") {
+        // a value starting with "//" means the colon belongs to an URL inside a sentence
+        Some((key, value)) => {
+            let key = key.trim();
+            !key.is_empty() && key.len() <= MAX_KEY_LEN && !value.starts_with("//")
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &str = "\
+% IANA WHOIS server\r
+# comment line\r
+Domain Name: SIMPAIX.NET\r
+Registrar URL: http://www.example.com\r
+For more information on Whois status codes, please visit https://icann.org/epp\r
+>>> Last update of WHOIS database: 2024-05-01T12:34:56Z <<<\r
+\r
+NOTICE: The expiration date displayed in this record is the date the registrar's sponsorship expires.\r
+";
+
+    #[test]
+    fn test_normalize() {
+        let normalized = normalize(RAW);
+
+        assert_eq!(normalized.body, "Domain Name: SIMPAIX.NET\nRegistrar URL: http://www.example.com");
+        assert_eq!(normalized.last_updated, Some(DateTime::<Utc>::from_str("2024-05-01T12:34:56Z").unwrap()));
+        assert!(normalized.notice.starts_with("IANA WHOIS server\ncomment line\nFor more information"));
+        assert!(normalized.notice.ends_with("sponsorship expires."));
+        assert!(!normalized.body.contains('\r'));
+    }
+}
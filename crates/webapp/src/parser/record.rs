@@ -0,0 +1,93 @@
+//! Declarative WHOIS records
+//!
+//! Any struct deriving [WhoisRecord](macro@super::WhoisRecord) can be filled by the [Parser](super::Parser).
+//! The derive maps WHOIS keys onto fields, the field type decides how the value is converted through [WhoisField].
+//!
+//! ### Example
+//! ```
+//! use webapp::parser::{DateTime, Parser, Utc, WhoisRecord};
+//!
+//! #[derive(Default, WhoisRecord)]
+//! struct Expiry {
+//!     #[whois(key = "Registry Expiry Date", alias = "Registrar Registration Expiration Date")]
+//!     expires: Option<DateTime<Utc>>,
+//!     #[whois(key = "Registrar IANA ID")]
+//!     iana_id: Option<u32>,
+//! }
+//!
+//! let record: Expiry = Parser::new().parse_record("Registrar IANA ID: 1068\nRegistry Expiry Date: 2030-01-01T00:00:00Z").unwrap();
+//! assert_eq!(record.iana_id, Some(1068));
+//! ```
+use std::{error::Error, str::FromStr};
+use chrono::{DateTime, Utc};
+
+/// Record that can be filled from WHOIS `key: value` lines, usually derived
+pub trait WhoisRecord<'a>: Default {
+    /// Applies a single `key: value` line to the record, unknown keys are ignored
+    fn apply_field(&mut self, key: &str, value: &'a str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Field of a [WhoisRecord], converts the WHOIS value into itself
+pub trait WhoisField<'a> {
+    /// Sets the field from a trimmed WHOIS value
+    fn set_value(&mut self, value: &'a str) -> Result<(), Box<dyn Error>>;
+}
+
+impl<'a> WhoisField<'a> for &'a str {
+    fn set_value(&mut self, value: &'a str) -> Result<(), Box<dyn Error>> {
+        *self = value;
+        Ok(())
+    }
+}
+
+impl WhoisField<'_> for String {
+    fn set_value(&mut self, value: &str) -> Result<(), Box<dyn Error>> {
+        value.clone_into(self);
+        Ok(())
+    }
+}
+
+impl WhoisField<'_> for DateTime<Utc> {
+    // whois datetimes are expressed in UTC
+    fn set_value(&mut self, value: &str) -> Result<(), Box<dyn Error>> {
+        *self = DateTime::<Utc>::from_str(value)?;
+        Ok(())
+    }
+}
+
+macro_rules! whois_field_from_str {
+    ($($ty:ty),+) => {
+        $(
+            impl WhoisField<'_> for $ty {
+                fn set_value(&mut self, value: &str) -> Result<(), Box<dyn Error>> {
+                    *self = value.parse()?;
+                    Ok(())
+                }
+            }
+        )+
+    };
+}
+
+whois_field_from_str!(u16, u32, u64, i32, i64, usize);
+
+/// Set on every occurrence of the key, a value that fails to convert is skipped and keeps the
+/// previous one, or `None`
+impl<'a, T: WhoisField<'a> + Default> WhoisField<'a> for Option<T> {
+    fn set_value(&mut self, value: &'a str) -> Result<(), Box<dyn Error>> {
+        let previous = self.is_some();
+        if self.get_or_insert_with(T::default).set_value(value).is_err() && !previous {
+            *self = None;
+        }
+        Ok(())
+    }
+}
+
+/// Collects every occurrence of the key
+impl<'a, T: WhoisField<'a> + Default> WhoisField<'a> for Vec<T> {
+    fn set_value(&mut self, value: &'a str) -> Result<(), Box<dyn Error>> {
+        let mut field = T::default();
+        field.set_value(value)?;
+        self.push(field);
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! WHOIS heuristics for phishing triage
+//!
+//! [WhoisInformation] exposes the raw signals (domain age, expiry, recent changes, privacy proxies),
+//! [RiskConfig] weighs them into a [RiskScore] that explains every signal that contributed.
+//!
+//! ### Example
+//! ```
+//! use webapp::parser::{risk::RiskConfig, Parser, Utc};
+//!
+//! let info = Parser::new().parse("Creation Date: 2024-05-01T00:00:00Z\nRegistrar: Example Registrar".into()).unwrap();
+//! let score = RiskConfig::default().score(&info, "2024-05-03T00:00:00Z".parse().unwrap());
+//!
+//! assert!(score.score > 0);
+//! for reason in &score.reasons {
+//!     println!("+{} {}", reason.weight, reason.explanation);
+//! }
+//! ```
+use chrono::{DateTime, TimeDelta, Utc};
+use super::WhoisInformation;
+
+impl<S: AsRef<str>> WhoisInformation<S> {
+    /// Time since the domain was created, zero for a creation date in the future
+    pub fn domain_age(&self, now: DateTime<Utc>) -> Option<TimeDelta> {
+        self.creation_date.map(|created| (now - created).max(TimeDelta::zero()))
+    }
+
+    /// Whole days until the registration expires, negative once expired
+    pub fn days_until_expiry(&self, now: DateTime<Utc>) -> Option<i64> {
+        self.registry_expirity_date.map(|expires| (expires - now).num_days())
+    }
+
+    /// Whether the record was updated within `window` before `now`, a date in the future doesn't count
+    pub fn recently_updated(&self, now: DateTime<Utc>, window: TimeDelta) -> bool {
+        self.updated_date.is_some_and(|updated| updated <= now && now - updated <= window)
+    }
+
+    /// Whether the domain is being or just has been transferred to another registrar.
+    ///
+    /// WHOIS has no transfer date, so this relies on the `pendingTransfer` and `transferPeriod` EPP statuses.
+    pub fn recently_transferred(&self) -> bool {
+        self.domain_status.iter().flatten().any(|status| {
+            let status = status.as_ref().to_ascii_lowercase();
+            status.contains("pendingtransfer") || status.contains("transferperiod")
+        })
+    }
+
+    /// Whether the registrar or registrant organization is a privacy or proxy service.
+    ///
+    /// Registries redact personal data since the GDPR (`REDACTED FOR PRIVACY`), which says nothing
+    /// about the registrant and never matches.
+    pub fn uses_privacy_proxy(&self, keywords: &[impl AsRef<str>]) -> bool {
+        [&self.registrar, &self.registrant_organization].into_iter().flatten().any(|name| {
+            let name = name.as_ref().to_lowercase();
+            !name.contains("redacted") && keywords.iter().any(|keyword| name.contains(&keyword.as_ref().to_lowercase()))
+        })
+    }
+}
+
+/// Signal that contributed to a [RiskScore]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+pub enum RiskSignal {
+    YoungDomain,
+    ExpiresSoon,
+    RecentlyUpdated,
+    RecentlyTransferred,
+    PrivacyProxy,
+    MissingCreationDate,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct RiskReason {
+    pub signal: RiskSignal,
+    pub weight: u32,
+    pub explanation: String,
+}
+
+/// Risk of a domain between 0 and [RiskConfig::max_score], with the reasons behind it
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct RiskScore {
+    pub score: u32,
+    pub reasons: Vec<RiskReason>,
+}
+
+/// Thresholds and weights of the risk signals, a weight of 0 disables the signal
+#[derive(Debug, Clone)]
+pub struct RiskConfig {
+    pub young_domain_age: TimeDelta,
+    pub young_domain_weight: u32,
+    pub expires_within_days: i64,
+    pub expires_soon_weight: u32,
+    pub recent_update_window: TimeDelta,
+    pub recently_updated_weight: u32,
+    pub recently_transferred_weight: u32,
+    /// Registrars and registrant organizations that hide the real registrant, matched case-insensitively
+    pub privacy_proxy_keywords: Vec<String>,
+    pub privacy_proxy_weight: u32,
+    pub missing_creation_date_weight: u32,
+    pub max_score: u32,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        RiskConfig {
+            young_domain_age: TimeDelta::days(30),
+            young_domain_weight: 40,
+            expires_within_days: 30,
+            expires_soon_weight: 10,
+            recent_update_window: TimeDelta::days(7),
+            recently_updated_weight: 15,
+            recently_transferred_weight: 20,
+            privacy_proxy_keywords: [
+                "privacy",
+                "proxy",
+                "withheld",
+                "whoisguard",
+                "contact privacy",
+                "perfect privacy",
+                "data protected",
+            ].map(String::from).to_vec(),
+            privacy_proxy_weight: 15,
+            missing_creation_date_weight: 10,
+            max_score: 100,
+        }
+    }
+}
+
+impl RiskConfig {
+    /// Scores the WHOIS information at the moment `now`
+    pub fn score<S: AsRef<str>>(&self, info: &WhoisInformation<S>, now: DateTime<Utc>) -> RiskScore {
+        let mut reasons = Vec::new();
+        let mut add = |signal, weight: u32, explanation: String| {
+            if weight > 0 {
+                reasons.push(RiskReason { signal, weight, explanation });
+            }
+        };
+
+        match info.domain_age(now) {
+            Some(age) if age < self.young_domain_age => add(
+                RiskSignal::YoungDomain,
+                self.young_domain_weight,
+                format!("domain was registered {} days ago", age.num_days()),
+            ),
+            Some(_) => {}
+            None => add(
+                RiskSignal::MissingCreationDate,
+                self.missing_creation_date_weight,
+                "registry didn't publish a creation date".into(),
+            ),
+        }
+
+        if let Some(days) = info.days_until_expiry(now).filter(|days| *days <= self.expires_within_days) {
+            let explanation = match days {
+                0.. => format!("registration expires in {days} days"),
+                _ => format!("registration expired {} days ago", -days),
+            };
+            add(RiskSignal::ExpiresSoon, self.expires_soon_weight, explanation);
+        }
+
+        if info.recently_updated(now, self.recent_update_window) {
+            add(
+                RiskSignal::RecentlyUpdated,
+                self.recently_updated_weight,
+                format!("record was updated within the last {} days", self.recent_update_window.num_days()),
+            );
+        }
+
+        if info.recently_transferred() {
+            add(
+                RiskSignal::RecentlyTransferred,
+                self.recently_transferred_weight,
+                "domain is in a transfer status".into(),
+            );
+        }
+
+        if info.uses_privacy_proxy(&self.privacy_proxy_keywords) {
+            add(
+                RiskSignal::PrivacyProxy,
+                self.privacy_proxy_weight,
+                "registrant is hidden behind a privacy or proxy service".into(),
+            );
+        }
+
+        let score = reasons.iter().map(|r| r.weight).sum::<u32>().min(self.max_score);
+        RiskScore { score, reasons }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_score() {
+        let info = Parser::new().parse("\
+Updated Date: 2024-05-01T00:00:00Z
+Creation Date: 2024-04-20T00:00:00Z
+Registry Expiry Date: 2025-04-20T00:00:00Z
+Domain Status: pendingTransfer https://icann.org/epp#pendingTransfer
+Registrant Organization: Domains By Proxy, LLC".into()).unwrap();
+        let now = "2024-05-02T00:00:00Z".parse().unwrap();
+
+        assert_eq!(info.domain_age(now), Some(TimeDelta::days(12)));
+        assert_eq!(info.days_until_expiry(now), Some(353));
+
+        let score = RiskConfig::default().score(&info, now);
+        let signals: Vec<_> = score.reasons.iter().map(|r| r.signal).collect();
+        assert_eq!(signals, [RiskSignal::YoungDomain, RiskSignal::RecentlyUpdated, RiskSignal::RecentlyTransferred, RiskSignal::PrivacyProxy]);
+        assert_eq!(score.score, 90);
+
+        let lenient = RiskConfig { young_domain_weight: 0, max_score: 20, ..RiskConfig::default() };
+        assert_eq!(lenient.score(&info, now).score, 20);
+    }
+
+    #[test]
+    fn test_signals() {
+        let info = Parser::new().parse("\
+Updated Date: 2024-06-01T00:00:00Z
+Domain Status: pendingTransfer https://icann.org/epp#pendingTransfer
+Domain Status: clientDeleteProhibited https://icann.org/epp#clientDeleteProhibited
+Registrant Organization: REDACTED FOR PRIVACY".into()).unwrap();
+        let now = "2024-05-02T00:00:00Z".parse().unwrap();
+
+        assert!(!info.recently_updated(now, TimeDelta::days(7)), "an update in the future");
+        assert!(info.recently_transferred(), "the transfer status isn't the last one");
+        let config = RiskConfig::default();
+        assert!(!info.uses_privacy_proxy(&config.privacy_proxy_keywords), "GDPR redaction");
+
+        let custom = RiskConfig { privacy_proxy_keywords: vec!["Example Shield".into()], ..RiskConfig::default() };
+        let shielded = Parser::new().parse("Registrar: Example Shield Inc.".into()).unwrap();
+        assert!(shielded.uses_privacy_proxy(&custom.privacy_proxy_keywords));
+        assert!(!shielded.uses_privacy_proxy(&config.privacy_proxy_keywords));
+
+        let expired = Parser::new().parse("\
+Creation Date: 2024-05-10T00:00:00Z
+Registry Expiry Date: 2024-04-20T00:00:00Z".into()).unwrap();
+        assert_eq!(expired.domain_age(now), Some(TimeDelta::zero()), "a creation date in the future");
+        let explanations: Vec<_> = config.score(&expired, now).reasons.into_iter().map(|r| r.explanation).collect();
+        assert_eq!(explanations, ["domain was registered 0 days ago", "registration expired 12 days ago"]);
+    }
+}
@@ -0,0 +1,291 @@
+//! Static assets with content-hash fingerprinted URLs.
+//!
+//! Templates link assets through the `asset_url()` function [Assets::register] adds,
+//! `asset_url("css/app.css")` renders `/static/css/app.0123456789abcdef.css` where the
+//! fingerprint is derived from the file content. A fingerprinted URL never serves different
+//! content, so [Assets::router] answers it with an immutable `Cache-Control` for a year; plain
+//! URLs have to be revalidated. Precompressed `.br`, `.zst` and `.gz` siblings are served to
+//! clients accepting them.
+//!
+//! Hashing reads the whole file, so it never runs on the async runtime: templates render the
+//! fingerprint known so far and refresh it on the blocking thread pool, an asset without one yet
+//! gets its plain URL.
+//!
+//! ```ignore
+//! let assets = Assets::new("assets", "/static");
+//! let views = Views::embedded(&VIEWS, move |env| assets.register(env))?;
+//! let app = Router::new().nest("/static", assets.router());
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use minijinja::Environment;
+use sha2::{Digest, Sha256};
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
+use super::error::AppError;
+
+/// Hex digits of the content hash in fingerprinted file names
+const FINGERPRINT_LEN: usize = 16;
+
+const IMMUTABLE: HeaderValue = HeaderValue::from_static("public, max-age=31536000, immutable");
+const REVALIDATE: HeaderValue = HeaderValue::from_static("no-cache");
+/// How long templates use a fingerprint before checking the file again
+const RECHECK_AFTER: Duration = Duration::from_secs(2);
+
+// fingerprints are recomputed when the file's modification time or size changes
+struct Cached {
+    modified: SystemTime,
+    len: u64,
+    fingerprint: String,
+    checked: Instant,
+}
+
+struct Inner {
+    dir: PathBuf,
+    mount: String,
+    serve: ServeDir,
+    cache: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+/// Static asset directory and its fingerprints, cheap to clone
+#[derive(Clone)]
+pub struct Assets {
+    inner: Arc<Inner>,
+}
+
+impl Assets {
+    /// Assets in `dir`, served under `mount`
+    pub fn new(dir: impl Into<PathBuf>, mount: &str) -> Assets {
+        let dir = dir.into();
+        let serve = ServeDir::new(&dir)
+            .precompressed_br()
+            .precompressed_zstd()
+            .precompressed_gzip();
+        Assets { inner: Arc::new(Inner { dir, mount: mount.trim_end_matches('/').to_owned(), serve, cache: Mutex::default() }) }
+    }
+
+    /// Content hash of the asset at the relative `path`, reads the file when it changed.
+    ///
+    /// This blocks, async code calls it through `spawn_blocking`.
+    pub fn fingerprint(&self, path: &str) -> io::Result<String> {
+        let file = self.file(path)?;
+        let metadata = fs::metadata(&file)?;
+        let modified = metadata.modified()?;
+
+        let cached = self.inner.cache.lock().unwrap().get_mut(&file)
+            .filter(|c| c.modified == modified && c.len == metadata.len())
+            .map(|cached| {
+                cached.checked = Instant::now();
+                cached.fingerprint.clone()
+            });
+        if let Some(fingerprint) = cached {
+            return Ok(fingerprint);
+        }
+
+        let digest = Sha256::digest(fs::read(&file)?);
+        let fingerprint = digest.iter().map(|b| format!("{b:02x}")).collect::<String>()[..FINGERPRINT_LEN].to_owned();
+        let cached = Cached { modified, len: metadata.len(), fingerprint: fingerprint.clone(), checked: Instant::now() };
+        self.inner.cache.lock().unwrap().insert(file, cached);
+        Ok(fingerprint)
+    }
+
+    /// Fingerprinted URL of the asset at `path`, the plain URL while it has no fingerprint yet.
+    ///
+    /// Only looks at the fingerprints computed so far, outdated or missing ones are computed on
+    /// the blocking thread pool for later calls. Outside of a Tokio runtime they're computed
+    /// right away.
+    pub fn url(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+        let plain = || format!("{}/{path}", self.inner.mount);
+        let Ok(file) = self.file(path) else { return plain() };
+
+        let cached = self.inner.cache.lock().unwrap().get(&file)
+            .map(|cached| (cached.fingerprint.clone(), cached.checked.elapsed() >= RECHECK_AFTER));
+        let fingerprint = match (cached, tokio::runtime::Handle::try_current()) {
+            (Some((fingerprint, false)), _) => Some(fingerprint),
+            (cached, Ok(runtime)) => {
+                let (assets, path) = (self.clone(), path.to_owned());
+                runtime.spawn_blocking(move || assets.refresh(&path));
+                cached.map(|(fingerprint, _)| fingerprint)
+            }
+            (_, Err(_)) => self.refresh(path),
+        };
+        match fingerprint {
+            Some(fingerprint) => format!("{}/{}", self.inner.mount, with_fingerprint(path, &fingerprint)),
+            None => plain(),
+        }
+    }
+
+    fn refresh(&self, path: &str) -> Option<String> {
+        self.fingerprint(path)
+            .inspect_err(|err| tracing::warn!("no fingerprint for asset {path}: {err}"))
+            .ok()
+    }
+
+    fn file(&self, path: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'));
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{path} leaves the asset directory")));
+        }
+        Ok(self.inner.dir.join(relative))
+    }
+
+    /// Adds the `asset_url(path)` function
+    pub fn register(&self, env: &mut Environment<'static>) {
+        let assets = self.clone();
+        env.add_function("asset_url", move |path: &str| assets.url(path));
+    }
+
+    /// Serves the assets, to be nested under the mount path
+    pub fn router<S>(&self) -> Router<S> {
+        Router::new().fallback(serve_asset).with_state(self.clone())
+    }
+}
+
+/// Inserts the fingerprint before the extension: `css/app.css` becomes `css/app.<fingerprint>.css`
+fn with_fingerprint(path: &str, fingerprint: &str) -> String {
+    let (dir, name) = path.rsplit_once('/').map_or(("", path), |(dir, name)| (dir, name));
+    let name = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem}.{fingerprint}.{ext}"),
+        _ => format!("{name}.{fingerprint}"),
+    };
+    if dir.is_empty() { name } else { format!("{dir}/{name}") }
+}
+
+/// Splits a fingerprinted path into the plain path and the fingerprint
+fn split_fingerprint(path: &str) -> Option<(String, &str)> {
+    let (dir, name) = path.rsplit_once('/').map_or(("", path), |(dir, name)| (dir, name));
+    let parts: Vec<_> = name.split('.').collect();
+    let is_fingerprint = |part: &str| part.len() == FINGERPRINT_LEN && part.bytes().all(|b| b.is_ascii_hexdigit());
+    let at = match parts.len() {
+        0 | 1 => return None,
+        2 => 1,
+        len => len - 2,
+    };
+    if !is_fingerprint(parts[at]) {
+        return None;
+    }
+    let fingerprint = parts[at];
+    let name = parts.iter().enumerate().filter(|&(i, _)| i != at).map(|(_, part)| *part).collect::<Vec<_>>().join(".");
+    Some((if dir.is_empty() { name } else { format!("{dir}/{name}") }, fingerprint))
+}
+
+async fn serve_asset(State(assets): State<Assets>, mut req: Request) -> Response {
+    let path = req.uri().path().trim_start_matches('/').to_owned();
+    let mut cache_control = REVALIDATE;
+
+    if let Some((plain, fingerprint)) = split_fingerprint(&path) {
+        // an outdated fingerprint still gets the current content, it just can't be cached for good
+        let (current, checked) = (assets.clone(), plain.clone());
+        let current = tokio::task::spawn_blocking(move || current.fingerprint(&checked)).await;
+        if current.is_ok_and(|current| current.is_ok_and(|current| current == fingerprint)) {
+            cache_control = IMMUTABLE;
+        }
+        let uri = match req.uri().query() {
+            Some(query) => format!("/{plain}?{query}"),
+            None => format!("/{plain}"),
+        };
+        match uri.parse::<Uri>() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => return AppError::NotFound.into_response(),
+        }
+    }
+
+    let Ok(res) = assets.inner.serve.clone().oneshot(req).await;
+    if res.status() == StatusCode::NOT_FOUND {
+        return AppError::NotFound.into_response();
+    }
+    let mut res = res.map(Body::new);
+    if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
+        res.headers_mut().insert(header::CACHE_CONTROL, cache_control);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+
+    #[test]
+    fn test_fingerprint_names() {
+        assert_eq!(with_fingerprint("css/app.css", "0123456789abcdef"), "css/app.0123456789abcdef.css");
+        assert_eq!(with_fingerprint("LICENSE", "0123456789abcdef"), "LICENSE.0123456789abcdef");
+        assert_eq!(split_fingerprint("css/app.0123456789abcdef.css"), Some(("css/app.css".into(), "0123456789abcdef")));
+        assert_eq!(split_fingerprint("LICENSE.0123456789abcdef"), Some(("LICENSE".into(), "0123456789abcdef")));
+        assert_eq!(split_fingerprint("css/app.css"), None);
+        assert_eq!(split_fingerprint("app.min.css"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("css")).unwrap();
+        fs::write(dir.path().join("css/app.css"), "body { color: red }").unwrap();
+        fs::write(dir.path().join("css/app.css.gz"), "not really gzip").unwrap();
+        let assets = Assets::new(dir.path(), "/static/");
+        let app: Router = Router::new().nest("/static", assets.router());
+
+        let mut env = Environment::new();
+        assets.register(&mut env);
+        assert_eq!(env.render_str("{{ asset_url('css/app.css') }}", ()).unwrap(), "/static/css/app.css", "not hashed yet");
+        let fingerprint = tokio::task::spawn_blocking({
+            let assets = assets.clone();
+            move || assets.fingerprint("css/app.css")
+        }).await.unwrap().unwrap();
+        let url = env.render_str("{{ asset_url('css/app.css') }}", ()).unwrap();
+        assert_eq!(url, format!("/static/css/app.{fingerprint}.css"));
+        assert_eq!(env.render_str("{{ asset_url('missing.js') }}", ()).unwrap(), "/static/missing.js");
+        assert!(assets.fingerprint("../app.css").is_err());
+
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(get(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "public, max-age=31536000, immutable");
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        assert_eq!(body, "body { color: red }");
+
+        let res = app.clone().oneshot(get("/static/css/app.css")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+
+        let res = app.clone().oneshot(get("/static/css/app.ffffffffffffffff.css")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+
+        let req = Request::get(&url).header(ACCEPT_ENCODING, "br, gzip").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        assert_eq!(body, "not really gzip");
+
+        let res = app.oneshot(get("/static/css/missing.css")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_url_outside_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("app.js"), "alert(1)").unwrap();
+        let assets = Assets::new(dir.path(), "/static");
+
+        let fingerprint = assets.fingerprint("app.js").unwrap();
+        assert_eq!(assets.url("app.js"), format!("/static/app.{fingerprint}.js"));
+        fs::write(dir.path().join("app.js"), "alert(2) // longer").unwrap();
+        assert_ne!(assets.fingerprint("app.js").unwrap(), fingerprint, "changed files are hashed again");
+    }
+}
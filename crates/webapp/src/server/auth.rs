@@ -0,0 +1,335 @@
+//! Cookie based authentication on top of [sessions](super::session)
+//!
+//! - [Users] holds the accounts and their argon2 password hashes, see [hash_password]
+//! - [Auth::router] serves the `/login` page and form, and `/logout`
+//! - [CurrentUser] extracts the signed in user, requests without one are rejected with
+//!   [AppError::Unauthorized]. `Option<CurrentUser>` serves anonymous requests as well.
+//! - [require_user_or_scopes] lets signed in users and API clients with the right bearer scopes in
+//!
+//! Signing in regenerates the session, the ID a client had before is worthless afterwards.
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Form, Router,
+};
+use serde::{Deserialize, Serialize};
+use crate::template;
+use super::{bearer::{require_scopes, RequiredScopes}, error::AppError, i18n::Locale, session::Session, views::Views};
+
+/// Session key of the signed in user
+const USER_KEY: &str = "user";
+
+/// Hashes a password for [Users], as a PHC string (`$argon2id$v=19$...`)
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    // the salt only has to be unique, uuid's 122 random bits are plenty
+    let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())?;
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// Checks `password` against a hash of [hash_password], malformed hashes never match
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// Signed in user, as stored in the session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+}
+
+/// Accounts by name, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct Users {
+    hashes: Arc<HashMap<String, String>>,
+}
+
+impl Users {
+    /// Takes pairs of user name and password hash
+    pub fn new(users: impl IntoIterator<Item = (String, String)>) -> Users {
+        Users { hashes: Arc::new(users.into_iter().collect()) }
+    }
+
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<User> {
+        match self.hashes.get(name) {
+            Some(hash) => verify_password(password, hash).then(|| User { name: name.to_owned() }),
+            None => {
+                // takes as long as a wrong password, response times don't tell which names exist
+                static DUMMY: OnceLock<String> = OnceLock::new();
+                let dummy = DUMMY.get_or_init(|| hash_password("dummy").expect("hashing a fixed password"));
+                verify_password(password, dummy);
+                None
+            }
+        }
+    }
+}
+
+/// The signed in user of the request
+#[derive(Debug, Clone)]
+pub struct CurrentUser(pub User);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state).await?;
+        session.get::<User>(USER_KEY).map(CurrentUser).ok_or(AppError::Unauthorized)
+    }
+}
+
+/// Passes signed in users, anyone else needs a bearer token with the required scopes, see
+/// [require_scopes]. Use it as a `route_layer`.
+pub async fn require_user_or_scopes(
+    State(required): State<RequiredScopes>,
+    user: Option<CurrentUser>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    match user {
+        Some(_) => Ok(next.run(req).await),
+        None => require_scopes(State(required), req, next).await,
+    }
+}
+
+/// Login and logout pages, cheap to clone
+#[derive(Clone)]
+pub struct Auth {
+    users: Users,
+    views: Views,
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+    csrf_token: String,
+}
+
+impl Auth {
+    pub fn new(users: Users, views: Views) -> Auth {
+        Auth { users, views }
+    }
+
+    /// `GET /login`, `POST /login` and `POST /logout`, the routes need the session middleware.
+    /// Signing in redirects to the `next` query parameter when it's a local path.
+    pub fn router<S: Clone + Send + Sync + 'static>(&self) -> Router<S> {
+        Router::new()
+            .route("/login", get(login_page).post(login))
+            .route("/logout", post(logout))
+            .with_state(self.clone())
+    }
+
+    fn render(&self, session: &Session, locale: Option<Locale>, username: &str, error: Option<&str>) -> Result<Html<String>, AppError> {
+        let page = template!(self.views.env(), "login.html", {
+            user => session.get::<User>(USER_KEY).map(|user| user.name),
+            csrf_token => session.csrf_token(),
+            username => username,
+            error => error,
+            locale => locale.map(|locale| locale.to_string())
+        }, error = AppError, globals = [t])?;
+        Ok(Html(page))
+    }
+}
+
+/// Local path to continue at after signing in, anything else could send users to another site
+fn redirect_target(next: Option<&str>) -> &str {
+    next.filter(|next| next.starts_with('/') && !next.starts_with("//") && !next.starts_with("/\\"))
+        .unwrap_or("/")
+}
+
+async fn login_page(State(auth): State<Auth>, session: Session, locale: Option<Extension<Locale>>) -> Result<Html<String>, AppError> {
+    auth.render(&session, locale.map(|Extension(locale)| locale), "", None)
+}
+
+async fn login(
+    State(auth): State<Auth>,
+    session: Session,
+    locale: Option<Extension<Locale>>,
+    Query(query): Query<LoginQuery>,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, AppError> {
+    let locale = locale.map(|Extension(locale)| locale);
+
+    // also checked by verify_csrf, but that lets requests without a session pass
+    if !session.verify_csrf(&form.csrf_token) {
+        let page = auth.render(&session, locale, &form.username, Some("login-expired"))?;
+        return Ok((StatusCode::FORBIDDEN, page).into_response());
+    }
+
+    // argon2 is deliberately slow, it must not stall the runtime
+    let users = auth.users.clone();
+    let (username, password) = (form.username.clone(), form.password);
+    let user = tokio::task::spawn_blocking(move || users.authenticate(&username, &password)).await
+        .map_err(|err| AppError::OopsError{err: err.to_string()})?;
+
+    let Some(user) = user else {
+        let page = auth.render(&session, locale, &form.username, Some("login-failed"))?;
+        return Ok((StatusCode::UNAUTHORIZED, page).into_response());
+    };
+
+    session.regenerate();
+    session.insert(USER_KEY, user)?;
+    Ok(Redirect::to(redirect_target(query.next.as_deref())).into_response())
+}
+
+async fn logout(session: Session) -> Redirect {
+    session.destroy();
+    Redirect::to("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{header, Method},
+        middleware,
+    };
+    use include_dir::{include_dir, Dir};
+    use tower::ServiceExt;
+    use sha2::Digest;
+    use crate::server::{bearer::{BearerAuth, BearerLayer}, i18n::Catalogs, session::{load_session, verify_csrf, MemoryStore, Sessions}};
+
+    static VIEWS: Dir = include_dir!("$CARGO_MANIFEST_DIR/views");
+
+    fn app(users: Users) -> Router {
+        let bearer = BearerAuth::new().with_api_key("ci", &format!("{:x}", sha2::Sha256::digest(b"key-123")), ["uploads:write"]).unwrap();
+        app_with_bearer(users, Some(bearer))
+    }
+
+    fn app_with_bearer(users: Users, bearer: Option<BearerAuth>) -> Router {
+        let catalogs = Catalogs::load(concat!(env!("CARGO_MANIFEST_DIR"), "/locales"), "en").unwrap();
+        let views = Views::embedded(&VIEWS, move |env| catalogs.register(env)).unwrap();
+        let sessions = Sessions::new(MemoryStore::default(), cookie::Key::generate());
+
+        let mut app = Router::new()
+            .route("/upload", post(|| async { "uploaded" }))
+            .route_layer(middleware::from_fn_with_state(RequiredScopes::new(["uploads:write"]), require_user_or_scopes))
+            .route("/me", get(|CurrentUser(user): CurrentUser| async move { user.name }))
+            .merge(Auth::new(users, views).router());
+        if let Some(bearer) = bearer {
+            app = app.layer(BearerLayer::new(bearer));
+        }
+        app.layer(middleware::from_fn(verify_csrf))
+            .layer(middleware::from_fn_with_state(sessions, load_session))
+    }
+
+    async fn send(app: &Router, method: Method, uri: &str, cookie: &str, form: Option<String>) -> Response {
+        let mut req = Request::builder().method(method).uri(uri).header(header::COOKIE, cookie);
+        if form.is_some() {
+            req = req.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+        }
+        app.clone().oneshot(req.body(form.map_or_else(Body::empty, Body::from)).unwrap()).await.unwrap()
+    }
+
+    fn set_cookie(res: &Response) -> String {
+        res.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_owned()
+    }
+
+    async fn body(res: Response) -> String {
+        String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+    }
+
+    fn csrf_token(page: &str) -> String {
+        let (_, rest) = page.split_once("name=\"csrf_token\" value=\"").unwrap();
+        rest.split('"').next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_passwords() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        assert_eq!(redirect_target(Some("/media?a=1")), "/media?a=1");
+        assert_eq!(redirect_target(Some("//evil.example")), "/");
+        assert_eq!(redirect_target(Some("/\\evil.example")), "/");
+        assert_eq!(redirect_target(Some("https://evil.example")), "/");
+        assert_eq!(redirect_target(None), "/");
+    }
+
+    #[tokio::test]
+    async fn test_upload_access() {
+        let upload = |app: &Router, authorization: Option<&'static str>| {
+            let mut req = Request::post("/upload");
+            if let Some(authorization) = authorization {
+                req = req.header(header::AUTHORIZATION, authorization);
+            }
+            let app = app.clone();
+            async move { app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap().status() }
+        };
+
+        let app = app(Users::default());
+        assert_eq!(upload(&app, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(upload(&app, Some("Basic YWxpY2U6c2VjcmV0")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(upload(&app, Some("Bearer key-123")).await, StatusCode::OK);
+
+        // without bearer auth only signed in users may upload
+        let app = app_with_bearer(Users::default(), None);
+        assert_eq!(upload(&app, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(upload(&app, Some("Bearer key-123")).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_login_flow() {
+        let app = app(Users::new([("alice".into(), hash_password("secret").unwrap())]));
+
+        let res = send(&app, Method::GET, "/me", "", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = send(&app, Method::GET, "/login", "", None).await;
+        let cookie = set_cookie(&res);
+        let token = csrf_token(&body(res).await);
+
+        let res = send(&app, Method::POST, "/login", &cookie, Some(format!("csrf_token={token}&username=alice&password=wrong"))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(body(res).await.contains("Unknown username or password"));
+
+        let res = send(&app, Method::POST, "/login", &cookie, Some("csrf_token=forged&username=alice&password=secret".into())).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let form = format!("csrf_token={token}&username=alice&password=secret");
+        let res = send(&app, Method::POST, "/login?next=/me", &cookie, Some(form)).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()[header::LOCATION], "/me");
+        let signed_in = set_cookie(&res);
+
+        let res = send(&app, Method::GET, "/me", &signed_in, None).await;
+        assert_eq!(body(res).await, "alice");
+        let res = send(&app, Method::GET, "/me", &cookie, None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "the session ID from before the login is void");
+
+        let res = send(&app, Method::GET, "/login", &signed_in, None).await;
+        let token = csrf_token(&body(res).await);
+        let res = send(&app, Method::POST, "/upload", &signed_in, Some(format!("csrf_token={token}"))).await;
+        assert_eq!(res.status(), StatusCode::OK, "signed in users upload without a bearer token");
+        let res = send(&app, Method::POST, "/logout", &signed_in, Some(format!("csrf_token={token}"))).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let res = send(&app, Method::GET, "/me", &signed_in, None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,463 @@
+//! Bearer token authentication for API clients
+//!
+//! [BearerLayer] checks the `Authorization: Bearer <token>` header of every request. The token is
+//! either a static API key or a JWT, signed with HS256 by a shared secret or with RS256 by a key of a
+//! local JWKS file. The [Claims] of a valid token are attached to the request extensions, for
+//! handlers to extract. Requests without the header or with another scheme, like `Basic`, pass
+//! unauthenticated, invalid bearer tokens are rejected with [AppError::InvalidToken].
+//! Without any key configured ([BearerAuth::is_enabled]) the layer isn't worth adding.
+//!
+//! Routes demand scopes with the [require_scopes] middleware, which also rejects unauthenticated
+//! requests:
+//!
+//! ```no_run
+//! # use axum::{middleware, routing::post, Router};
+//! # use webapp::server::bearer::{require_scopes, BearerAuth, BearerLayer, RequiredScopes};
+//! # async fn upload() {}
+//! # fn run(auth: BearerAuth) -> Router {
+//! Router::new()
+//!     .route("/upload", post(upload))
+//!     .route_layer(middleware::from_fn_with_state(RequiredScopes::new(["uploads:write"]), require_scopes))
+//!     .layer(BearerLayer::new(auth))
+//! # }
+//! ```
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tower::{Layer, Service};
+use super::{config::BearerConfig, error::AppError};
+
+#[derive(Error, Debug)]
+pub enum BearerError {
+    #[error("couldn't read JWKS {path}: {source}")]
+    Read{path: String, source: std::io::Error},
+
+    #[error("invalid JWKS {path}: {reason}")]
+    Jwks{path: String, reason: String},
+
+    #[error("API key hash of {name} must be 64 hex characters of SHA-256")]
+    KeyHash{name: String},
+}
+
+/// How the request authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    ApiKey,
+    Jwt,
+}
+
+/// Authenticated caller of the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    /// JWT `sub` claim or the name of the API key
+    pub subject: String,
+    pub scopes: BTreeSet<String>,
+    pub kind: TokenKind,
+}
+
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains(scope)
+    }
+}
+
+/// Rejects requests that didn't authenticate with a bearer token
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Claims {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Claims>().cloned().ok_or(AppError::Unauthorized)
+    }
+}
+
+/// Claims read from a JWT, scopes are space separated as in OAuth 2
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: String,
+    #[serde(default)]
+    scope: String,
+}
+
+#[derive(Debug)]
+struct ApiKey {
+    name: String,
+    scopes: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// By SHA-256 of the key, the keys themselves aren't kept
+    api_keys: HashMap<[u8; 32], ApiKey>,
+    hs256: Option<DecodingKey>,
+    /// RS256 keys by key ID
+    rs256: HashMap<Option<String>, DecodingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+/// Accepted API keys and JWT keys, cheap to clone
+#[derive(Clone, Default)]
+pub struct BearerAuth {
+    inner: Arc<Inner>,
+}
+
+impl BearerAuth {
+    /// Accepts nothing until keys are added
+    pub fn new() -> BearerAuth {
+        BearerAuth::default()
+    }
+
+    /// Everything the `bearer` section of the configuration accepts
+    pub fn from_config(config: &BearerConfig) -> Result<BearerAuth, BearerError> {
+        let mut auth = BearerAuth::new();
+        for (name, key) in &config.api_keys {
+            auth = auth.with_api_key(name, &key.sha256, &key.scopes)?;
+        }
+        if let Some(secret) = &config.hs256_secret {
+            auth = auth.with_hs256_secret(secret.as_bytes());
+        }
+        if let Some(jwks) = &config.jwks {
+            auth = auth.with_jwks_file(jwks)?;
+        }
+        if let Some(issuer) = &config.issuer {
+            auth = auth.with_issuer(issuer);
+        }
+        if let Some(audience) = &config.audience {
+            auth = auth.with_audience(audience);
+        }
+        Ok(auth)
+    }
+
+    /// Accepts an API key, given as the hex SHA-256 of the key (`printf %s "$KEY" | sha256sum`)
+    pub fn with_api_key<I>(self, name: &str, sha256_hex: &str, scopes: I) -> Result<BearerAuth, BearerError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let hash = decode_hex(sha256_hex).ok_or_else(|| BearerError::KeyHash { name: name.to_owned() })?;
+        let key = ApiKey { name: name.to_owned(), scopes: scopes.into_iter().map(Into::into).collect() };
+        Ok(self.configure(|inner| { inner.api_keys.insert(hash, key); }))
+    }
+
+    /// Accepts JWTs signed with HS256 by `secret`
+    pub fn with_hs256_secret(self, secret: &[u8]) -> BearerAuth {
+        self.configure(|inner| inner.hs256 = Some(DecodingKey::from_secret(secret)))
+    }
+
+    /// Accepts JWTs signed with RS256 by a key of the JWKS file, matched by the `kid` header
+    pub fn with_jwks_file(self, path: impl AsRef<Path>) -> Result<BearerAuth, BearerError> {
+        let path = path.as_ref();
+        let display = path.display().to_string();
+        let source = std::fs::read(path).map_err(|source| BearerError::Read { path: display.clone(), source })?;
+        let jwks: JwkSet = serde_json::from_slice(&source)
+            .map_err(|err| BearerError::Jwks { path: display.clone(), reason: err.to_string() })?;
+
+        let mut keys = HashMap::new();
+        for jwk in &jwks.keys {
+            let key = DecodingKey::from_jwk(jwk).map_err(|err| BearerError::Jwks { path: display.clone(), reason: err.to_string() })?;
+            keys.insert(jwk.common.key_id.clone(), key);
+        }
+        if keys.is_empty() {
+            return Err(BearerError::Jwks { path: display, reason: "contains no keys".into() });
+        }
+        Ok(self.configure(|inner| inner.rs256.extend(keys)))
+    }
+
+    /// Requires the `iss` claim of JWTs to match
+    pub fn with_issuer(self, issuer: impl Into<String>) -> BearerAuth {
+        self.configure(|inner| inner.issuer = Some(issuer.into()))
+    }
+
+    /// Requires the `aud` claim of JWTs to contain `audience`
+    pub fn with_audience(self, audience: impl Into<String>) -> BearerAuth {
+        self.configure(|inner| inner.audience = Some(audience.into()))
+    }
+
+    /// Whether any API key or JWT key is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.inner.api_keys.is_empty() || self.inner.hs256.is_some() || !self.inner.rs256.is_empty()
+    }
+
+    fn configure(mut self, configure: impl FnOnce(&mut Inner)) -> BearerAuth {
+        configure(Arc::get_mut(&mut self.inner).expect("bearer auth is configured before it's shared"));
+        self
+    }
+
+    /// Claims of the bearer token in `headers`, `None` without an `Authorization` header or with
+    /// one of another scheme
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Claims>, AppError> {
+        let Some(value) = headers.get(header::AUTHORIZATION) else { return Ok(None) };
+        let invalid = |reason: &str| AppError::InvalidToken { reason: reason.to_owned() };
+
+        let bytes = value.as_bytes();
+        let scheme_end = bytes.iter().position(|&b| b == b' ').unwrap_or(bytes.len());
+        if !bytes[..scheme_end].eq_ignore_ascii_case(b"bearer") {
+            return Ok(None);
+        }
+        let token = value.to_str().map_err(|_| invalid("is not visible ASCII"))?[scheme_end..].trim();
+        if token.is_empty() {
+            return Err(invalid("is empty"));
+        }
+
+        let hash: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        if let Some(key) = self.inner.api_keys.get(&hash) {
+            return Ok(Some(Claims { subject: key.name.clone(), scopes: key.scopes.clone(), kind: TokenKind::ApiKey }));
+        }
+        self.decode_jwt(token).map(Some)
+    }
+
+    fn decode_jwt(&self, token: &str) -> Result<Claims, AppError> {
+        let invalid = |reason: String| AppError::InvalidToken { reason };
+
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid("is neither a known API key nor a JWT".into()))?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.inner.hs256.as_ref(),
+            // without a kid, a JWKS with a single key still matches
+            Algorithm::RS256 => self.inner.rs256.get(&header.kid)
+                .or_else(|| (header.kid.is_none() && self.inner.rs256.len() == 1).then(|| self.inner.rs256.values().next()).flatten()),
+            alg => return Err(invalid(format!("algorithm {alg:?} is not accepted"))),
+        };
+        let key = key.ok_or_else(|| invalid("is signed by an unknown key".into()))?;
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.inner.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.inner.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = jsonwebtoken::decode::<JwtClaims>(token, key, &validation)
+            .map_err(|err| invalid(err.to_string()))?
+            .claims;
+        Ok(Claims {
+            subject: claims.sub,
+            scopes: claims.scope.split_whitespace().map(str::to_owned).collect(),
+            kind: TokenKind::Jwt,
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim().as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Authenticates bearer tokens, see the [module](self) docs
+#[derive(Clone)]
+pub struct BearerLayer {
+    auth: BearerAuth,
+}
+
+impl BearerLayer {
+    pub fn new(auth: BearerAuth) -> BearerLayer {
+        BearerLayer { auth }
+    }
+}
+
+impl<S> Layer<S> for BearerLayer {
+    type Service = BearerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BearerService { inner, auth: self.auth.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct BearerService<S> {
+    inner: S,
+    auth: BearerAuth,
+}
+
+impl<S> Service<Request> for BearerService<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        match self.auth.authenticate(request.headers()) {
+            Ok(claims) => {
+                if let Some(claims) = claims {
+                    request.extensions_mut().insert(claims);
+                }
+                Box::pin(self.inner.call(request))
+            }
+            Err(err) => Box::pin(async move { Ok(err.into_response()) }),
+        }
+    }
+}
+
+/// Scopes a route requires, all of them have to be granted
+#[derive(Debug, Clone)]
+pub struct RequiredScopes(Arc<[String]>);
+
+impl RequiredScopes {
+    pub fn new<I>(scopes: I) -> RequiredScopes
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        RequiredScopes(scopes.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Rejects requests without [Claims] or lacking one of the required scopes, use it as a `route_layer`
+pub async fn require_scopes(State(required): State<RequiredScopes>, req: Request, next: Next) -> Result<Response, AppError> {
+    let claims = req.extensions().get::<Claims>().ok_or(AppError::Unauthorized)?;
+    if let Some(missing) = required.0.iter().find(|scope| !claims.has_scope(scope)) {
+        return Err(AppError::InsufficientScope { scope: missing.clone() });
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/jwt");
+
+    fn sign(header: Header, key: &EncodingKey, claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+
+    fn exp(offset: i64) -> i64 {
+        chrono::Utc::now().timestamp() + offset
+    }
+
+    fn rsa() -> EncodingKey {
+        EncodingKey::from_rsa_pem(&std::fs::read(format!("{FIXTURES}/rsa.pem")).unwrap()).unwrap()
+    }
+
+    fn app() -> Router {
+        let config: BearerConfig = toml::from_str(&format!(r#"
+            hs256_secret = "shared secret"
+            jwks = "{FIXTURES}/jwks.json"
+            issuer = "https://issuer.example"
+            [api_keys.ci]
+            sha256 = "{:x}"
+            scopes = ["uploads:write"]
+        "#, Sha256::digest(b"key-123"))).unwrap();
+        let auth = BearerAuth::from_config(&config).unwrap();
+
+        Router::new()
+            .route("/upload", get(|claims: Claims| async move { claims.subject }))
+            .route_layer(middleware::from_fn_with_state(RequiredScopes::new(["uploads:write"]), require_scopes))
+            .route("/open", get(|claims: Option<Claims>| async move { format!("{:?}", claims.map(|c| c.kind)) }))
+            .layer(BearerLayer::new(auth))
+    }
+
+    async fn get_with(app: &Router, uri: &str, token: Option<&str>) -> (StatusCode, String) {
+        let mut req = Request::get(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        let status = res.status();
+        let challenge = res.headers().get(header::WWW_AUTHENTICATE).map(|v| v.to_str().unwrap().to_owned());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        match status.is_success() {
+            true => (status, String::from_utf8(body.to_vec()).unwrap()),
+            false => (status, challenge.unwrap_or_default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_key_and_scopes() {
+        let app = app();
+        assert_eq!(get_with(&app, "/open", None).await, (StatusCode::OK, "None".into()));
+        assert_eq!(get_with(&app, "/open", Some("key-123")).await, (StatusCode::OK, "Some(ApiKey)".into()));
+        assert_eq!(get_with(&app, "/upload", Some("key-123")).await, (StatusCode::OK, "ci".into()));
+
+        assert_eq!(get_with(&app, "/upload", None).await, (StatusCode::UNAUTHORIZED, "Bearer".into()));
+        let (status, challenge) = get_with(&app, "/open", Some("key-456")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(challenge.starts_with("Bearer error=\"invalid_token\""), "{challenge}");
+    }
+
+    #[tokio::test]
+    async fn test_other_schemes() {
+        let app = app();
+        let send = |authorization: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = Request::get("/open").header(header::AUTHORIZATION, authorization).body(Body::empty()).unwrap();
+                app.oneshot(req).await.unwrap().status()
+            }
+        };
+        assert_eq!(send("Basic YWxpY2U6c2VjcmV0").await, StatusCode::OK, "left to whoever handles Basic");
+        assert_eq!(send("Digest username=\"alice\"").await, StatusCode::OK);
+        assert_eq!(send("Bearer").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("bearer   ").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_jwt() {
+        let app = app();
+        let claims = |scope: &str, exp: i64| json!({ "sub": "svc", "scope": scope, "exp": exp, "iss": "https://issuer.example" });
+
+        let mut rs256 = Header::new(Algorithm::RS256);
+        rs256.kid = Some("test-2026".into());
+        let token = sign(rs256.clone(), &rsa(), claims("media:read uploads:write", exp(60)));
+        assert_eq!(get_with(&app, "/upload", Some(&token)).await, (StatusCode::OK, "svc".into()));
+
+        let hs256 = EncodingKey::from_secret(b"shared secret");
+        let token = sign(Header::default(), &hs256, claims("media:read", exp(60)));
+        assert_eq!(get_with(&app, "/open", Some(&token)).await, (StatusCode::OK, "Some(Jwt)".into()));
+        assert_eq!(get_with(&app, "/upload", Some(&token)).await,
+            (StatusCode::FORBIDDEN, "Bearer error=\"insufficient_scope\", scope=\"uploads:write\"".into()));
+
+        let rejected = [
+            sign(rs256.clone(), &rsa(), claims("uploads:write", exp(-3600))),
+            sign(Header::default(), &EncodingKey::from_secret(b"other secret"), claims("uploads:write", exp(60))),
+            sign(rs256, &rsa(), json!({ "sub": "svc", "scope": "uploads:write", "exp": exp(60), "iss": "https://evil.example" })),
+            sign(Header::new(Algorithm::HS512), &hs256, claims("uploads:write", exp(60))),
+        ];
+        for token in rejected {
+            assert_eq!(get_with(&app, "/upload", Some(&token)).await.0, StatusCode::UNAUTHORIZED, "{token}");
+        }
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(matches!(BearerAuth::new().with_api_key("ci", "abc", ["x"]), Err(BearerError::KeyHash { .. })));
+        assert!(matches!(BearerAuth::new().with_jwks_file(format!("{FIXTURES}/rsa.pem")), Err(BearerError::Jwks { .. })));
+        assert!(!BearerAuth::new().is_enabled());
+    }
+}
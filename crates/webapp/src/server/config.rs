@@ -0,0 +1,648 @@
+//! Configuration of the webapp binary
+//!
+//! Settings are layered, later layers override earlier ones:
+//! 1. built-in defaults ([Config::default])
+//! 2. a TOML file, `--config <file>` or `WEBAPP_CONFIG`, `webapp.toml` when it exists
+//! 3. environment variables, `WEBAPP_` followed by the key path with `__` between levels
+//!    (`WEBAPP_LIMITS__DRAIN_TIMEOUT=5`). Variables that don't name a section are left to whoever
+//!    else uses the prefix, `WEBAPP_TEMPLATE_MODE` and `WEBAPP_DRAIN_TIMEOUT` of earlier versions
+//!    still set `templates.mode` and `limits.drain_timeout`.
+//! 4. command line flags, `--set key.path=value` reaches every setting
+//!
+//! Values from the environment and `--set` are read as TOML values when they parse as one
+//! (`5`, `true`, `["a", "b"]`) and as strings otherwise. The merged configuration is validated
+//! by [Config::load], which reports every problem at once.
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use base64::Engine;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use toml::{Table, Value};
+use super::{ratelimit::{Algorithm, KeyBy}, views::TemplateMode};
+
+/// Prefix of configuration environment variables
+pub const ENV_PREFIX: &str = "WEBAPP_";
+const DEFAULT_FILE: &str = "webapp.toml";
+/// Environment variables of earlier versions and the setting they map to, the new name wins
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("WEBAPP_TEMPLATE_MODE", "templates.mode"),
+    ("WEBAPP_DRAIN_TIMEOUT", "limits.drain_timeout"),
+];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("couldn't read config file {path}: {source}")]
+    Read{path: PathBuf, source: std::io::Error},
+
+    #[error("invalid config file {path}: {source}")]
+    Parse{path: PathBuf, source: toml::de::Error},
+
+    #[error("invalid override {setting}: expected key.path=value")]
+    Override{setting: String},
+
+    #[error("invalid configuration: {0}")]
+    Invalid(#[from] toml::de::Error),
+
+    #[error("invalid configuration:\n  {}", .0.join("\n  "))]
+    Validation(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server listens on
+    pub listen: SocketAddr,
+    /// Serves HTTPS when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    pub paths: PathsConfig,
+    pub templates: TemplatesConfig,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+    pub session: SessionConfig,
+    pub auth: AuthConfig,
+    pub bearer: BearerConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+    /// Offers HTTP/2 through ALPN next to HTTP/1.1
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// Plain HTTP listener redirecting to HTTPS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_from: Option<SocketAddr>,
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub views: PathBuf,
+    pub locales: PathBuf,
+    /// Served under `/static` and `/media`, uploads end up here
+    pub assets: PathBuf,
+    /// Unfinished uploads, must be on the same filesystem as `assets`
+    pub uploads: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplatesConfig {
+    pub mode: TemplateMode,
+    /// Locale used when none of the requested ones is available
+    pub fallback_locale: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest accepted upload in bytes
+    pub upload_max_size: u64,
+    /// Seconds in-flight requests get to finish on shutdown
+    pub drain_timeout: u64,
+    /// Seconds new connections are still accepted after `/readyz` started failing on shutdown
+    pub pre_stop_delay: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `error`, `warn`, `info`, `debug` or `trace`
+    pub level: String,
+    pub format: LogFormat,
+    /// gRPC endpoint of an OpenTelemetry collector receiving the request spans, needs the `otlp` feature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Base64 encoded key of at least 64 bytes signing and encrypting the session cookie. Without
+    /// one a random key is generated and sessions don't survive a restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub store: SessionStoreKind,
+    /// Directory of the `file` store
+    pub dir: PathBuf,
+    /// Seconds a session lives without being used
+    pub ttl: u64,
+    /// Seconds a session holding nothing but a CSRF token lives, e.g. one of a visit to the login page
+    pub anonymous_ttl: u64,
+    /// Sends the cookie over HTTPS only, defaults to whether `tls` is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    #[default]
+    Memory,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// User name to argon2 password hash, `webapp --hash-password` creates one
+    pub users: BTreeMap<String, String>,
+}
+
+/// Bearer tokens of API clients, see [BearerAuth](super::bearer::BearerAuth)
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BearerConfig {
+    /// Shared secret of HS256 signed JWTs, at least 32 bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hs256_secret: Option<String>,
+    /// JWKS file with the keys of RS256 signed JWTs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<PathBuf>,
+    /// Required `iss` claim
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// Required `aud` claim
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// API keys by name
+    pub api_keys: BTreeMap<String, ApiKeyConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Hex SHA-256 of the key
+    pub sha256: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// Per-client request quotas, see [RateLimiter](super::ratelimit::RateLimiter)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// What identifies a client: `ip`, `api_key` or `header:<name>`
+    pub key: String,
+    /// Proxies in front that append to `X-Forwarded-For`, the client IP is the entry the outermost
+    /// one added. `0` uses the peer address.
+    pub trusted_proxies: usize,
+    /// Quota of routes without one of their own, unlimited when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<QuotaConfig>,
+    /// Quotas by route pattern
+    pub routes: BTreeMap<String, QuotaConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    pub limit: u32,
+    /// Seconds
+    pub period: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            tls: None,
+            paths: PathsConfig::default(),
+            templates: TemplatesConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig::default(),
+            session: SessionConfig::default(),
+            auth: AuthConfig::default(),
+            bearer: BearerConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            views: "crates/webapp/views".into(),
+            locales: "crates/webapp/locales".into(),
+            assets: "crates/webapp/assets".into(),
+            uploads: "crates/webapp/.uploads".into(),
+        }
+    }
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        TemplatesConfig { mode: TemplateMode::default(), fallback_locale: "en".into() }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig { upload_max_size: 512 * 1024 * 1024, drain_timeout: 30, pre_stop_delay: 5 }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            secret: None,
+            store: SessionStoreKind::default(),
+            dir: "crates/webapp/.sessions".into(),
+            ttl: 24 * 60 * 60,
+            anonymous_ttl: 60 * 60,
+            secure: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        // the video stream and the data endpoint are the expensive ones, login hashes passwords
+        let routes = [
+            ("/", QuotaConfig { algorithm: Algorithm::SlidingWindow, limit: 120, period: 60 }),
+            ("/x-data", QuotaConfig { algorithm: Algorithm::TokenBucket, limit: 60, period: 60 }),
+            ("/login", QuotaConfig { algorithm: Algorithm::TokenBucket, limit: 10, period: 60 }),
+        ];
+        RateLimitConfig {
+            key: KeyBy::ClientIp.to_string(),
+            trusted_proxies: 0,
+            default: None,
+            routes: routes.into_iter().map(|(route, quota)| (route.to_owned(), quota)).collect(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: "info".into(), format: LogFormat::default(), otlp_endpoint: None }
+    }
+}
+
+/// Command line flags of the webapp binary
+#[derive(Debug, Default, Parser)]
+#[command(name = "webapp", about = "Serves the webapp")]
+pub struct Cli {
+    /// TOML config file [default: webapp.toml when it exists, or WEBAPP_CONFIG]
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    pub listen: Option<String>,
+    /// Directory of the templates
+    #[arg(long)]
+    pub views: Option<PathBuf>,
+    /// Directory of the static assets
+    #[arg(long)]
+    pub assets: Option<PathBuf>,
+    /// development or embedded
+    #[arg(long)]
+    pub template_mode: Option<String>,
+    /// error, warn, info, debug or trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Overrides any setting, e.g. `--set limits.drain_timeout=5`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+    /// Reads a password from stdin and prints its hash for `auth.users`
+    #[arg(long)]
+    pub hash_password: bool,
+}
+
+impl Config {
+    /// Loads the configuration from the process arguments, environment and config file
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_from(Cli::parse(), std::env::vars_os())
+    }
+
+    /// Loads the configuration from the given flags and environment
+    pub fn load_from(cli: Cli, env: impl IntoIterator<Item = (OsString, OsString)>) -> Result<Config, ConfigError> {
+        let env: Vec<(String, String)> = env.into_iter()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+            .collect();
+
+        let mut merged = Table::try_from(Config::default()).expect("default config serializes to a table");
+        // tls is left out of the defaults as long as it's unset
+        let sections: Vec<String> = merged.keys().cloned().chain(["tls".to_owned()]).collect();
+
+        let file = cli.config.clone()
+            .or_else(|| env.iter().find(|(key, _)| key == "WEBAPP_CONFIG").map(|(_, path)| path.into()))
+            .or_else(|| Path::new(DEFAULT_FILE).exists().then(|| DEFAULT_FILE.into()));
+        if let Some(path) = file {
+            let source = std::fs::read_to_string(&path).map_err(|source| ConfigError::Read { path: path.clone(), source })?;
+            let table: Table = toml::from_str(&source).map_err(|source| ConfigError::Parse { path, source })?;
+            merge(&mut merged, table);
+        }
+
+        for (legacy, path) in LEGACY_ENV {
+            if let Some((_, value)) = env.iter().find(|(key, _)| key == legacy) {
+                set(&mut merged, path, parse_value(value));
+            }
+        }
+        for (key, value) in &env {
+            let Some(path) = key.strip_prefix(ENV_PREFIX) else { continue };
+            let path = path.to_lowercase().replace("__", ".");
+            let section = path.split('.').next().unwrap_or_default();
+            if sections.iter().any(|known| known == section) {
+                set(&mut merged, &path, parse_value(value));
+            }
+        }
+
+        let flags = [
+            ("listen", cli.listen.map(Value::String)),
+            ("paths.views", cli.views.map(|path| Value::String(path.display().to_string()))),
+            ("paths.assets", cli.assets.map(|path| Value::String(path.display().to_string()))),
+            ("templates.mode", cli.template_mode.map(Value::String)),
+            ("log.level", cli.log_level.map(Value::String)),
+        ];
+        for (key, value) in flags {
+            if let Some(value) = value {
+                set(&mut merged, key, value);
+            }
+        }
+        for setting in cli.overrides {
+            let (key, value) = setting.split_once('=').filter(|(key, _)| !key.trim().is_empty())
+                .ok_or_else(|| ConfigError::Override { setting: setting.clone() })?;
+            set(&mut merged, key.trim(), parse_value(value.trim()));
+        }
+
+        let config: Config = merged.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks settings the types can't express, reports every problem at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let dirs = [
+            ("paths.views", &self.paths.views, self.templates.mode == TemplateMode::Development),
+            ("paths.locales", &self.paths.locales, self.templates.mode == TemplateMode::Development),
+            ("paths.assets", &self.paths.assets, true),
+        ];
+        for (key, dir, required) in dirs {
+            if required && !dir.is_dir() {
+                problems.push(format!("{key}: {} is not a directory", dir.display()));
+            }
+        }
+        if self.paths.uploads.is_file() {
+            problems.push(format!("paths.uploads: {} is a file, expected a directory", self.paths.uploads.display()));
+        }
+
+        if let Some(tls) = &self.tls {
+            for (key, file) in [("tls.cert", &tls.cert), ("tls.key", &tls.key)] {
+                if !file.is_file() {
+                    problems.push(format!("{key}: {} does not exist", file.display()));
+                }
+            }
+            if tls.redirect_from == Some(self.listen) {
+                problems.push(format!("tls.redirect_from: {} is already the HTTPS listener", self.listen));
+            }
+        }
+
+        if let Some(secret) = &self.session.secret {
+            match base64::engine::general_purpose::STANDARD.decode(secret) {
+                Ok(key) if key.len() >= 64 => {}
+                Ok(key) => problems.push(format!("session.secret: has {} bytes, at least 64 are required", key.len())),
+                Err(_) => problems.push("session.secret: is not valid base64".into()),
+            }
+        }
+        if self.session.ttl == 0 {
+            problems.push("session.ttl: must be greater than 0".into());
+        }
+        if self.session.anonymous_ttl == 0 {
+            problems.push("session.anonymous_ttl: must be greater than 0".into());
+        }
+        if self.session.store == SessionStoreKind::File && self.session.dir.is_file() {
+            problems.push(format!("session.dir: {} is a file, expected a directory", self.session.dir.display()));
+        }
+        for (name, hash) in &self.auth.users {
+            if argon2::PasswordHash::new(hash).is_err() {
+                problems.push(format!("auth.users.{name}: is not a password hash, create one with --hash-password"));
+            }
+        }
+
+        if self.bearer.hs256_secret.as_ref().is_some_and(|secret| secret.len() < 32) {
+            problems.push("bearer.hs256_secret: must be at least 32 bytes".into());
+        }
+        if let Some(jwks) = self.bearer.jwks.as_ref().filter(|jwks| !jwks.is_file()) {
+            problems.push(format!("bearer.jwks: {} does not exist", jwks.display()));
+        }
+        for (name, key) in &self.bearer.api_keys {
+            if key.sha256.len() != 64 || !key.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                problems.push(format!("bearer.api_keys.{name}.sha256: expected 64 hex characters"));
+            }
+        }
+
+        if let Err(err) = self.rate_limit.key.parse::<KeyBy>() {
+            problems.push(format!("rate_limit.key: {err}"));
+        }
+        let quotas = self.rate_limit.default.iter().map(|quota| ("rate_limit.default".to_owned(), quota))
+            .chain(self.rate_limit.routes.iter().map(|(route, quota)| (format!("rate_limit.routes.{route:?}"), quota)));
+        for (key, quota) in quotas {
+            if quota.limit == 0 || quota.period == 0 {
+                problems.push(format!("{key}: limit and period must be greater than 0"));
+            }
+        }
+
+        if self.templates.fallback_locale.parse::<unic_langid::LanguageIdentifier>().is_err() {
+            problems.push(format!("templates.fallback_locale: {:?} is not a locale", self.templates.fallback_locale));
+        }
+        if self.limits.upload_max_size == 0 {
+            problems.push("limits.upload_max_size: must be greater than 0".into());
+        }
+        if !matches!(self.log.level.as_str(), "error" | "warn" | "info" | "debug" | "trace") {
+            problems.push(format!("log.level: {:?} is not one of error, warn, info, debug or trace", self.log.level));
+        }
+        if self.log.otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+            problems.push("log.otlp_endpoint: built without the otlp feature".into());
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Validation(problems)),
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.limits.drain_timeout)
+    }
+
+    pub fn pre_stop_delay(&self) -> Duration {
+        Duration::from_secs(self.limits.pre_stop_delay)
+    }
+
+    /// Session cookie key, `None` when the sessions should use a random one
+    pub fn session_key(&self) -> Option<cookie::Key> {
+        let secret = base64::engine::general_purpose::STANDARD.decode(self.session.secret.as_ref()?).ok()?;
+        cookie::Key::try_from(secret.as_slice()).ok()
+    }
+
+    pub fn session_secure(&self) -> bool {
+        self.session.secure.unwrap_or(self.tls.is_some())
+    }
+}
+
+/// Merges `layer` into `base`, tables are merged key by key and other values replaced
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(layer)) => merge(base, layer),
+            (_, value) => { base.insert(key, value); }
+        }
+    }
+}
+
+/// Sets a dotted key path, creating tables on the way
+fn set(table: &mut Table, path: &str, value: Value) {
+    let mut keys = path.split('.').map(str::trim).peekable();
+    let mut table = table;
+    while let Some(key) = keys.next() {
+        if keys.peek().is_none() {
+            table.insert(key.to_owned(), value);
+            return;
+        }
+        let entry = table.entry(key.to_owned()).or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+        table = entry.as_table_mut().expect("just made a table");
+    }
+}
+
+/// Reads a value from the environment or the command line, strings don't need quotes
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter().map(|(k, v)| (k.into(), v.into())).collect()
+    }
+
+    fn dirs() -> (tempfile::TempDir, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let overrides = ["views", "locales", "assets"].iter().map(|name| {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+            format!("paths.{name}={:?}", dir.path().join(name).display().to_string())
+        }).collect();
+        (dir, overrides)
+    }
+
+    #[test]
+    fn test_precedence() {
+        let (dir, overrides) = dirs();
+        let file = dir.path().join("webapp.toml");
+        std::fs::write(&file, "listen = \"127.0.0.1:8000\"\n[limits]\ndrain_timeout = 10\nupload_max_size = 1024\n").unwrap();
+
+        let secret = base64::engine::general_purpose::STANDARD.encode([7; 64]);
+        let cli = Cli { config: Some(file), log_level: Some("debug".into()), overrides, ..Cli::default() };
+        let config = Config::load_from(cli, env(&[
+            ("WEBAPP_SESSION__SECRET", &secret),
+            ("WEBAPP_LIMITS__DRAIN_TIMEOUT", "5"),
+            ("WEBAPP_LOG__LEVEL", "warn"),
+            ("WEBAPP_TEMPLATES__MODE", "prod"),
+            ("OTHER_LISTEN", "ignored"),
+        ])).unwrap();
+
+        assert_eq!(config.listen, "127.0.0.1:8000".parse().unwrap());
+        assert_eq!(config.limits.upload_max_size, 1024);
+        assert_eq!(config.drain_timeout(), Duration::from_secs(5));
+        assert_eq!(config.log.level, "debug");
+        assert_eq!(config.templates.mode, TemplateMode::Embedded);
+        assert_eq!(config.templates.fallback_locale, "en");
+        assert_eq!(config.session_key().unwrap().master(), [7; 64]);
+        assert!(!config.session_secure());
+    }
+
+    #[test]
+    fn test_errors() {
+        let (_dir, mut overrides) = dirs();
+        overrides.push("tls.cert=/nonexistent/cert.pem".into());
+        overrides.push("tls.key=/nonexistent/key.pem".into());
+        overrides.push("tls.redirect_from=0.0.0.0:3000".into());
+        overrides.push("limits.upload_max_size=0".into());
+        overrides.push("session.secret=c2hvcnQ=".into());
+        overrides.push("auth.users.alice=hunter2".into());
+        overrides.push("bearer.api_keys.ci.sha256=abc".into());
+        overrides.push("rate_limit.key=cookie".into());
+
+        let err = Config::load_from(Cli { overrides, log_level: Some("loud".into()), ..Cli::default() }, env(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "invalid configuration:\n  \
+            tls.cert: /nonexistent/cert.pem does not exist\n  \
+            tls.key: /nonexistent/key.pem does not exist\n  \
+            tls.redirect_from: 0.0.0.0:3000 is already the HTTPS listener\n  \
+            session.secret: has 5 bytes, at least 64 are required\n  \
+            auth.users.alice: is not a password hash, create one with --hash-password\n  \
+            bearer.api_keys.ci.sha256: expected 64 hex characters\n  \
+            rate_limit.key: \"cookie\" is not one of ip, api_key or header:<name>\n  \
+            limits.upload_max_size: must be greater than 0\n  \
+            log.level: \"loud\" is not one of error, warn, info, debug or trace");
+
+        let err = Config::load_from(Cli::default(), env(&[("WEBAPP_LIMITS__DRAIN_TIMOUT", "5")])).unwrap_err();
+        assert!(err.to_string().contains("unknown field `drain_timout`"), "{err}");
+
+        let err = Config::load_from(Cli::default(), env(&[("WEBAPP_LISTEN", "localhost")])).unwrap_err();
+        assert!(err.to_string().contains("listen"), "{err}");
+
+        let err = Config::load_from(Cli { overrides: vec!["listen".into()], ..Cli::default() }, env(&[])).unwrap_err();
+        assert!(matches!(err, ConfigError::Override { .. }));
+    }
+
+    #[test]
+    fn test_legacy_and_foreign_env() {
+        let (_dir, overrides) = dirs();
+        let config = Config::load_from(Cli { overrides: overrides.clone(), ..Cli::default() }, env(&[
+            ("WEBAPP_TEMPLATE_MODE", "development"),
+            ("WEBAPP_DRAIN_TIMEOUT", "5"),
+            ("WEBAPP_VERSION", "1.4.2"),
+        ])).unwrap();
+        assert_eq!(config.templates.mode, TemplateMode::Development);
+        assert_eq!(config.drain_timeout(), Duration::from_secs(5));
+
+        let config = Config::load_from(Cli { overrides, ..Cli::default() }, env(&[
+            ("WEBAPP_DRAIN_TIMEOUT", "5"),
+            ("WEBAPP_LIMITS__DRAIN_TIMEOUT", "7"),
+        ])).unwrap();
+        assert_eq!(config.drain_timeout(), Duration::from_secs(7), "the new name wins");
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("5"), Value::Integer(5));
+        assert_eq!(parse_value("0.0.0.0:80"), Value::String("0.0.0.0:80".into()));
+        assert_eq!(parse_value("\"quoted\""), Value::String("quoted".into()));
+    }
+}
@@ -0,0 +1,372 @@
+//! Error model of the web server
+//!
+//! Every [AppError] is answered with an RFC 7807 `application/problem+json` body carrying a stable
+//! machine readable `code`. The [problem_details] middleware adds a correlation ID to every response
+//! and renders the `error.html` template instead when the client prefers HTML, such as browsers do.
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use minijinja::{context, Environment};
+use serde::Serialize;
+use thiserror::Error;
+use super::{i18n::Locale, telemetry::REQUEST_ID, views::Views};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+pub const CORRELATION_ID: HeaderName = HeaderName::from_static("x-correlation-id");
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("Oops, something went wrong!")]
+    Oops,
+    #[error("Request payload has not been satisfied")]
+    RequestPayload,
+    #[error("Header {header} {reason}")]
+    InvalidHeader{header: &'static str, reason: String},
+    #[error("Oops something went wrong: {detail}")]
+    OopsWithDetails{code: StatusCode, detail: String},
+    #[error("Oops something went wrong: {err}")]
+    OopsError{err: String},
+    #[error("The requested resource could not be found")]
+    NotFound,
+    #[error("The requested range is not satisfiable")]
+    RangeNotSatisfiable{size: u64},
+    #[error("A precondition of the request failed")]
+    PreconditionFailed,
+    #[error("Request payload exceeds the limit of {limit} bytes")]
+    PayloadTooLarge{limit: u64},
+    #[error("Content type {content_type} is not supported")]
+    UnsupportedMediaType{content_type: String},
+    #[error("Checksum of the request payload doesn't match")]
+    ChecksumMismatch,
+    #[error("Request conflicts with the current state: {detail}")]
+    Conflict{detail: String},
+    #[error("Authentication is required")]
+    Unauthorized,
+    #[error("CSRF token is missing or invalid")]
+    CsrfTokenMismatch,
+    #[error("Bearer token {reason}")]
+    InvalidToken{reason: String},
+    #[error("The scope {scope} is required")]
+    InsufficientScope{scope: String},
+    #[error("Too many requests, retry after {retry_after}s")]
+    TooManyRequests{retry_after: u64},
+}
+
+impl AppError {
+    /// Machine readable error code, clients may rely on it so never change an existing one
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Oops | AppError::OopsError{..} => "internal_error",
+            AppError::RequestPayload | AppError::InvalidHeader{..} => "request_payload",
+            AppError::OopsWithDetails{..} => "request_failed",
+            AppError::NotFound => "not_found",
+            AppError::RangeNotSatisfiable{..} => "range_not_satisfiable",
+            AppError::PreconditionFailed => "precondition_failed",
+            AppError::PayloadTooLarge{..} => "payload_too_large",
+            AppError::UnsupportedMediaType{..} => "unsupported_media_type",
+            AppError::ChecksumMismatch => "checksum_mismatch",
+            AppError::Conflict{..} => "conflict",
+            AppError::Unauthorized => "unauthorized",
+            AppError::CsrfTokenMismatch => "csrf_token_mismatch",
+            AppError::InvalidToken{..} => "invalid_token",
+            AppError::InsufficientScope{..} => "insufficient_scope",
+            AppError::TooManyRequests{..} => "rate_limited",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Oops | AppError::OopsError{..} => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RequestPayload | AppError::InvalidHeader{..} => StatusCode::BAD_REQUEST,
+            AppError::OopsWithDetails{code, ..} => *code,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::RangeNotSatisfiable{..} => StatusCode::RANGE_NOT_SATISFIABLE,
+            AppError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            AppError::PayloadTooLarge{..} => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType{..} => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            // tus checksum extension status
+            AppError::ChecksumMismatch => StatusCode::from_u16(460).unwrap_or(StatusCode::BAD_REQUEST),
+            AppError::Conflict{..} => StatusCode::CONFLICT,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::CsrfTokenMismatch => StatusCode::FORBIDDEN,
+            AppError::InvalidToken{..} => StatusCode::UNAUTHORIZED,
+            AppError::InsufficientScope{..} => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests{..} => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl From<minijinja::Error> for AppError {
+    fn from(err: minijinja::Error) -> Self {
+        AppError::OopsError{err: format!("{err:#}")}
+    }
+}
+
+/// RFC 7807 problem details
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &'static str, detail: Option<String>) -> Problem {
+        Problem {
+            type_uri: format!("/problems/{code}"),
+            title: status.canonical_reason().unwrap_or("Error").to_owned(),
+            status: status.as_u16(),
+            detail,
+            instance: None,
+            code,
+            correlation_id: None,
+        }
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut res = (
+            self.status_code(),
+            [(header::CONTENT_TYPE, PROBLEM_JSON)],
+            Json(&self),
+        ).into_response();
+
+        // picked up by the problem_details middleware
+        res.extensions_mut().insert(self);
+        res
+    }
+}
+
+/// Internal description of a server error, logged but never sent to the client
+#[derive(Debug, Clone)]
+struct ErrorSource(String);
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        // server errors may carry internals (file paths, template errors), so the client gets a generic detail
+        let detail = if status.is_server_error() {
+            AppError::Oops.to_string()
+        } else {
+            self.to_string()
+        };
+
+        let mut res = Problem::new(status, self.code(), Some(detail)).into_response();
+        let extra_header = match &self {
+            AppError::RangeNotSatisfiable{size} => Some((header::CONTENT_RANGE, format!("bytes */{size}"))),
+            // RFC 6750 challenges
+            AppError::Unauthorized => Some((header::WWW_AUTHENTICATE, "Bearer".to_owned())),
+            AppError::InvalidToken{..} => Some((header::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\"".to_owned())),
+            AppError::InsufficientScope{scope} => Some((header::WWW_AUTHENTICATE, format!("Bearer error=\"insufficient_scope\", scope=\"{scope}\""))),
+            AppError::TooManyRequests{retry_after} => Some((header::RETRY_AFTER, retry_after.to_string())),
+            _ => None,
+        };
+        if let Some((name, value)) = extra_header {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+        res.extensions_mut().insert(ErrorSource(self.to_string()));
+        res
+    }
+}
+
+/// Correlation ID of the current request, available as a request extension
+#[derive(Debug, Clone)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+    /// Reuses the ID the client or a proxy sent along, or generates a new one
+    pub fn from_headers(headers: &HeaderMap) -> CorrelationId {
+        let id = [&CORRELATION_ID, &REQUEST_ID].into_iter()
+            .filter_map(|name| headers.get(name)?.to_str().ok())
+            .find(|id| !id.is_empty() && id.len() <= 128)
+            .map(str::to_owned)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        CorrelationId(id)
+    }
+}
+
+/// Whether the client rather receives HTML than JSON, based on the `Accept` quality values
+fn prefers_html(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else { return false };
+
+    let quality = |wanted: &[&str]| {
+        accept.split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let media = params.next()?;
+                let q = params.find_map(|p| p.strip_prefix("q=")).and_then(|q| q.parse().ok()).unwrap_or(1.0);
+                wanted.contains(&media).then_some(q)
+            })
+            .fold(0.0f32, f32::max)
+    };
+
+    let html = quality(&["text/html"]);
+    html > 0.0 && html > quality(&["application/json", PROBLEM_JSON])
+}
+
+/// Assigns a correlation ID to every request and negotiates the representation of problems
+pub async fn problem_details(State(views): State<Views>, mut req: Request, next: Next) -> Response {
+    // the request ID of trace_requests when it runs first
+    let correlation_id = req.extensions().get::<CorrelationId>().cloned()
+        .unwrap_or_else(|| CorrelationId::from_headers(req.headers()));
+    let html = prefers_html(req.headers());
+    let instance = req.uri().path().to_owned();
+    let locale = req.extensions().get::<Locale>().map(ToString::to_string);
+    req.extensions_mut().insert(correlation_id.clone());
+
+    let mut res = next.run(req).await;
+    let header_value = HeaderValue::from_str(&correlation_id.0).ok();
+
+    if let Some(mut problem) = res.extensions_mut().remove::<Problem>() {
+        problem.correlation_id = Some(correlation_id.0.clone());
+        problem.instance = Some(instance);
+
+        if let Some(ErrorSource(source)) = res.extensions_mut().remove::<ErrorSource>() {
+            if problem.status_code().is_server_error() {
+                tracing::error!(status = problem.status, code = %problem.code, "{source}");
+            }
+        }
+
+        // headers the error set (Content-Range, WWW-Authenticate) survive the new representation
+        let mut headers = std::mem::take(res.headers_mut());
+        headers.remove(header::CONTENT_TYPE);
+        headers.remove(header::CONTENT_LENGTH);
+
+        // a page that fails to render falls back to JSON
+        let page = html.then(|| render_problem(&views.env(), &problem, locale)).flatten();
+        let is_page = page.is_some();
+        res = page.unwrap_or_else(|| Json(&problem).into_response());
+        *res.status_mut() = problem.status_code();
+        res.headers_mut().extend(headers);
+        if !is_page {
+            res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        }
+        // the representation depends on Accept, caches must not serve one for the other
+        res.headers_mut().append(header::VARY, HeaderValue::from_static("accept"));
+    }
+
+    if let Some(value) = header_value {
+        res.headers_mut().insert(CORRELATION_ID, value);
+    }
+    res
+}
+
+fn render_problem(views: &Environment<'static>, problem: &Problem, locale: Option<String>) -> Option<Response> {
+    let page = views.get_template("error.html").ok()?
+        .render(context! {
+            text => problem.detail.as_deref().unwrap_or(&problem.title),
+            status => problem.status,
+            code => problem.code,
+            correlation_id => problem.correlation_id,
+            locale => locale,
+        })
+        .ok()?;
+    Some(Html(page).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, handler::Handler, middleware, routing::get, Router};
+    use include_dir::{include_dir, Dir};
+    use tower::ServiceExt;
+    use crate::server::i18n::Catalogs;
+
+    static VIEWS: Dir = include_dir!("$CARGO_MANIFEST_DIR/views");
+
+    fn accept(value: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(header::ACCEPT, HeaderValue::from_static(value))])
+    }
+
+    fn app<H: Handler<T, ()>, T: 'static>(handler: H) -> Router {
+        app_with_views(&VIEWS, handler)
+    }
+
+    fn app_with_views<H: Handler<T, ()>, T: 'static>(views: &'static Dir<'static>, handler: H) -> Router {
+        let catalogs = Catalogs::load(concat!(env!("CARGO_MANIFEST_DIR"), "/locales"), "en").unwrap();
+        let views = Views::embedded(views, move |env| catalogs.register(env)).unwrap();
+        Router::new()
+            .route("/", get(handler))
+            .layer(middleware::from_fn_with_state(views, problem_details))
+    }
+
+    #[test]
+    fn test_prefers_html() {
+        assert!(prefers_html(&accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8")));
+        assert!(!prefers_html(&accept("application/json")));
+        assert!(!prefers_html(&accept("text/html;q=0.5, application/problem+json")));
+        assert!(!prefers_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_problem_codes() {
+        let res = AppError::OopsError{err: "template missing".into()}.into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+
+        let problem = res.extensions().get::<Problem>().unwrap();
+        assert_eq!(problem.code, "internal_error");
+        assert_eq!(problem.detail.as_deref(), Some("Oops, something went wrong!"));
+
+        assert_eq!(AppError::RequestPayload.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_problem_details_keeps_content_range() {
+        let app = app(|| async { AppError::RangeNotSatisfiable { size: 1000 } });
+
+        let res = app.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */1000");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+    }
+
+    #[tokio::test]
+    async fn test_problem_details_keeps_headers() {
+        let app = app(|| async { AppError::InsufficientScope { scope: "uploads:write".into() } });
+
+        let res = app.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer error=\"insufficient_scope\", scope=\"uploads:write\"");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        assert!(res.headers().contains_key(CORRELATION_ID));
+    }
+
+    #[tokio::test]
+    async fn test_problem_details_negotiation() {
+        static NO_VIEWS: Dir = Dir::new("", &[]);
+        let send = |views: &'static Dir<'static>| {
+            let app = app_with_views(views, || async { AppError::NotFound });
+            app.oneshot(Request::get("/").header(header::ACCEPT, "text/html").body(Body::empty()).unwrap())
+        };
+
+        let res = send(&VIEWS).await.unwrap();
+        assert!(res.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+        assert_eq!(res.headers()[header::VARY], "accept");
+
+        let res = send(&NO_VIEWS).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON, "error.html can't render");
+        assert_eq!(res.headers()[header::VARY], "accept");
+    }
+}
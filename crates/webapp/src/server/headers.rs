@@ -0,0 +1,166 @@
+//! Typed request headers
+//!
+//! A type implementing [Header] declares the header name, how the value is parsed and validated and
+//! what happens when it's missing. It can then be extracted in a handler with [TypedHeader], or
+//! checked up front for a whole route with the [typed_header] middleware, which stores the parsed
+//! value for handlers to pick up as `TypedHeader<T>` or `Extension<T>`.
+//!
+//! Every failure is answered with [AppError::InvalidHeader], which names the offending header.
+//!
+//! ```no_run
+//! # use webapp::server::headers::Header;
+//! #[derive(Clone)]
+//! struct ApiVersion(u8);
+//!
+//! impl Header for ApiVersion {
+//!     const NAME: &'static str = "x-api-version";
+//!
+//!     fn parse(value: &str) -> Result<Self, String> {
+//!         value.parse().map(ApiVersion).map_err(|_| "expected a number".into())
+//!     }
+//!
+//!     // optional, defaults to version 1
+//!     fn missing() -> Result<Self, String> {
+//!         Ok(ApiVersion(1))
+//!     }
+//! }
+//! ```
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use super::error::AppError;
+
+/// Header that can be extracted with [TypedHeader]
+pub trait Header: Sized + Clone + Send + Sync + 'static {
+    /// Header name, matched case insensitively
+    const NAME: &'static str;
+
+    /// Parses and validates the header value, the error describes what is wrong with it
+    fn parse(value: &str) -> Result<Self, String>;
+
+    /// Called when the header is absent, required headers (the default) reject the request
+    fn missing() -> Result<Self, String> {
+        Err("is missing".into())
+    }
+
+    /// Decodes the header from a header map
+    fn decode(headers: &HeaderMap) -> Result<Self, AppError> {
+        let invalid = |reason: String| AppError::InvalidHeader { header: Self::NAME, reason };
+
+        let mut values = headers.get_all(Self::NAME).iter();
+        let value = match (values.next(), values.next()) {
+            (None, _) => return Self::missing().map_err(invalid),
+            (Some(_), Some(_)) => return Err(invalid("was sent more than once".into())),
+            (Some(value), None) => value,
+        };
+
+        let value = value.to_str().map_err(|_| invalid("contains non visible ASCII characters".into()))?;
+        Self::parse(value.trim()).map_err(invalid)
+    }
+}
+
+/// Optional header, absent headers become `None` while invalid ones are still rejected
+impl<T: Header> Header for Option<T> {
+    const NAME: &'static str = T::NAME;
+
+    fn parse(value: &str) -> Result<Self, String> {
+        T::parse(value).map(Some)
+    }
+
+    fn missing() -> Result<Self, String> {
+        Ok(None)
+    }
+}
+
+/// Extractor for a [Header]
+#[derive(Debug, Clone)]
+pub struct TypedHeader<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for TypedHeader<T>
+where
+    T: Header,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // already parsed by the typed_header middleware
+        if let Some(header) = parts.extensions.get::<T>() {
+            return Ok(TypedHeader(header.clone()));
+        }
+        T::decode(&parts.headers).map(TypedHeader)
+    }
+}
+
+/// Middleware rejecting requests without a valid `T` header
+/// (`middleware::from_fn(typed_header::<T>)`), handlers receive the parsed value as an extension.
+pub async fn typed_header<T: Header>(mut req: Request, next: Next) -> Result<Response, AppError> {
+    let header = T::decode(req.headers())?;
+    req.extensions_mut().insert(header);
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, middleware, routing::get, Extension, Router, body::Body};
+    use tower::ServiceExt;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Port(u16);
+
+    impl Header for Port {
+        const NAME: &'static str = "x-port";
+
+        fn parse(value: &str) -> Result<Self, String> {
+            match value.parse() {
+                Ok(0) | Err(_) => Err(format!("{value:?} is not a port")),
+                Ok(port) => Ok(Port(port)),
+            }
+        }
+    }
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Port", value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Port::decode(&headers(&[" 443 "])).unwrap(), Port(443));
+        assert_eq!(Option::<Port>::decode(&headers(&[])).unwrap(), None);
+
+        let rejection = |headers| match Option::<Port>::decode(&headers) {
+            Err(AppError::InvalidHeader { header, reason }) => format!("{header} {reason}"),
+            other => panic!("expected a rejection, got {other:?}"),
+        };
+        assert_eq!(rejection(headers(&["0"])), "x-port \"0\" is not a port");
+        assert_eq!(rejection(headers(&["1", "2"])), "x-port was sent more than once");
+        assert!(matches!(Port::decode(&headers(&[])), Err(AppError::InvalidHeader { reason, .. }) if reason == "is missing"));
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let app = Router::new()
+            .route("/", get(|Extension(port): Extension<Port>, TypedHeader(again): TypedHeader<Port>| async move {
+                assert_eq!(port, again);
+                port.0.to_string()
+            }))
+            .layer(middleware::from_fn(typed_header::<Port>));
+
+        let res = app.clone().oneshot(Request::get("/").header("x-port", "8080").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = app.oneshot(Request::get("/").header("x-port", "http").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.extensions().get::<super::super::error::Problem>().unwrap().code, "request_payload");
+    }
+}
@@ -0,0 +1,346 @@
+//! Translations for the views
+//!
+//! [Catalogs] holds a Fluent bundle per locale, loaded from a directory with one subdirectory per
+//! locale (`locales/de/*.ftl`). The [negotiate_locale] middleware picks the best available locale
+//! from `Accept-Language` and stores it as a [Locale] request extension, templates receive it as the
+//! `locale` variable and translate through the `t()` function [Catalogs::register] adds:
+//!
+//! ```jinja
+//! {{ t("error-title", status=status) }}
+//! ```
+//!
+//! Messages missing for a locale fall back to the fallback locale, and to the key itself when that
+//! lacks it too. With [Catalogs::with_missing_report] enabled, as in development, those misses are
+//! recorded and listed by [missing_keys_handler] together with keys a locale never translated.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource, FluentValue};
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use include_dir::Dir;
+use minijinja::{value::Kwargs, Environment, Value};
+use thiserror::Error;
+use unic_langid::LanguageIdentifier;
+
+#[derive(Error, Debug)]
+pub enum I18nError {
+    #[error("couldn't read {path}: {source}")]
+    Io{path: PathBuf, source: std::io::Error},
+
+    #[error("{name} is not a valid locale")]
+    InvalidLocale{name: String},
+
+    #[error("invalid catalog {file}: {errors}")]
+    Syntax{file: String, errors: String},
+
+    #[error("fallback locale {locale} has no catalog")]
+    MissingFallback{locale: String},
+}
+
+/// Locale negotiated for the current request, available as a request extension
+#[derive(Debug, Clone, PartialEq)]
+pub struct Locale(pub LanguageIdentifier);
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct Catalog {
+    bundle: FluentBundle<FluentResource>,
+    keys: BTreeSet<String>,
+}
+
+struct Inner {
+    fallback: LanguageIdentifier,
+    available: Vec<LanguageIdentifier>,
+    catalogs: HashMap<String, Catalog>,
+    // (locale, key) looked up but not translated, only recorded when reporting is enabled
+    missing: Option<Mutex<BTreeSet<(String, String)>>>,
+}
+
+/// Translation catalogs of all locales, cheap to clone
+#[derive(Clone)]
+pub struct Catalogs {
+    inner: Arc<Inner>,
+}
+
+impl Catalogs {
+    /// Loads `<dir>/<locale>/*.ftl`
+    pub fn load(dir: impl AsRef<Path>, fallback: &str) -> Result<Catalogs, I18nError> {
+        let dir = dir.as_ref();
+        let io = |path: &Path| { let path = path.to_owned(); move |source| I18nError::Io { path, source } };
+
+        let mut sources = Vec::new();
+        for locale in std::fs::read_dir(dir).map_err(io(dir))? {
+            let locale = locale.map_err(io(dir))?.path();
+            if !locale.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(&locale).map_err(io(&locale))? {
+                let file = file.map_err(io(&locale))?.path();
+                if file.extension().is_some_and(|ext| ext == "ftl") {
+                    let source = std::fs::read_to_string(&file).map_err(io(&file))?;
+                    sources.push((dir_name(&locale), file.display().to_string(), source));
+                }
+            }
+        }
+        Catalogs::from_sources(sources, fallback)
+    }
+
+    /// Loads catalogs embedded into the binary (`include_dir!`), laid out like [Catalogs::load]
+    pub fn embedded(dir: &'static Dir<'static>, fallback: &str) -> Result<Catalogs, I18nError> {
+        let sources = dir.dirs()
+            .flat_map(|locale| locale.files().map(move |file| (locale, file)))
+            .filter(|(_, file)| file.path().extension().is_some_and(|ext| ext == "ftl"))
+            .map(|(locale, file)| {
+                let source = file.contents_utf8().ok_or_else(|| I18nError::Syntax {
+                    file: file.path().display().to_string(),
+                    errors: "not valid UTF-8".into(),
+                })?;
+                Ok((dir_name(locale.path()), file.path().display().to_string(), source.to_owned()))
+            })
+            .collect::<Result<Vec<_>, I18nError>>()?;
+        Catalogs::from_sources(sources, fallback)
+    }
+
+    /// Builds the catalogs from `(locale, file name, source)`
+    fn from_sources(sources: Vec<(String, String, String)>, fallback: &str) -> Result<Catalogs, I18nError> {
+        let mut catalogs: HashMap<String, Catalog> = HashMap::new();
+
+        for (locale, file, source) in sources {
+            let langid: LanguageIdentifier = locale.parse().map_err(|_| I18nError::InvalidLocale { name: locale.clone() })?;
+            let resource = FluentResource::try_new(source).map_err(|(_, errors)| I18nError::Syntax {
+                file: file.clone(),
+                errors: errors.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "),
+            })?;
+
+            let catalog = catalogs.entry(langid.to_string()).or_insert_with(|| {
+                let mut bundle = FluentBundle::new_concurrent(vec![langid]);
+                // isolation marks end up as invisible characters in the HTML
+                bundle.set_use_isolating(false);
+                Catalog { bundle, keys: BTreeSet::new() }
+            });
+            catalog.keys.extend(resource.entries().filter_map(|entry| match entry {
+                fluent_syntax::ast::Entry::Message(message) => Some(message.id.name.to_owned()),
+                _ => None,
+            }));
+            catalog.bundle.add_resource(resource).map_err(|errors| I18nError::Syntax {
+                file,
+                errors: errors.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "),
+            })?;
+        }
+
+        let fallback: LanguageIdentifier = fallback.parse().map_err(|_| I18nError::InvalidLocale { name: fallback.to_owned() })?;
+        if !catalogs.contains_key(&fallback.to_string()) {
+            return Err(I18nError::MissingFallback { locale: fallback.to_string() });
+        }
+
+        let mut available: Vec<LanguageIdentifier> = catalogs.keys().filter_map(|locale| locale.parse().ok()).collect();
+        available.sort_by_key(ToString::to_string);
+        Ok(Catalogs { inner: Arc::new(Inner { fallback, available, catalogs, missing: None }) })
+    }
+
+    /// Records messages that couldn't be translated, meant for development
+    pub fn with_missing_report(self, enabled: bool) -> Catalogs {
+        self.configure(|inner| inner.missing = enabled.then(Default::default))
+    }
+
+    fn configure(mut self, configure: impl FnOnce(&mut Inner)) -> Catalogs {
+        configure(Arc::get_mut(&mut self.inner).expect("catalogs are configured before they're shared"));
+        self
+    }
+
+    pub fn fallback(&self) -> Locale {
+        Locale(self.inner.fallback.clone())
+    }
+
+    /// Best available locale for an `Accept-Language` header value
+    pub fn negotiate(&self, accept_language: Option<&str>) -> Locale {
+        let requested = accept_language.map(requested_locales).unwrap_or_default();
+        let negotiated = negotiate_languages(&requested, &self.inner.available, Some(&self.inner.fallback), NegotiationStrategy::Lookup);
+        Locale(negotiated.first().map_or_else(|| self.inner.fallback.clone(), |locale| (*locale).clone()))
+    }
+
+    /// Translates `key`, falling back to the fallback locale and then to the key itself
+    pub fn translate(&self, locale: &str, key: &str, args: Option<&FluentArgs>) -> String {
+        let fallback = self.inner.fallback.to_string();
+        let mut candidates = vec![locale];
+        if locale != fallback {
+            candidates.push(&fallback);
+        }
+
+        for (i, candidate) in candidates.into_iter().enumerate() {
+            let Some(catalog) = self.inner.catalogs.get(candidate) else { continue };
+            let Some(pattern) = catalog.bundle.get_message(key).and_then(|message| message.value()) else { continue };
+
+            if i > 0 {
+                self.record_missing(locale, key);
+            }
+            let mut errors = Vec::new();
+            return catalog.bundle.format_pattern(pattern, args, &mut errors).into_owned();
+        }
+
+        self.record_missing(locale, key);
+        key.to_owned()
+    }
+
+    fn record_missing(&self, locale: &str, key: &str) {
+        if let Some(missing) = &self.inner.missing {
+            if missing.lock().unwrap().insert((locale.to_owned(), key.to_owned())) {
+                tracing::warn!(key, locale, "missing translation");
+            }
+        }
+    }
+
+    /// Adds the `t(key, **args)` function, translating into the `locale` variable of the template
+    pub fn register(&self, env: &mut Environment<'static>) {
+        let catalogs = self.clone();
+        env.add_function("t", move |state: &minijinja::State, key: &str, kwargs: Kwargs| -> Result<String, minijinja::Error> {
+            let locale = state.lookup("locale").and_then(|locale| locale.as_str().map(str::to_owned))
+                .unwrap_or_else(|| catalogs.inner.fallback.to_string());
+
+            let mut args = FluentArgs::new();
+            for name in kwargs.args() {
+                args.set(name.to_owned(), fluent_value(kwargs.get::<Value>(name)?));
+            }
+            Ok(catalogs.translate(&locale, key, Some(&args)))
+        });
+    }
+
+    /// Keys looked up without a translation, and keys of the fallback locale other locales lack
+    pub fn missing_report(&self) -> String {
+        let mut report = String::new();
+
+        if let Some(missing) = &self.inner.missing {
+            for (locale, key) in missing.lock().unwrap().iter() {
+                report.push_str(&format!("missing {locale} {key}\n"));
+            }
+        }
+
+        let fallback = &self.inner.catalogs[&self.inner.fallback.to_string()].keys;
+        let catalogs: BTreeMap<_, _> = self.inner.catalogs.iter().collect();
+        for (locale, catalog) in catalogs {
+            for key in fallback.difference(&catalog.keys) {
+                report.push_str(&format!("untranslated {locale} {key}\n"));
+            }
+        }
+        report
+    }
+}
+
+fn dir_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn fluent_value(value: Value) -> FluentValue<'static> {
+    if let Some(s) = value.as_str() {
+        return FluentValue::from(s.to_owned());
+    }
+    match f64::try_from(value.clone()) {
+        Ok(number) => FluentValue::from(number),
+        Err(_) => FluentValue::from(value.to_string()),
+    }
+}
+
+/// Language ranges of an `Accept-Language` header, most preferred first
+fn requested_locales(header: &str) -> Vec<LanguageIdentifier> {
+    let mut ranges: Vec<(f32, LanguageIdentifier)> = header.split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let tag = params.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+            let q = params.find_map(|p| p.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse().ok())?;
+            (q > 0.0).then_some((q, tag.parse().ok()?))
+        })
+        .collect();
+    ranges.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    ranges.into_iter().map(|(_, locale)| locale).collect()
+}
+
+/// Negotiates the [Locale] of every request (`middleware::from_fn_with_state(catalogs, negotiate_locale)`)
+pub async fn negotiate_locale(State(catalogs): State<Catalogs>, mut req: Request, next: Next) -> Response {
+    let accept_language = req.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok());
+    let locale = catalogs.negotiate(accept_language);
+    req.extensions_mut().insert(locale);
+    next.run(req).await
+}
+
+/// Lists missing translations, only meant to be routed in development
+pub async fn missing_keys_handler(State(catalogs): State<Catalogs>) -> String {
+    catalogs.missing_report()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalogs() -> Catalogs {
+        Catalogs::from_sources(vec![
+            ("en".into(), "en/main.ftl".into(), "hello = Hello { $name }\nbye = Bye\n".into()),
+            ("de".into(), "de/main.ftl".into(), "hello = Hallo { $name }\n".into()),
+            ("de-AT".into(), "de-AT/main.ftl".into(), "hello = Servus { $name }\nbye = Pfiat di\n".into()),
+        ], "en").unwrap().with_missing_report(true)
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let catalogs = catalogs();
+        assert_eq!(catalogs.negotiate(Some("fr-FR, de;q=0.8, en;q=0.5")).to_string(), "de");
+        assert_eq!(catalogs.negotiate(Some("en;q=0.1, de-AT")).to_string(), "de-AT");
+        assert_eq!(catalogs.negotiate(Some("de-CH")).to_string(), "de");
+        assert_eq!(catalogs.negotiate(Some("fr, de;q=0")).to_string(), "en");
+        assert_eq!(catalogs.negotiate(None), catalogs.fallback());
+    }
+
+    #[test]
+    fn test_translate() {
+        let catalogs = catalogs();
+        let args = FluentArgs::from_iter([("name", "Ada")]);
+        assert_eq!(catalogs.translate("de", "hello", Some(&args)), "Hallo Ada");
+        assert_eq!(catalogs.translate("de", "bye", None), "Bye");
+        assert_eq!(catalogs.translate("de", "unknown", None), "unknown");
+
+        assert_eq!(catalogs.missing_report(), "missing de bye\nmissing de unknown\nuntranslated de bye\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "catalogs are configured before they're shared")]
+    fn test_configure_shared() {
+        let catalogs = catalogs();
+        let _shared = catalogs.clone();
+        catalogs.with_missing_report(false);
+    }
+
+    #[test]
+    fn test_views() {
+        let catalogs = Catalogs::load(concat!(env!("CARGO_MANIFEST_DIR"), "/locales"), "en").unwrap();
+        assert_eq!(catalogs.missing_report(), "", "every locale translates every key");
+
+        let mut env = Environment::new();
+        env.set_loader(minijinja::path_loader(concat!(env!("CARGO_MANIFEST_DIR"), "/views")));
+        catalogs.register(&mut env);
+
+        let page = crate::template!(env, "error.html", {
+            text => "<gone>",
+            status => 404,
+            correlation_id => "abc",
+            locale => catalogs.negotiate(Some("de")).to_string()
+        }, globals = [t]).unwrap();
+
+        assert!(page.contains("<html lang=\"de\">"));
+        assert!(page.contains("<title>Fehler 404</title>"));
+        assert!(page.contains("<p>&lt;gone&gt;</p>"));
+        assert!(page.contains("<p>Referenz: abc</p>"));
+        assert!(page.contains("<footer>Ausgeliefert von webapp</footer>"));
+    }
+}
@@ -0,0 +1,348 @@
+//! Server lifecycle: health probes, in-flight request tracking and graceful shutdown
+//!
+//! [Lifecycle] counts requests until their response body has been sent completely, so a video
+//! stream counts as in flight for as long as it's streaming. On SIGTERM or SIGINT ([shutdown_signal])
+//! `/readyz` starts failing right away, the listener keeps accepting connections for
+//! [Lifecycle::pre_stop_delay] so load balancers notice before new connections get refused, then
+//! stops and in-flight requests get [Lifecycle::drain_timeout] to finish before the server exits anyway.
+//!
+//! ```no_run
+//! # use webapp::server::lifecycle::{shutdown_signal, Lifecycle};
+//! # async fn run(listener: tokio::net::TcpListener, app: axum::Router) {
+//! let lifecycle = Lifecycle::new(std::time::Duration::from_secs(30));
+//! lifecycle.serve(axum::serve(listener, app), shutdown_signal()).await.unwrap();
+//! # }
+//! ```
+use std::{
+    future::{Future, IntoFuture},
+    pin::Pin,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    task::{Context, Poll},
+    time::Duration,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    serve::{Serve, WithGracefulShutdown},
+};
+use http_body::{Frame, SizeHint};
+use tokio::sync::{watch, Notify};
+
+/// Future the server's graceful shutdown waits for
+pub type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[derive(Debug)]
+struct Inner {
+    drain_timeout: Duration,
+    pre_stop_delay: Duration,
+    // flips to true once, every server of the process stops accepting connections pre_stop_delay later
+    shutting_down: watch::Sender<bool>,
+    in_flight: AtomicUsize,
+    // woken whenever the last in-flight request finishes
+    idle: Notify,
+}
+
+/// Shared lifecycle state, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Lifecycle {
+    inner: Arc<Inner>,
+}
+
+/// Request in flight, counted until dropped
+#[derive(Debug)]
+pub struct InFlight {
+    lifecycle: Lifecycle,
+}
+
+impl Lifecycle {
+    pub fn new(drain_timeout: Duration) -> Lifecycle {
+        Lifecycle { inner: Arc::new(Inner {
+            drain_timeout,
+            pre_stop_delay: Duration::ZERO,
+            shutting_down: watch::Sender::new(false),
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }) }
+    }
+
+    /// Keeps accepting connections for `delay` after readiness probes started failing
+    pub fn with_pre_stop_delay(mut self, delay: Duration) -> Self {
+        Arc::get_mut(&mut self.inner).expect("the lifecycle is configured before it's shared").pre_stop_delay = delay;
+        self
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.inner.drain_timeout
+    }
+
+    pub fn pre_stop_delay(&self) -> Duration {
+        self.inner.pre_stop_delay
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.shutting_down.borrow()
+    }
+
+    /// Marks the server as shutting down, readiness probes fail from now on
+    pub fn begin_shutdown(&self) {
+        self.inner.shutting_down.send_replace(true);
+    }
+
+    /// Completes the pre-stop delay after shutdown began, usable as the shutdown signal of further servers
+    pub fn shutdown_requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let began = self.shutdown_began();
+        let delay = self.inner.pre_stop_delay;
+        async move {
+            began.await;
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn shutdown_began(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutting_down = self.inner.shutting_down.subscribe();
+        async move {
+            let _ = shutting_down.wait_for(|shutting_down| *shutting_down).await;
+        }
+    }
+
+    /// Counts a request as in flight, see [InFlight::attach] to keep counting while the body streams
+    pub fn track(&self) -> InFlight {
+        self.inner.in_flight.fetch_add(1, Ordering::AcqRel);
+        InFlight { lifecycle: self.clone() }
+    }
+
+    /// Waits until no request is in flight anymore
+    pub async fn drained(&self) {
+        loop {
+            let idle = self.inner.idle.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Runs the server until `signal` completes or shutdown began otherwise, then drains in-flight
+    /// requests for at most the drain timeout
+    pub async fn serve<M, S, F>(&self, server: Serve<M, S>, signal: F) -> std::io::Result<()>
+    where
+        WithGracefulShutdown<M, S, ShutdownSignal>: IntoFuture<Output = std::io::Result<()>, IntoFuture: Send>,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let stop: ShutdownSignal = Box::pin(self.shutdown_requested());
+        let graceful = server.with_graceful_shutdown(stop).into_future();
+        self.drain(graceful, signal).await
+    }
+
+    /// Runs `server` until `signal` completes or shutdown began otherwise, then gives it the pre-stop
+    /// delay plus the drain timeout to finish. The server is expected to stop on its own once [Lifecycle::shutdown_requested] completes.
+    pub async fn drain<S, F>(&self, server: S, signal: F) -> std::io::Result<()>
+    where
+        S: Future<Output = std::io::Result<()>>,
+        F: Future<Output = ()>,
+    {
+        let begin = async {
+            tokio::select! {
+                _ = signal => self.begin_shutdown(),
+                _ = self.shutdown_began() => {},
+            }
+        };
+        let deadline = async {
+            begin.await;
+            tokio::time::sleep(self.inner.pre_stop_delay + self.inner.drain_timeout).await;
+        };
+
+        tokio::select! {
+            res = server => res,
+            _ = deadline => {
+                tracing::warn!("drain timeout of {:?} elapsed, dropping {} in-flight requests", self.inner.drain_timeout, self.in_flight());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl InFlight {
+    /// Keeps the request in flight until the response body has been sent, or the client went away
+    pub fn attach(self, response: Response) -> Response {
+        response.map(|body| Body::new(TrackedBody { inner: body, _in_flight: self }))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let inner = &self.lifecycle.inner;
+        if inner.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            inner.idle.notify_waiters();
+        }
+    }
+}
+
+struct TrackedBody {
+    inner: Body,
+    _in_flight: InFlight,
+}
+
+impl http_body::Body for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Completes on SIGTERM or SIGINT (Ctrl+C)
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("couldn't listen for SIGINT: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; }
+            Err(err) => {
+                tracing::error!("couldn't listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Liveness probe, answers as long as the process serves requests
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness probe, fails once shutdown started so load balancers stop sending traffic
+pub async fn readyz(State(lifecycle): State<Lifecycle>) -> Response {
+    match lifecycle.is_shutting_down() {
+        true => (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response(),
+        false => "ready".into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_in_flight_until_body_sent() {
+        let lifecycle = Lifecycle::new(Duration::from_secs(1));
+        let response = lifecycle.track().attach(Response::new(Body::from("streamed")));
+        assert_eq!(lifecycle.in_flight(), 1);
+
+        let drained = tokio::spawn({
+            let lifecycle = lifecycle.clone();
+            async move { lifecycle.drained().await }
+        });
+
+        let mut body = response.into_body().into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "streamed");
+        assert_eq!(lifecycle.in_flight(), 1, "the body hasn't been dropped yet");
+        drop(body);
+
+        tokio::time::timeout(Duration::from_secs(1), drained).await.unwrap().unwrap();
+        assert_eq!(lifecycle.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let lifecycle = Lifecycle::new(Duration::ZERO);
+        assert_eq!(readyz(State(lifecycle.clone())).await.status(), StatusCode::OK);
+
+        lifecycle.begin_shutdown();
+        assert_eq!(readyz(State(lifecycle)).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let lifecycle = Lifecycle::new(Duration::from_millis(50));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // a response that never finishes streaming
+        let app = axum::Router::new().route("/", axum::routing::get(|| async {
+            Body::from_stream(futures_util::stream::pending::<Result<Bytes, std::io::Error>>())
+        }));
+        let app = app.layer(axum::middleware::from_fn_with_state(lifecycle.clone(), |State(lifecycle): State<Lifecycle>, req, next: axum::middleware::Next| async move {
+            lifecycle.track().attach(next.run(req).await)
+        }));
+
+        let (trigger, signal) = tokio::sync::oneshot::channel::<()>();
+        let client = tokio::spawn({
+            let lifecycle = lifecycle.clone();
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                tokio::io::AsyncWriteExt::write_all(&mut stream, b"GET / HTTP/1.1\r\nhost: test\r\n\r\n").await.unwrap();
+                while lifecycle.in_flight() == 0 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                trigger.send(()).unwrap();
+                stream
+            }
+        });
+
+        let server = lifecycle.serve(axum::serve(listener, app), async { let _ = signal.await; });
+        tokio::time::timeout(Duration::from_secs(2), server).await.expect("drain timeout stops the server").unwrap();
+        client.await.unwrap();
+        assert!(lifecycle.is_shutting_down());
+    }
+
+    #[tokio::test]
+    async fn test_pre_stop_delay() {
+        let lifecycle = Lifecycle::new(Duration::from_secs(1)).with_pre_stop_delay(Duration::from_millis(500));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/readyz", axum::routing::get(readyz)).with_state(lifecycle.clone());
+
+        let (trigger, signal) = tokio::sync::oneshot::channel::<()>();
+        let client = tokio::spawn({
+            let lifecycle = lifecycle.clone();
+            async move {
+                trigger.send(()).unwrap();
+                lifecycle.shutdown_began().await;
+
+                // readiness already fails, but new connections are still served
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                tokio::io::AsyncWriteExt::write_all(&mut stream, b"GET /readyz HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n").await.unwrap();
+                let mut response = String::new();
+                tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response).await.unwrap();
+                response
+            }
+        });
+
+        let started = std::time::Instant::now();
+        let server = lifecycle.serve(axum::serve(listener, app), async { let _ = signal.await; });
+        tokio::time::timeout(Duration::from_secs(2), server).await.expect("stops accepting after the delay").unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        let response = client.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    }
+}
@@ -0,0 +1,387 @@
+//! Media files served with HTTP range and conditional request support
+//!
+//! [MediaDir] serves any file below its root directory. Browsers need `Range` support to seek in
+//! video and audio, so single ranges are answered with `206 Partial Content` and multiple ranges
+//! with a `multipart/byteranges` body. `ETag` and `Last-Modified` validators enable conditional
+//! requests (`If-None-Match`, `If-Modified-Since`, `If-Match`, `If-Unmodified-Since`, `If-Range`).
+//!
+//! It can be used as a tower service (`Router::nest_service("/media", media_dir)`) or from a
+//! handler through [MediaDir::serve].
+use std::{
+    convert::Infallible,
+    future::Future,
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::{fs::File, io::{AsyncRead, AsyncReadExt, AsyncSeekExt}};
+use tokio_util::io::ReaderStream;
+use tower::Service;
+use super::error::AppError;
+
+/// More ranges than this are served as the full file, guards against tiny range floods
+const MAX_RANGES: usize = 16;
+const BOUNDARY: &str = "webapp-byteranges";
+
+/// Directory media files are served from
+#[derive(Debug, Clone)]
+pub struct MediaDir {
+    root: PathBuf,
+}
+
+/// Inclusive byte range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{size}", self.start, self.end)
+    }
+}
+
+/// File validators used by conditional requests
+struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl MediaDir {
+    pub fn new(root: impl Into<PathBuf>) -> MediaDir {
+        MediaDir { root: root.into() }
+    }
+
+    /// Serves the file at `path`, relative to the root, answering the request `headers`
+    pub async fn serve(&self, path: &str, method: &Method, headers: &HeaderMap) -> Result<Response, AppError> {
+        if method != Method::GET && method != Method::HEAD {
+            return Err(AppError::OopsWithDetails { code: StatusCode::METHOD_NOT_ALLOWED, detail: format!("{method} is not supported") });
+        }
+
+        let path = self.resolve(path).ok_or(AppError::NotFound)?;
+        let file = File::open(&path).await.map_err(|_| AppError::NotFound)?;
+        let metadata = file.metadata().await.map_err(|_| AppError::NotFound)?;
+        if !metadata.is_file() {
+            return Err(AppError::NotFound);
+        }
+
+        let size = metadata.len();
+        let last_modified = metadata.modified().ok();
+        let mtime = last_modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+        let validators = Validators { etag: format!("\"{size:x}-{mtime:x}\""), last_modified };
+
+        let mut res = match check_preconditions(headers, &validators) {
+            Precondition::Failed => return Err(AppError::PreconditionFailed),
+            Precondition::NotModified => StatusCode::NOT_MODIFIED.into_response(),
+            Precondition::Passed => {
+                let ranges = if_range_matches(headers, &validators)
+                    .then(|| headers.get(header::RANGE).and_then(|v| v.to_str().ok()))
+                    .flatten()
+                    .and_then(|range| parse_ranges(range, size));
+
+                let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+                match ranges {
+                    Some(Err(())) => return Err(AppError::RangeNotSatisfiable { size }),
+                    Some(Ok(ranges)) if ranges.len() == 1 => single_range(file, ranges[0], size, content_type.as_ref()).await?,
+                    Some(Ok(ranges)) => multi_range(&path, &ranges, size, content_type.as_ref()).await?,
+                    None => full(file, size, content_type.as_ref()),
+                }
+            }
+        };
+
+        let res_headers = res.headers_mut();
+        res_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(etag) = HeaderValue::from_str(&validators.etag) {
+            res_headers.insert(header::ETAG, etag);
+        }
+        if let Some(last_modified) = validators.last_modified {
+            if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(last_modified)) {
+                res_headers.insert(header::LAST_MODIFIED, value);
+            }
+        }
+
+        if method == Method::HEAD {
+            *res.body_mut() = Body::empty();
+        }
+        Ok(res)
+    }
+
+    /// Joins `path` onto the root, refusing anything that could escape it
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'));
+        relative.components().all(|c| matches!(c, Component::Normal(_))).then(|| self.root.join(relative))
+    }
+}
+
+fn full(file: File, size: u64, content_type: &str) -> Response {
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, size)
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .unwrap_or_else(|_| AppError::Oops.into_response())
+}
+
+async fn single_range(mut file: File, range: ByteRange, size: u64, content_type: &str) -> Result<Response, AppError> {
+    file.seek(SeekFrom::Start(range.start)).await.map_err(|err| AppError::OopsError { err: err.to_string() })?;
+
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, range.len())
+        .header(header::CONTENT_RANGE, range.content_range(size))
+        .body(Body::from_stream(ReaderStream::new(file.take(range.len()))))
+        .map_err(|err| AppError::OopsError { err: err.to_string() })
+}
+
+async fn multi_range(path: &Path, ranges: &[ByteRange], size: u64, content_type: &str) -> Result<Response, AppError> {
+    let mut body: Box<dyn AsyncRead + Send + Unpin> = Box::new(tokio::io::empty());
+    let mut length = 0;
+
+    // every part reads through its own handle, so the parts can simply be chained
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{BOUNDARY}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(size)
+        );
+        let mut file = File::open(path).await.map_err(|err| AppError::OopsError { err: err.to_string() })?;
+        file.seek(SeekFrom::Start(range.start)).await.map_err(|err| AppError::OopsError { err: err.to_string() })?;
+
+        length += part_header.len() as u64 + range.len();
+        body = Box::new(body.chain(std::io::Cursor::new(part_header)).chain(file.take(range.len())));
+    }
+    let closing = format!("\r\n--{BOUNDARY}--\r\n");
+    length += closing.len() as u64;
+    body = Box::new(body.chain(std::io::Cursor::new(closing)));
+
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={BOUNDARY}"))
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::from_stream(ReaderStream::new(body)))
+        .map_err(|err| AppError::OopsError { err: err.to_string() })
+}
+
+/// Parses a `Range` header, see RFC 9110 section 14.
+///
+/// Returns `None` when the header should be ignored, `Some(Err(()))` when no range can be satisfied.
+fn parse_ranges(header: &str, size: u64) -> Option<Result<Vec<ByteRange>, ()>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (start, end) = spec.split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix > 0 && size > 0).then(|| ByteRange { start: size.saturating_sub(suffix), end: size - 1 })
+            }
+            (start, end) => {
+                let start: u64 = start.parse().ok()?;
+                let end = match end {
+                    "" => u64::MAX,
+                    end => end.parse().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                (start < size).then(|| ByteRange { start, end: end.min(size - 1) })
+            }
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return Some(Err(()));
+    }
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    Some(Ok(ranges))
+}
+
+enum Precondition {
+    Passed,
+    NotModified,
+    Failed,
+}
+
+fn etag_matches(header: &str, etag: &str, weak: bool) -> bool {
+    let strip = |tag: &str| if weak { tag.trim_start_matches("W/").to_owned() } else { tag.to_owned() };
+    header.split(',').map(str::trim).any(|tag| tag == "*" || ((weak || !tag.starts_with("W/")) && strip(tag) == etag))
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+// http dates have a resolution of a second
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Evaluates the preconditions in the order of RFC 9110 section 13.2.2
+fn check_preconditions(headers: &HeaderMap, validators: &Validators) -> Precondition {
+    if let Some(if_match) = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok()) {
+        if !etag_matches(if_match, &validators.etag, false) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(since), Some(modified)) = (header_date(headers, header::IF_UNMODIFIED_SINCE), validators.last_modified) {
+        if secs(modified) > secs(since) {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        if etag_matches(if_none_match, &validators.etag, true) {
+            return Precondition::NotModified;
+        }
+    } else if let (Some(since), Some(modified)) = (header_date(headers, header::IF_MODIFIED_SINCE), validators.last_modified) {
+        if secs(modified) <= secs(since) {
+            return Precondition::NotModified;
+        }
+    }
+    Precondition::Passed
+}
+
+/// Whether the `Range` header applies, `If-Range` asks for the full file once it changed
+fn if_range_matches(headers: &HeaderMap, validators: &Validators) -> bool {
+    let Some(if_range) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) else { return true };
+
+    if if_range.starts_with('"') {
+        return if_range == validators.etag;
+    }
+    match (httpdate::parse_http_date(if_range).ok(), validators.last_modified) {
+        (Some(date), Some(modified)) => secs(date) == secs(modified),
+        _ => false,
+    }
+}
+
+impl Service<Request> for MediaDir {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let media = self.clone();
+        Box::pin(async move {
+            let path = percent_decode(request.uri().path());
+            Ok(media.serve(&path, request.method(), request.headers()).await.into_response())
+        })
+    }
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tower::ServiceExt;
+
+    fn request(path: &str, headers: &[(&'static str, &str)]) -> Request {
+        let mut req = Request::builder().uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    async fn media() -> (tempfile::TempDir, MediaDir) {
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join("clip.mp4"), b"0123456789").await.unwrap();
+        let media = MediaDir::new(dir.path());
+        (dir, media)
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_ranges("bytes=0-4", 10), Some(Ok(vec![ByteRange { start: 0, end: 4 }])));
+        assert_eq!(parse_ranges("bytes=-3, 8-", 10), Some(Ok(vec![ByteRange { start: 7, end: 9 }, ByteRange { start: 8, end: 9 }])));
+        assert_eq!(parse_ranges("bytes=20-30", 10), Some(Err(())));
+        assert_eq!(parse_ranges("bytes=5-1", 10), None);
+        assert_eq!(parse_ranges("items=0-1", 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_ranges() {
+        let (_dir, media) = media().await;
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "video/mp4");
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[("range", "bytes=2-4")])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await.unwrap(), "234");
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[("range", "bytes=0-1,-2")])).await.unwrap();
+        let length: usize = res.headers()[header::CONTENT_LENGTH].to_str().unwrap().parse().unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), length);
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-1/10\r\n\r\n01"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89"));
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[("range", "bytes=50-")])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn test_conditional() {
+        let (_dir, media) = media().await;
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[])).await.unwrap();
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_owned();
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[("if-none-match", &etag)])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = media.clone().oneshot(request("/clip.mp4", &[("if-match", "\"other\"")])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        // a stale If-Range gets the whole file
+        let res = media.clone().oneshot(request("/clip.mp4", &[("range", "bytes=0-1"), ("if-range", "\"other\"")])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = media.oneshot(request("/../clip.mp4", &[])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
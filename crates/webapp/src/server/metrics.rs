@@ -0,0 +1,277 @@
+//! Request metrics in the Prometheus text exposition format
+//!
+//! [Metrics] records per-route request counts by status class, in-flight requests and latency
+//! histograms. Routes are labelled with their template (`/uploads/resumable/:id`) rather than the
+//! raw path, so the number of series stays bounded; requests that didn't match a route share the
+//! `unmatched` label. Services mounted with `Router::nest_service`, and nested routers answering
+//! through their fallback like [Assets::router](super::assets::Assets::router), don't expose a
+//! template to outer layers, register their mount points with [Metrics::with_nested]. Methods
+//! other than the standard ones are labelled `OTHER`.
+//!
+//! Record requests with [Metrics::track] from a middleware and expose them with [metrics_handler].
+use std::{collections::BTreeMap, fmt::Write, sync::{Arc, Mutex}, time::Instant};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+/// Content type of the Prometheus text format
+pub const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Route label of requests no route matched
+pub const UNMATCHED: &str = "unmatched";
+/// Latency histogram bucket bounds in seconds, the Prometheus client defaults
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    // non cumulative, summed up when rendered
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    // (method, route, status class)
+    requests: BTreeMap<(String, String, &'static str), u64>,
+    // (method, route)
+    in_flight: BTreeMap<(String, String), i64>,
+    latency: BTreeMap<(String, String), Histogram>,
+}
+
+/// Shared request metrics, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    nested: Arc<Vec<String>>,
+}
+
+/// Request being tracked, counted as in flight until [RequestTimer::finish] or drop
+#[must_use = "the request is only recorded once finished"]
+pub struct RequestTimer {
+    metrics: Metrics,
+    method: String,
+    route: String,
+    started: Instant,
+    finished: bool,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Labels requests below `prefix` as `{prefix}/*path`, for services mounted with `nest_service`
+    /// and nested routers without routes of their own
+    pub fn with_nested(mut self, prefix: &str) -> Metrics {
+        Arc::make_mut(&mut self.nested).push(prefix.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Starts tracking a request, labelled by the route template axum matched
+    pub fn track(&self, req: &Request) -> RequestTimer {
+        if let Some(matched) = req.extensions().get::<MatchedPath>() {
+            return self.track_route(req.method(), matched.as_str());
+        }
+
+        let path = req.uri().path();
+        let nested = self.nested.iter().find(|prefix| {
+            path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        match nested {
+            Some(prefix) => self.track_route(req.method(), &format!("{prefix}/*path")),
+            None => self.track_route(req.method(), UNMATCHED),
+        }
+    }
+
+    /// Starts tracking a request for an explicit route label
+    pub fn track_route(&self, method: &Method, route: &str) -> RequestTimer {
+        // clients can send any token as the method, each would be a new series
+        let method = match *method {
+            Method::GET | Method::HEAD | Method::POST | Method::PUT | Method::DELETE
+                | Method::CONNECT | Method::OPTIONS | Method::TRACE | Method::PATCH => method.as_str(),
+            _ => "OTHER",
+        };
+        let key = (method.to_owned(), route.to_owned());
+        *self.registry.lock().unwrap().in_flight.entry(key.clone()).or_default() += 1;
+
+        RequestTimer { metrics: self.clone(), method: key.0, route: key.1, started: Instant::now(), finished: false }
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Total number of HTTP requests.\n# TYPE http_requests_total counter\n");
+        for ((method, route, status), count) in &registry.requests {
+            let _ = writeln!(out, "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{status}\"}} {count}", escape(method), escape(route));
+        }
+
+        out.push_str("# HELP http_requests_in_flight Number of HTTP requests being served.\n# TYPE http_requests_in_flight gauge\n");
+        for ((method, route), count) in &registry.in_flight {
+            let _ = writeln!(out, "http_requests_in_flight{{method=\"{}\",route=\"{}\"}} {count}", escape(method), escape(route));
+        }
+
+        out.push_str("# HELP http_request_duration_seconds HTTP request latency in seconds.\n# TYPE http_request_duration_seconds histogram\n");
+        for ((method, route), histogram) in &registry.latency {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{{labels}}} {}", histogram.count);
+        }
+        out
+    }
+}
+
+impl RequestTimer {
+    /// Records the finished request with the status it was answered with
+    pub fn finish(mut self, status: StatusCode) {
+        self.record(Some(status));
+    }
+
+    fn record(&mut self, status: Option<StatusCode>) {
+        self.finished = true;
+        let mut registry = self.metrics.registry.lock().unwrap();
+        let key = (std::mem::take(&mut self.method), std::mem::take(&mut self.route));
+
+        if let Some(in_flight) = registry.in_flight.get_mut(&key) {
+            *in_flight -= 1;
+        }
+        // the client went away before a response was produced
+        let Some(status) = status else { return };
+
+        *registry.requests.entry((key.0.clone(), key.1.clone(), status_class(status))).or_default() += 1;
+        registry.latency.entry(key).or_default().observe(self.started.elapsed().as_secs_f64());
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.record(None);
+        }
+    }
+}
+
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// Escapes a label value as required by the text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics (`Router::route("/metrics", get(metrics_handler))`)
+pub async fn metrics_handler(State(metrics): State<Metrics>) -> Response {
+    ([(header::CONTENT_TYPE, HeaderValue::from_static(TEXT_FORMAT))], metrics.render()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.track_route(&Method::GET, "/uploads/resumable/:id").finish(StatusCode::NO_CONTENT);
+        metrics.track_route(&Method::GET, "/uploads/resumable/:id").finish(StatusCode::NOT_FOUND);
+        let pending = metrics.track_route(&Method::POST, "/\"quoted\"");
+
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"/uploads/resumable/:id\",status=\"2xx\"} 1\n"));
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"/uploads/resumable/:id\",status=\"4xx\"} 1\n"));
+        assert!(text.contains("http_requests_in_flight{method=\"POST\",route=\"/\\\"quoted\\\"\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{method=\"GET\",route=\"/uploads/resumable/:id\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_count{method=\"GET\",route=\"/uploads/resumable/:id\"} 2\n"));
+
+        // dropped without a response, no longer in flight and not counted
+        drop(pending);
+        let text = metrics.render();
+        assert!(text.contains("http_requests_in_flight{method=\"POST\",route=\"/\\\"quoted\\\"\"} 0\n"));
+        assert!(!text.contains("method=\"POST\",route=\"/\\\"quoted\\\"\",status"));
+    }
+
+    #[test]
+    fn test_route_label() {
+        let metrics = Metrics::new().with_nested("/static/");
+        let request = |path: &str| Request::get(path).body(axum::body::Body::empty()).unwrap();
+
+        metrics.track(&request("/static/css/site.css")).finish(StatusCode::OK);
+        metrics.track(&request("/staticfile")).finish(StatusCode::NOT_FOUND);
+
+        let text = metrics.render();
+        assert!(text.contains("route=\"/static/*path\",status=\"2xx\"} 1\n"));
+        assert!(text.contains("route=\"unmatched\",status=\"4xx\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_nested_router_label() {
+        use axum::{middleware::{self, Next}, Router};
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("site.css"), "body {}").unwrap();
+        let assets = super::super::assets::Assets::new(dir.path(), "/static");
+        let metrics = Metrics::new().with_nested("/static");
+        let app = Router::new()
+            .nest("/static", assets.router())
+            .layer(middleware::from_fn_with_state(metrics.clone(), |State(metrics): State<Metrics>, req: Request, next: Next| async move {
+                let timer = metrics.track(&req);
+                let res = next.run(req).await;
+                timer.finish(res.status());
+                res
+            }));
+
+        // the assets router only has a fallback, so there's no matched path to label with
+        let res = app.oneshot(Request::get("/static/site.css").body(axum::body::Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(metrics.render().contains("http_requests_total{method=\"GET\",route=\"/static/*path\",status=\"2xx\"} 1\n"));
+    }
+
+    #[test]
+    fn test_method_label() {
+        let metrics = Metrics::new();
+        metrics.track_route(&Method::from_bytes(b"PROPFIND").unwrap(), "/").finish(StatusCode::METHOD_NOT_ALLOWED);
+        metrics.track_route(&Method::from_bytes(b"X-RANDOM-1").unwrap(), "/").finish(StatusCode::METHOD_NOT_ALLOWED);
+
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total{method=\"OTHER\",route=\"/\",status=\"4xx\"} 2\n"), "{text}");
+        assert!(!text.contains("PROPFIND"));
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.004);
+        histogram.observe(0.3);
+        histogram.observe(60.0);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[BUCKETS.iter().position(|b| *b == 0.5).unwrap()], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 2);
+        assert_eq!(histogram.count, 3);
+    }
+}
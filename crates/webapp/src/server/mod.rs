@@ -0,0 +1,17 @@
+//! Building blocks of the axum web server, the binary wires them together
+pub mod assets;
+pub mod auth;
+pub mod bearer;
+pub mod config;
+pub mod error;
+pub mod headers;
+pub mod i18n;
+pub mod lifecycle;
+pub mod media;
+pub mod metrics;
+pub mod ratelimit;
+pub mod session;
+pub mod telemetry;
+pub mod tls;
+pub mod upload;
+pub mod views;
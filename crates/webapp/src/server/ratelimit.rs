@@ -0,0 +1,567 @@
+//! Per-client rate limiting
+//!
+//! [RateLimitLayer] counts requests per client and route and answers requests over the limit with
+//! [AppError::TooManyRequests] (`429` with `Retry-After`). Every response of a limited route carries
+//! the `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and `RateLimit-Policy` headers of
+//! the IETF RateLimit header fields draft.
+//!
+//! - [Algorithm::TokenBucket] allows bursts of `limit` requests and refills evenly over `period`
+//! - [Algorithm::SlidingWindow] allows `limit` requests in any `period`, weighting the previous
+//!   window by how much of it still overlaps
+//!
+//! Routes are matched on their route pattern (`/users/:id`), routes without a quota of their own
+//! share the default quota. The layer has to be added with `Router::layer`, so it runs after routing.
+//! Keying by API key needs the [BearerLayer](super::bearer::BearerLayer) to run first.
+//!
+//! At most [MAX_CLIENTS] clients are tracked, once that many are busy new clients share a single
+//! quota per route, so clients inventing keys can't grow the counters without bound.
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tower::{Layer, Service};
+use super::{bearer::Claims, config::RateLimitConfig, error::AppError};
+
+const LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+/// Idle clients are forgotten every that many requests
+const SWEEP_INTERVAL: u64 = 1024;
+/// Counters kept at most, new clients share the overflow quota beyond that
+pub const MAX_CLIENTS: usize = 100_000;
+/// A full map is swept at most this often, new clients go straight to the overflow quota in between
+const FULL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const OVERFLOW: &str = "overflow";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    #[default]
+    TokenBucket,
+    SlidingWindow,
+}
+
+/// `limit` requests per `period`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub algorithm: Algorithm,
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub fn token_bucket(limit: u32, period: Duration) -> Quota {
+        Quota { algorithm: Algorithm::TokenBucket, limit, period }
+    }
+
+    pub fn sliding_window(limit: u32, period: Duration) -> Quota {
+        Quota { algorithm: Algorithm::SlidingWindow, limit, period }
+    }
+}
+
+/// What identifies a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyBy {
+    /// Address of the peer, or the `X-Forwarded-For` entry added by the outermost trusted proxy
+    ClientIp,
+    /// Subject of the bearer token, anonymous requests fall back to the client IP
+    ApiKey,
+    /// Value of a header, requests without it fall back to the client IP. Only use a header a proxy
+    /// in front sets or overwrites, clients can send any value otherwise.
+    Header(HeaderName),
+}
+
+impl FromStr for KeyBy {
+    type Err = String;
+
+    /// `ip`, `api_key` or `header:<name>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(KeyBy::ClientIp),
+            "api_key" => Ok(KeyBy::ApiKey),
+            _ => match s.strip_prefix("header:") {
+                Some(name) => name.parse().map(KeyBy::Header).map_err(|_| format!("{name:?} is not a header name")),
+                None => Err(format!("{s:?} is not one of ip, api_key or header:<name>")),
+            },
+        }
+    }
+}
+
+impl fmt::Display for KeyBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyBy::ClientIp => f.write_str("ip"),
+            KeyBy::ApiKey => f.write_str("api_key"),
+            KeyBy::Header(name) => write!(f, "header:{name}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Bucket {
+    Tokens{tokens: f64, updated: Instant},
+    Window{start: Instant, current: u32, previous: u32},
+}
+
+/// Outcome of counting a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// Until the quota is fully available again
+    reset: Duration,
+    /// Until the next request is allowed, zero when this one was
+    retry_after: Duration,
+}
+
+impl Bucket {
+    fn new(quota: &Quota, now: Instant) -> Bucket {
+        match quota.algorithm {
+            Algorithm::TokenBucket => Bucket::Tokens { tokens: quota.limit as f64, updated: now },
+            Algorithm::SlidingWindow => Bucket::Window { start: now, current: 0, previous: 0 },
+        }
+    }
+
+    fn check(&mut self, quota: &Quota, now: Instant) -> Decision {
+        let limit = quota.limit as f64;
+        let period = quota.period.as_secs_f64();
+        match self {
+            Bucket::Tokens{tokens, updated} => {
+                let rate = limit / period;
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(limit);
+                *updated = now;
+
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                Decision {
+                    allowed,
+                    remaining: tokens.floor() as u32,
+                    reset: Duration::from_secs_f64((limit - *tokens) / rate),
+                    retry_after: match allowed {
+                        true => Duration::ZERO,
+                        false => Duration::from_secs_f64((1.0 - *tokens) / rate),
+                    },
+                }
+            }
+            Bucket::Window{start, current, previous} => {
+                let elapsed = now.duration_since(*start);
+                if elapsed >= quota.period {
+                    // windows are aligned to the first request, a gap of more than one window forgets everything
+                    let windows = (elapsed.as_secs_f64() / period).floor();
+                    *previous = if windows < 2.0 { *current } else { 0 };
+                    *current = 0;
+                    *start += quota.period.mul_f64(windows);
+                }
+                let into_window = now.duration_since(*start).as_secs_f64();
+                let weight = 1.0 - into_window / period;
+                let estimate = |current: u32| *previous as f64 * weight + current as f64;
+
+                let allowed = estimate(*current) + 1.0 <= limit;
+                if allowed {
+                    *current += 1;
+                }
+                let until_window_end = period - into_window;
+                let retry_after = match allowed {
+                    true => 0.0,
+                    // the previous window's weight has to fade enough, or the next window has to start
+                    false if *previous > 0 && (*current as f64) + 1.0 <= limit => {
+                        let needed_weight = (limit - *current as f64 - 1.0) / *previous as f64;
+                        (period * (1.0 - needed_weight) - into_window).clamp(0.0, until_window_end)
+                    }
+                    false => until_window_end,
+                };
+                Decision {
+                    allowed,
+                    remaining: (limit - estimate(*current)).max(0.0).floor() as u32,
+                    reset: Duration::from_secs_f64(until_window_end + if *current > 0 { period } else { 0.0 }),
+                    retry_after: Duration::from_secs_f64(retry_after),
+                }
+            }
+        }
+    }
+
+    /// Whether the client is back to a full quota, so forgetting it changes nothing
+    fn is_idle(&self, quota: &Quota, now: Instant) -> bool {
+        match self {
+            Bucket::Tokens{updated, ..} => now.duration_since(*updated) >= quota.period,
+            Bucket::Window{start, ..} => now.duration_since(*start) >= quota.period * 2,
+        }
+    }
+}
+
+struct Inner {
+    key_by: KeyBy,
+    trusted_proxies: usize,
+    max_clients: usize,
+    default: Option<Quota>,
+    routes: HashMap<String, Quota>,
+    /// By route pattern (empty for the default quota) and client
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+    sweeps: Mutex<Sweeps>,
+}
+
+#[derive(Default)]
+struct Sweeps {
+    requests: u64,
+    // last sweep because the map was full, sweeping on every new client would hold the lock for long
+    full: Option<Instant>,
+}
+
+/// Quotas and counters of all clients, cheap to clone
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+impl RateLimiter {
+    /// Limits every route to `default` unless it has a quota of its own, `None` only limits routes
+    /// added with [RateLimiter::with_route]
+    pub fn new(key_by: KeyBy, default: Option<Quota>) -> RateLimiter {
+        RateLimiter { inner: Arc::new(Inner {
+            key_by,
+            trusted_proxies: 0,
+            max_clients: MAX_CLIENTS,
+            default,
+            routes: HashMap::new(),
+            buckets: Mutex::new(HashMap::new()),
+            sweeps: Mutex::default(),
+        }) }
+    }
+
+    /// Quotas of the `rate_limit` section of the configuration
+    pub fn from_config(config: &RateLimitConfig) -> Result<RateLimiter, String> {
+        let quota = |quota: &super::config::QuotaConfig| Quota {
+            algorithm: quota.algorithm,
+            limit: quota.limit,
+            period: Duration::from_secs(quota.period),
+        };
+        let mut limiter = RateLimiter::new(config.key.parse()?, config.default.as_ref().map(quota))
+            .with_trusted_proxies(config.trusted_proxies);
+        for (route, route_quota) in &config.routes {
+            limiter = limiter.with_route(route, quota(route_quota));
+        }
+        Ok(limiter)
+    }
+
+    /// Quota of the route with the given pattern
+    pub fn with_route(self, route: impl Into<String>, quota: Quota) -> RateLimiter {
+        self.configure(|inner| { inner.routes.insert(route.into(), quota); })
+    }
+
+    /// Takes the client IP from `X-Forwarded-For` behind that many proxies, each appending the
+    /// address it got the request from. Entries left of theirs are up to the client and ignored.
+    pub fn with_trusted_proxies(self, proxies: usize) -> RateLimiter {
+        self.configure(|inner| inner.trusted_proxies = proxies)
+    }
+
+    /// Clients tracked at most, [MAX_CLIENTS] by default
+    pub fn with_max_clients(self, max_clients: usize) -> RateLimiter {
+        self.configure(|inner| inner.max_clients = max_clients)
+    }
+
+    fn configure(mut self, configure: impl FnOnce(&mut Inner)) -> RateLimiter {
+        configure(Arc::get_mut(&mut self.inner).expect("rate limits are configured before they're shared"));
+        self
+    }
+
+    fn client_ip(&self, req: &Request) -> String {
+        let proxies = self.inner.trusted_proxies;
+        let forwarded = (proxies > 0).then(|| {
+            req.headers().get("x-forwarded-for")?.to_str().ok()?
+                .rsplit(',').nth(proxies - 1)?.trim().parse::<IpAddr>().ok()
+        }).flatten();
+        forwarded
+            .or_else(|| req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip()))
+            .map_or_else(|| "unknown".to_owned(), |ip| ip.to_string())
+    }
+
+    fn client(&self, req: &Request) -> String {
+        let key = match &self.inner.key_by {
+            KeyBy::ClientIp => None,
+            KeyBy::ApiKey => req.extensions().get::<Claims>().map(|claims| format!("sub:{}", claims.subject)),
+            KeyBy::Header(name) => req.headers().get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| format!("header:{value}")),
+        };
+        key.unwrap_or_else(|| format!("ip:{}", self.client_ip(req)))
+    }
+
+    /// Counts the request, `None` when no quota applies to its route
+    fn check(&self, req: &Request) -> Option<(Quota, Decision)> {
+        let route = req.extensions().get::<MatchedPath>().map(MatchedPath::as_str);
+        let (route, quota) = match route.and_then(|route| self.inner.routes.get_key_value(route)) {
+            Some((route, quota)) => (route.clone(), *quota),
+            None => (String::new(), self.inner.default?),
+        };
+
+        let now = Instant::now();
+        let client = self.client(req);
+        let mut buckets = self.inner.buckets.lock().unwrap();
+        let mut sweeps = self.inner.sweeps.lock().unwrap();
+        sweeps.requests += 1;
+        let mut key = (route, client);
+        let full = |buckets: &HashMap<_, _>| buckets.len() >= self.inner.max_clients && !buckets.contains_key(&key);
+        if sweeps.requests.is_multiple_of(SWEEP_INTERVAL) {
+            self.sweep(&mut buckets, now);
+        } else if full(&buckets) && sweeps.full.is_none_or(|last| now.duration_since(last) >= FULL_SWEEP_INTERVAL) {
+            sweeps.full = Some(now);
+            self.sweep(&mut buckets, now);
+        }
+        if full(&buckets) {
+            key.1 = OVERFLOW.to_owned();
+        }
+        let decision = buckets.entry(key)
+            .or_insert_with(|| Bucket::new(&quota, now))
+            .check(&quota, now);
+        Some((quota, decision))
+    }
+
+    fn sweep(&self, buckets: &mut HashMap<(String, String), Bucket>, now: Instant) {
+        let quotas = |route: &str| if route.is_empty() { self.inner.default } else { self.inner.routes.get(route).copied() };
+        buckets.retain(|(route, _), bucket| quotas(route).is_some_and(|quota| !bucket.is_idle(&quota, now)));
+    }
+}
+
+fn add_headers(headers: &mut HeaderMap, quota: &Quota, decision: &Decision) {
+    // RateLimit-Reset is a delta in seconds, rounded up so clients don't come back too early
+    let values = [
+        (LIMIT, quota.limit.to_string()),
+        (REMAINING, decision.remaining.to_string()),
+        (RESET, decision.reset.as_secs_f64().ceil().to_string()),
+        (POLICY, format!("{};w={}", quota.limit, quota.period.as_secs())),
+    ];
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Rate limits requests, see the [module](self) docs
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: RateLimiter) -> RateLimitLayer {
+        RateLimitLayer { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService { inner, limiter: self.limiter.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: RateLimiter,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let Some((quota, decision)) = self.limiter.check(&request) else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        if !decision.allowed {
+            let retry_after = decision.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let mut res = AppError::TooManyRequests { retry_after }.into_response();
+            add_headers(res.headers_mut(), &quota, &decision);
+            return Box::pin(async move { Ok(res) });
+        }
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut res = future.await?;
+            add_headers(res.headers_mut(), &quota, &decision);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::{header, StatusCode}, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn seconds(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let quota = Quota::token_bucket(2, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut bucket = Bucket::new(&quota, start);
+
+        assert!(bucket.check(&quota, start).allowed);
+        let second = bucket.check(&quota, start);
+        assert!(second.allowed);
+        assert_eq!((second.remaining, second.reset), (0, seconds(10.0)));
+
+        let denied = bucket.check(&quota, start + seconds(1.0));
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after.as_secs_f64().round(), 4.0);
+        assert!(bucket.check(&quota, start + seconds(5.0)).allowed, "a token refills every 5 seconds");
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let quota = Quota::sliding_window(4, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut bucket = Bucket::new(&quota, start);
+
+        for _ in 0..4 {
+            assert!(bucket.check(&quota, start).allowed);
+        }
+        let denied = bucket.check(&quota, start + seconds(5.0));
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, seconds(5.0), "waits for the next window");
+
+        // 2.5 seconds into the next window, 75% of the previous 4 requests still count
+        assert!(bucket.check(&quota, start + seconds(12.5)).allowed);
+        let denied = bucket.check(&quota, start + seconds(12.5));
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, seconds(2.5), "waits until only half of the previous window counts");
+        let later = bucket.check(&quota, start + seconds(17.5));
+        assert!(later.allowed);
+        assert_eq!(later.remaining, 1);
+
+        assert!(bucket.is_idle(&quota, start + seconds(30.0)));
+    }
+
+    #[test]
+    fn test_key_by() {
+        assert_eq!("ip".parse(), Ok(KeyBy::ClientIp));
+        assert_eq!("header:X-Client-Id".parse(), Ok(KeyBy::Header(HeaderName::from_static("x-client-id"))));
+        assert!("cookie".parse::<KeyBy>().is_err());
+        assert_eq!(KeyBy::Header(HeaderName::from_static("x-client-id")).to_string(), "header:x-client-id");
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let limiter = RateLimiter::new(KeyBy::Header(HeaderName::from_static("x-client")), Some(Quota::token_bucket(100, Duration::from_secs(60))))
+            .with_route("/video", Quota::sliding_window(1, Duration::from_secs(60)));
+        let app = Router::new()
+            .route("/video", get(|| async { "stream" }))
+            .route("/other", get(|| async { "other" }))
+            .layer(RateLimitLayer::new(limiter));
+
+        let send = |uri: &'static str, client: &'static str| {
+            let app = app.clone();
+            async move { app.oneshot(Request::get(uri).header("x-client", client).body(Body::empty()).unwrap()).await.unwrap() }
+        };
+
+        let res = send("/video", "a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[&LIMIT], "1");
+        assert_eq!(res.headers()[&REMAINING], "0");
+        assert_eq!(res.headers()[&POLICY], "1;w=60");
+
+        let res = send("/video", "a").await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "60");
+        assert_eq!(res.headers()[&REMAINING], "0");
+
+        assert_eq!(send("/video", "b").await.status(), StatusCode::OK, "clients are counted separately");
+        let res = send("/other", "a").await;
+        assert_eq!(res.status(), StatusCode::OK, "routes are counted separately");
+        assert_eq!(res.headers()[&REMAINING], "99");
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let limiter = RateLimiter::new(KeyBy::ClientIp, None);
+        let req = |forwarded_for: &str| {
+            let mut req = Request::get("/").header("x-forwarded-for", forwarded_for).body(Body::empty()).unwrap();
+            req.extensions_mut().insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            req
+        };
+
+        assert_eq!(limiter.client(&req("203.0.113.7")), "ip:10.0.0.1", "untrusted without proxies");
+        let limiter = limiter.with_trusted_proxies(1);
+        assert_eq!(limiter.client(&req("198.51.100.1, 203.0.113.7")), "ip:203.0.113.7", "the client picks the entries left of the proxy's");
+        let limiter = limiter.with_trusted_proxies(2);
+        assert_eq!(limiter.client(&req("198.51.100.1, 203.0.113.7, 10.0.0.2")), "ip:203.0.113.7");
+        assert_eq!(limiter.client(&req("10.0.0.2")), "ip:10.0.0.1", "fewer entries than proxies");
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let limiter = RateLimiter::new(KeyBy::Header(HeaderName::from_static("x-client")), Some(Quota::token_bucket(2, Duration::from_secs(60))))
+            .with_max_clients(2);
+        let check = |client: &str| {
+            let req = Request::get("/").header("x-client", client).body(Body::empty()).unwrap();
+            limiter.check(&req).unwrap().1.allowed
+        };
+
+        assert!(check("a"));
+        assert!(check("b"));
+        assert!(check("c"));
+        assert!(check("d"));
+        assert!(!check("e"), "new clients share the overflow quota");
+        assert!(check("a"), "known clients keep their own");
+        assert_eq!(limiter.inner.buckets.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_full_sweep_interval() {
+        let limiter = RateLimiter::new(KeyBy::Header(HeaderName::from_static("x-client")), Some(Quota::token_bucket(2, Duration::from_millis(20))))
+            .with_max_clients(2);
+        let check = |client: &str| {
+            let req = Request::get("/").header("x-client", client).body(Body::empty()).unwrap();
+            limiter.check(&req).unwrap().1.allowed
+        };
+        let clients = || limiter.inner.buckets.lock().unwrap().keys().map(|(_, client)| client.clone()).collect::<std::collections::BTreeSet<_>>();
+
+        check("a");
+        check("b");
+        check("c");
+        assert_eq!(clients(), ["header:a", "header:b", OVERFLOW].map(String::from).into());
+
+        // a and b went idle, but the map was swept just now
+        std::thread::sleep(Duration::from_millis(30));
+        check("d");
+        assert_eq!(clients(), ["header:a", "header:b", OVERFLOW].map(String::from).into());
+
+        limiter.inner.sweeps.lock().unwrap().full = Some(Instant::now() - FULL_SWEEP_INTERVAL);
+        check("e");
+        assert_eq!(clients(), ["header:e", OVERFLOW].map(String::from).into(), "d kept the overflow bucket busy");
+    }
+}
@@ -0,0 +1,784 @@
+//! Server side sessions identified by a cookie
+//!
+//! The [load_session] middleware reads the session ID from an encrypted (or signed) cookie, loads the
+//! session from a [SessionStore] and makes it available to handlers as the [Session] extractor.
+//! Once the response is ready, a modified session is saved and the cookie is sent along, an
+//! untouched one is only written again when half of its lifetime has passed. Anonymous sessions,
+//! holding nothing but a CSRF token for a form, get a shorter lifetime.
+//!
+//! - [MemoryStore] keeps sessions in memory, they are lost on restart
+//! - [FileStore] keeps one JSON file per session in a directory
+//!
+//! Sessions nobody comes back for are removed by [Sessions::spawn_sweep].
+//!
+//! Every session carries a CSRF token, see [Session::csrf_token] and the [verify_csrf] middleware.
+//!
+//! ```no_run
+//! # use webapp::server::session::{load_session, verify_csrf, MemoryStore, Sessions};
+//! # fn run(app: axum::Router) -> axum::Router {
+//! let sessions = Sessions::new(MemoryStore::default(), cookie::Key::generate());
+//! sessions.spawn_sweep();
+//! app.layer(axum::middleware::from_fn(verify_csrf))
+//!     .layer(axum::middleware::from_fn_with_state(sessions, load_session))
+//! # }
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRequest, FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Form,
+};
+use cookie::{Cookie, CookieJar, Key, SameSite};
+use futures_util::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use super::error::AppError;
+
+pub const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+const CSRF_KEY: &str = "_csrf";
+/// Largest form [verify_csrf] buffers to look for the token
+const CSRF_FORM_LIMIT: usize = 64 * 1024;
+/// How often [Sessions::spawn_sweep] removes expired sessions
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("session store failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("couldn't (de)serialize session: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl From<SessionError> for AppError {
+    fn from(err: SessionError) -> Self {
+        AppError::OopsError{err: err.to_string()}
+    }
+}
+
+/// Stored state of a session
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub values: BTreeMap<String, serde_json::Value>,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+impl Record {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= unix_now()
+    }
+}
+
+/// Storage of sessions, IDs are random and only contain ASCII letters and digits
+#[async_trait]
+pub trait SessionStore: Send + Sync + 'static {
+    async fn load(&self, id: &str) -> Result<Option<Record>, SessionError>;
+    async fn save(&self, id: &str, record: &Record) -> Result<(), SessionError>;
+    async fn delete(&self, id: &str) -> Result<(), SessionError>;
+
+    /// Removes expired sessions, returns how many
+    async fn sweep(&self) -> Result<usize, SessionError>;
+}
+
+/// Sessions in memory, expired ones are dropped when they're looked up or swept
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, Record>>,
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn load(&self, id: &str) -> Result<Option<Record>, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(id).is_some_and(Record::is_expired) {
+            sessions.remove(id);
+        }
+        Ok(sessions.get(id).cloned())
+    }
+
+    async fn save(&self, id: &str, record: &Record) -> Result<(), SessionError> {
+        self.sessions.lock().unwrap().insert(id.to_owned(), record.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), SessionError> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn sweep(&self) -> Result<usize, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, record| !record.is_expired());
+        Ok(before - sessions.len())
+    }
+}
+
+/// One JSON file per session, expired files are removed when they're looked up or swept
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Creates `dir` if it doesn't exist yet
+    pub fn new(dir: impl Into<PathBuf>) -> Result<FileStore, SessionError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // IDs come from cookies, nothing but the generated alphabet may reach the filesystem
+        let valid = !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric());
+        valid.then(|| self.dir.join(format!("{id}.json")))
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileStore {
+    async fn load(&self, id: &str) -> Result<Option<Record>, SessionError> {
+        let Some(path) = self.path(id) else { return Ok(None) };
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let record: Record = serde_json::from_slice(&data)?;
+        if record.is_expired() {
+            self.delete(id).await?;
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    async fn save(&self, id: &str, record: &Record) -> Result<(), SessionError> {
+        let Some(path) = self.path(id) else { return Ok(()) };
+        // written next to the target and renamed, readers never see a partial file
+        let staging = path.with_extension("json.tmp");
+        tokio::fs::write(&staging, serde_json::to_vec(record)?).await?;
+        tokio::fs::rename(&staging, &path).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), SessionError> {
+        let Some(path) = self.path(id) else { return Ok(()) };
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn sweep(&self) -> Result<usize, SessionError> {
+        let mut removed = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(id) = path.file_name().and_then(|name| name.to_str()?.strip_suffix(".json")) else { continue };
+            // another request may save or delete the session meanwhile, only drop what's still expired
+            let expired = match tokio::fs::read(&path).await {
+                Ok(data) => serde_json::from_slice::<Record>(&data).map_or(true, |record| record.is_expired()),
+                Err(_) => false,
+            };
+            if expired {
+                self.delete(id).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// How the session cookie is protected, both use the same [Key]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CookieProtection {
+    /// Signed, clients can read the session ID but not change it
+    Signed,
+    /// Encrypted and authenticated
+    #[default]
+    Encrypted,
+}
+
+struct SessionsInner {
+    store: Box<dyn SessionStore>,
+    key: Key,
+    protection: CookieProtection,
+    cookie_name: String,
+    ttl: Duration,
+    anonymous_ttl: Duration,
+    secure: bool,
+}
+
+/// Session configuration and store, cheap to clone
+#[derive(Clone)]
+pub struct Sessions {
+    inner: Arc<SessionsInner>,
+}
+
+impl Sessions {
+    /// Sessions kept for a day in a `session` cookie, anonymous ones for an hour
+    pub fn new(store: impl SessionStore, key: Key) -> Sessions {
+        Sessions { inner: Arc::new(SessionsInner {
+            store: Box::new(store),
+            key,
+            protection: CookieProtection::default(),
+            cookie_name: "session".into(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            anonymous_ttl: Duration::from_secs(60 * 60),
+            secure: false,
+        }) }
+    }
+
+    pub fn with_ttl(self, ttl: Duration) -> Sessions {
+        self.configure(|inner| inner.ttl = ttl)
+    }
+
+    /// Lifetime of sessions holding nothing but a CSRF token, capped at the regular one
+    pub fn with_anonymous_ttl(self, ttl: Duration) -> Sessions {
+        self.configure(|inner| inner.anonymous_ttl = ttl)
+    }
+
+    pub fn with_cookie_name(self, name: impl Into<String>) -> Sessions {
+        self.configure(|inner| inner.cookie_name = name.into())
+    }
+
+    pub fn with_protection(self, protection: CookieProtection) -> Sessions {
+        self.configure(|inner| inner.protection = protection)
+    }
+
+    /// Restricts the cookie to HTTPS
+    pub fn with_secure(self, secure: bool) -> Sessions {
+        self.configure(|inner| inner.secure = secure)
+    }
+
+    fn configure(mut self, configure: impl FnOnce(&mut SessionsInner)) -> Sessions {
+        configure(Arc::get_mut(&mut self.inner).expect("sessions are configured before they're shared"));
+        self
+    }
+
+    /// Removes expired sessions from the store every few minutes, until the returned task is
+    /// aborted
+    ///
+    /// # Panics
+    ///
+    /// Outside of a Tokio runtime
+    pub fn spawn_sweep(&self) -> tokio::task::JoinHandle<()> {
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(err) = sessions.inner.store.sweep().await {
+                    tracing::warn!("couldn't sweep sessions: {err}");
+                }
+            }
+        })
+    }
+
+    /// Lifetime of `record` in seconds
+    fn ttl(&self, record: &Record) -> u64 {
+        let anonymous = record.values.keys().all(|key| key == CSRF_KEY);
+        match anonymous {
+            true => self.inner.anonymous_ttl.min(self.inner.ttl).as_secs(),
+            false => self.inner.ttl.as_secs(),
+        }
+    }
+
+    /// Session ID from the cookie, if it carries a valid one
+    fn session_id(&self, headers: &HeaderMap) -> Option<String> {
+        let mut jar = CookieJar::new();
+        headers.get_all(header::COOKIE).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(Cookie::split_parse)
+            .flatten()
+            .filter(|cookie| cookie.name() == self.inner.cookie_name)
+            .for_each(|cookie| jar.add_original(cookie.into_owned()));
+
+        let cookie = match self.inner.protection {
+            CookieProtection::Signed => jar.signed(&self.inner.key).get(&self.inner.cookie_name),
+            CookieProtection::Encrypted => jar.private(&self.inner.key).get(&self.inner.cookie_name),
+        };
+        cookie.map(|cookie| cookie.value().to_owned())
+    }
+
+    fn cookie(&self, id: Option<&str>, ttl: u64) -> Option<HeaderValue> {
+        let mut cookie = Cookie::build((self.inner.cookie_name.clone(), id.unwrap_or_default().to_owned()))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(self.inner.secure)
+            .max_age(cookie::time::Duration::seconds(ttl as i64))
+            .build();
+        if id.is_none() {
+            cookie.make_removal();
+            return HeaderValue::from_str(&cookie.to_string()).ok();
+        }
+
+        let mut jar = CookieJar::new();
+        match self.inner.protection {
+            CookieProtection::Signed => jar.signed_mut(&self.inner.key).add(cookie),
+            CookieProtection::Encrypted => jar.private_mut(&self.inner.key).add(cookie),
+        }
+        jar.get(&self.inner.cookie_name).and_then(|cookie| HeaderValue::from_str(&cookie.to_string()).ok())
+    }
+
+    async fn load(&self, headers: &HeaderMap) -> Result<Session, SessionError> {
+        if let Some(id) = self.session_id(headers) {
+            if let Some(record) = self.inner.store.load(&id).await? {
+                return Ok(Session::new(Some(id), record));
+            }
+        }
+        Ok(Session::new(None, Record::default()))
+    }
+
+    /// Persists what the request did to the session, returns the cookie to send along
+    async fn finish(&self, session: &Session) -> Result<Option<HeaderValue>, SessionError> {
+        let (id, record, status, changed) = {
+            let state = session.state.lock().unwrap();
+            (state.id.clone(), state.record.clone(), state.status, state.changed)
+        };
+        let ttl = self.ttl(&record);
+
+        match status {
+            Status::Destroyed => {
+                let Some(id) = id else { return Ok(None) };
+                self.inner.store.delete(&id).await?;
+                Ok(self.cookie(None, 0))
+            }
+            Status::Active if !changed => {
+                // rolling expiry, written again once half of the lifetime passed
+                let Some(id) = id else { return Ok(None) };
+                if record.expires_at.saturating_sub(unix_now()) > ttl / 2 {
+                    return Ok(None);
+                }
+                let record = Record { expires_at: unix_now() + ttl, ..record };
+                self.inner.store.save(&id, &record).await?;
+                Ok(self.cookie(Some(&id), ttl))
+            }
+            Status::Active | Status::Regenerated => {
+                if let (Status::Regenerated, Some(old)) = (status, &id) {
+                    self.inner.store.delete(old).await?;
+                }
+                let id = match (status, id) {
+                    (Status::Active, Some(id)) => id,
+                    _ => new_id(),
+                };
+                let record = Record { expires_at: unix_now() + ttl, ..record };
+                self.inner.store.save(&id, &record).await?;
+                Ok(self.cookie(Some(&id), ttl))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Active,
+    /// Gets a new ID when saved, the old one is deleted
+    Regenerated,
+    Destroyed,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    id: Option<String>,
+    record: Record,
+    status: Status,
+    changed: bool,
+}
+
+/// Session of the current request, changes are saved once the response is ready
+#[derive(Debug, Clone)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    fn new(id: Option<String>, record: Record) -> Session {
+        let state = SessionState { id, record, status: Status::Active, changed: false };
+        Session { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Whether the session was created by this request, so the client didn't send a session cookie
+    pub fn is_new(&self) -> bool {
+        self.state.lock().unwrap().id.is_none()
+    }
+
+    /// Value stored under `key`, `None` if it's missing or doesn't deserialize to `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let state = self.state.lock().unwrap();
+        state.record.values.get(key).and_then(|value| T::deserialize(value).ok())
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<(), SessionError> {
+        let value = serde_json::to_value(value)?;
+        let mut state = self.state.lock().unwrap();
+        state.record.values.insert(key.to_owned(), value);
+        state.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.changed |= state.record.values.remove(key).is_some();
+    }
+
+    /// Moves the session to a new ID and CSRF token, call it when privileges change (login)
+    /// so an ID planted before can't be used to ride the session
+    pub fn regenerate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.record.values.remove(CSRF_KEY);
+        state.status = Status::Regenerated;
+    }
+
+    /// Deletes the session and the cookie
+    pub fn destroy(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.record = Record::default();
+        state.status = Status::Destroyed;
+    }
+
+    /// CSRF token of the session, created on first use. Forms send it in the `csrf_token` field,
+    /// scripts in the `X-CSRF-Token` header.
+    pub fn csrf_token(&self) -> String {
+        if let Some(token) = self.get::<String>(CSRF_KEY) {
+            return token;
+        }
+        let token = new_id();
+        let mut state = self.state.lock().unwrap();
+        state.record.values.insert(CSRF_KEY.to_owned(), token.clone().into());
+        state.changed = true;
+        token
+    }
+
+    /// Compares `token` with the CSRF token of the session in constant time
+    pub fn verify_csrf(&self, token: &str) -> bool {
+        let Some(expected) = self.get::<String>(CSRF_KEY) else { return false };
+        expected.len() == token.len()
+            && expected.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Session {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Session>().cloned()
+            .ok_or_else(|| AppError::OopsError{err: "Session extracted without the load_session middleware".into()})
+    }
+}
+
+/// Loads the session of the request and saves it after the handler ran
+pub async fn load_session(State(sessions): State<Sessions>, mut req: Request, next: Next) -> Response {
+    let session = match sessions.load(req.headers()).await {
+        Ok(session) => session,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    req.extensions_mut().insert(session.clone());
+
+    let mut res = next.run(req).await;
+    match sessions.finish(&session).await {
+        Ok(Some(cookie)) => { res.headers_mut().append(header::SET_COOKIE, cookie); }
+        Ok(None) => {}
+        Err(err) => return AppError::from(err).into_response(),
+    }
+    res
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: String,
+}
+
+/// Rejects state changing requests of an existing session that don't carry its CSRF token, either
+/// in the `X-CSRF-Token` header or the `csrf_token` field of a form. In a `multipart/form-data` form
+/// it has to be the first field, so an upload following it is passed on without being buffered.
+///
+/// Requests without a session cookie pass, they can't ride on anyone's session. Runs inside
+/// [load_session].
+pub async fn verify_csrf(req: Request, next: Next) -> Result<Response, AppError> {
+    if req.method().is_safe() {
+        return Ok(next.run(req).await);
+    }
+    let Some(session) = req.extensions().get::<Session>().cloned() else {
+        return Err(AppError::OopsError{err: "verify_csrf runs without the load_session middleware".into()});
+    };
+    if session.is_new() {
+        return Ok(next.run(req).await);
+    }
+
+    if let Some(token) = req.headers().get(CSRF_HEADER) {
+        return match session.verify_csrf(token.to_str().unwrap_or_default()) {
+            true => Ok(next.run(req).await),
+            false => Err(AppError::CsrfTokenMismatch),
+        };
+    }
+
+    let content_type = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if let Some(boundary) = multipart_boundary(content_type) {
+        let (parts, body) = req.into_parts();
+        let (token, body) = multipart_csrf_token(body, &boundary).await?;
+        return match token.is_some_and(|token| session.verify_csrf(&token)) {
+            true => Ok(next.run(Request::from_parts(parts, body)).await),
+            false => Err(AppError::CsrfTokenMismatch),
+        };
+    }
+    if !content_type.starts_with("application/x-www-form-urlencoded") {
+        return Err(AppError::CsrfTokenMismatch);
+    }
+
+    // the handler reads the form as well, so the body is buffered and put back
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, CSRF_FORM_LIMIT).await
+        .map_err(|_| AppError::PayloadTooLarge { limit: CSRF_FORM_LIMIT as u64 })?;
+    let form_req = Request::from_parts(parts.clone(), Body::from(body.clone()));
+    let token = Form::<CsrfForm>::from_request(form_req, &()).await.ok().map(|Form(form)| form.csrf_token);
+
+    match token.is_some_and(|token| session.verify_csrf(&token)) {
+        true => Ok(next.run(Request::from_parts(parts, Body::from(body))).await),
+        false => Err(AppError::CsrfTokenMismatch),
+    }
+}
+
+/// Boundary parameter of a `multipart/form-data` content type
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_owned())
+}
+
+/// Reads the body up to the end of its first part and returns that part's value if it's the
+/// `csrf_token` field, along with a body replaying what was read followed by the rest
+async fn multipart_csrf_token(body: Body, boundary: &str) -> Result<(Option<String>, Body), AppError> {
+    let first = format!("--{boundary}\r\n");
+    let next = format!("\r\n--{boundary}");
+    let mut stream = body.into_data_stream();
+    let mut read = Vec::new();
+
+    let token = loop {
+        if let Some(value) = read.strip_prefix(first.as_bytes()) {
+            if let Some(headers_end) = find(value, b"\r\n\r\n") {
+                let (headers, value) = (&value[..headers_end], &value[headers_end + 4..]);
+                if let Some(value_end) = find(value, next.as_bytes()) {
+                    let is_token = String::from_utf8_lossy(headers).lines().any(|line| {
+                        let Some((name, value)) = line.split_once(':') else { return false };
+                        name.trim().eq_ignore_ascii_case("content-disposition")
+                            && value.split(';').any(|param| param.trim() == "name=\"csrf_token\"")
+                    });
+                    break is_token.then(|| String::from_utf8_lossy(&value[..value_end]).into_owned());
+                }
+            }
+        } else if read.len() >= first.len() {
+            break None;
+        }
+        if read.len() > CSRF_FORM_LIMIT {
+            break None;
+        }
+        match stream.next().await {
+            Some(chunk) => read.extend_from_slice(&chunk.map_err(|_| AppError::RequestPayload)?),
+            None => break None,
+        }
+    };
+
+    let read = futures_util::stream::once(async move { Ok::<_, axum::Error>(Bytes::from(read)) });
+    Ok((token, Body::from_stream(read.chain(stream))))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::{Method, StatusCode}, middleware, routing::{get, post}, Router};
+    use tower::ServiceExt;
+
+    fn app(sessions: Sessions) -> Router {
+        Router::new()
+            .route("/count", get(|session: Session| async move {
+                let count = session.get::<u32>("count").unwrap_or(0) + 1;
+                session.insert("count", count).unwrap();
+                count.to_string()
+            }))
+            .route("/token", get(|session: Session| async move { session.csrf_token() }))
+            .route("/login", post(|session: Session| async move { session.regenerate(); "ok" }))
+            .route("/logout", post(|session: Session| async move { session.destroy(); "bye" }))
+            .route("/upload", post(|mut multipart: axum::extract::Multipart| async move {
+                let mut fields = Vec::new();
+                while let Some(field) = multipart.next_field().await.unwrap() {
+                    fields.push(format!("{}={}", field.name().unwrap().to_owned(), field.text().await.unwrap()));
+                }
+                fields.join("&")
+            }))
+            .layer(middleware::from_fn(verify_csrf))
+            .layer(middleware::from_fn_with_state(sessions, load_session))
+    }
+
+    async fn send(app: &Router, method: Method, uri: &str, cookie: Option<&str>, csrf: Option<&str>) -> (StatusCode, Option<String>, String) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        let body = match csrf {
+            Some(token) => {
+                req = req.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+                Body::from(format!("csrf_token={token}"))
+            }
+            None => Body::empty(),
+        };
+
+        let res = app.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+        let status = res.status();
+        let cookie = res.headers().get(header::SET_COOKIE)
+            .map(|value| value.to_str().unwrap().split(';').next().unwrap().to_owned());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, cookie, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_session_roundtrip() {
+        for protection in [CookieProtection::Encrypted, CookieProtection::Signed] {
+            let app = app(Sessions::new(MemoryStore::default(), Key::generate()).with_protection(protection));
+
+            let (_, cookie, body) = send(&app, Method::GET, "/count", None, None).await;
+            assert_eq!(body, "1");
+            let cookie = cookie.expect("new sessions set a cookie");
+            let (_, _, body) = send(&app, Method::GET, "/count", Some(&cookie), None).await;
+            assert_eq!(body, "2");
+
+            // a cookie of another key is ignored
+            let (_, tampered, _) = send(&app, Method::GET, "/count", None, None).await;
+            let forged = format!("{}x", tampered.unwrap());
+            let (_, _, body) = send(&app, Method::GET, "/count", Some(&forged), None).await;
+            assert_eq!(body, "1");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_csrf_and_regenerate() {
+        let app = app(Sessions::new(MemoryStore::default(), Key::generate()));
+        let (_, cookie, token) = send(&app, Method::GET, "/token", None, None).await;
+        let cookie = cookie.unwrap();
+
+        let (status, _, _) = send(&app, Method::POST, "/login", Some(&cookie), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) = send(&app, Method::POST, "/login", Some(&cookie), Some("wrong")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, regenerated, _) = send(&app, Method::POST, "/login", Some(&cookie), Some(&token)).await;
+        assert_eq!(status, StatusCode::OK);
+        let regenerated = regenerated.unwrap();
+        assert_ne!(regenerated, cookie);
+
+        // the old ID is gone
+        let (_, _, body) = send(&app, Method::GET, "/count", Some(&cookie), None).await;
+        assert_eq!(body, "1");
+
+        let (_, _, token) = send(&app, Method::GET, "/token", Some(&regenerated), None).await;
+        let (status, removal, _) = send(&app, Method::POST, "/logout", Some(&regenerated), Some(&token)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(removal.as_deref(), Some("session="));
+    }
+
+    #[tokio::test]
+    async fn test_multipart_csrf() {
+        let app = app(Sessions::new(MemoryStore::default(), Key::generate()));
+        let (_, cookie, token) = send(&app, Method::GET, "/token", None, None).await;
+        let cookie = cookie.unwrap();
+
+        let upload = |fields: Vec<(&str, String)>| {
+            let body: String = fields.iter()
+                .map(|(name, value)| format!("--x\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"))
+                .chain(["--x--\r\n".to_owned()])
+                .collect();
+            let req = Request::post("/upload")
+                .header(header::COOKIE, &cookie)
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=\"x\"")
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap() }
+        };
+
+        let res = upload(vec![("csrf_token", token.clone()), ("file", "data".repeat(50_000))]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, format!("csrf_token={token}&file={}", "data".repeat(50_000)), "the handler gets the whole form");
+
+        assert_eq!(upload(vec![("csrf_token", "wrong".into()), ("file", "data".into())]).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(upload(vec![("file", "data".into()), ("csrf_token", token)]).await.status(), StatusCode::FORBIDDEN, "the token has to come first");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_ttl_and_sweep() {
+        let sessions = Sessions::new(MemoryStore::default(), Key::generate());
+        let app = app(sessions.clone());
+        let max_age = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+                let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap().to_owned();
+                cookie.split("; ").find_map(|attr| attr.strip_prefix("Max-Age=")).unwrap().to_owned()
+            }
+        };
+        assert_eq!(max_age("/token").await, "3600");
+        assert_eq!(max_age("/count").await, "86400");
+
+        let expired = Record { values: BTreeMap::new(), expires_at: unix_now() - 1 };
+        let store = &sessions.inner.store;
+        store.save("old", &expired).await.unwrap();
+        assert_eq!(store.sweep().await.unwrap(), 1, "only the expired session is swept");
+        assert_eq!(store.load("old").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().join("sessions")).unwrap();
+        let record = Record { values: BTreeMap::from([("user".into(), "alice".into())]), expires_at: unix_now() + 60 };
+
+        store.save("abc", &record).await.unwrap();
+        assert_eq!(store.load("abc").await.unwrap(), Some(record.clone()));
+        assert_eq!(store.load("../abc").await.unwrap(), None);
+
+        store.save("old", &Record { expires_at: unix_now() - 1, ..record.clone() }).await.unwrap();
+        assert_eq!(store.load("old").await.unwrap(), None);
+        assert!(!dir.path().join("sessions/old.json").exists());
+
+        store.save("old", &Record { expires_at: unix_now() - 1, ..record }).await.unwrap();
+        std::fs::write(dir.path().join("sessions/broken.json"), "{").unwrap();
+        assert_eq!(store.sweep().await.unwrap(), 2);
+        assert!(dir.path().join("sessions/abc.json").exists());
+
+        store.delete("abc").await.unwrap();
+        assert_eq!(store.load("abc").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Structured logging and request tracing.
+//!
+//! [init] installs the global subscriber described by the `[log]` section: human readable text
+//! or one JSON object per line, and with the `otlp` feature an OpenTelemetry exporter sending
+//! spans to a collector. The [trace_requests] middleware runs every request in a `request` span
+//! carrying its ID; the span closes with the response status and the latency.
+//!
+//! ```ignore
+//! let telemetry = telemetry::init(&config.log)?;
+//! let app = Router::new()
+//!     .route("/", get(handler))
+//!     .layer(middleware::from_fn(trace_requests));
+//! // ...
+//! telemetry.shutdown();
+//! ```
+
+use std::{io, time::Instant};
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use thiserror::Error;
+use tracing::{field::Empty, Instrument, Subscriber};
+use tracing_subscriber::{
+    filter::ParseError,
+    fmt::{format::FmtSpan, MakeWriter},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::{SubscriberInitExt, TryInitError},
+    EnvFilter, Layer,
+};
+use super::{config::{LogConfig, LogFormat}, error::CorrelationId};
+
+/// Header carrying the request ID, taken from the request or generated, and echoed in the response
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("invalid log level: {0}")]
+    Filter(#[from] ParseError),
+
+    #[error("couldn't install the subscriber: {0}")]
+    Init(#[from] TryInitError),
+
+    #[cfg(feature = "otlp")]
+    #[error("couldn't set up the OTLP exporter: {0}")]
+    Exporter(#[from] opentelemetry_otlp::ExporterBuildError),
+
+    #[cfg(not(feature = "otlp"))]
+    #[error("OTLP export needs the otlp feature")]
+    OtlpDisabled,
+}
+
+/// Handle of the installed subscriber, [Telemetry::shutdown] flushes spans not exported yet
+#[must_use = "dropping the handle loses spans not exported yet"]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                tracing::warn!("couldn't flush spans: {err}");
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber, logging to stderr
+pub fn init(config: &LogConfig) -> Result<Telemetry, TelemetryError> {
+    // the exporter's own HTTP/2 client would otherwise trace itself
+    let filter = EnvFilter::try_new(format!("{},h2=warn,hyper=warn,tonic=warn,tower=warn", config.level))?;
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer(config.format, io::stderr));
+
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_otlp::WithExportConfig;
+
+        let provider = match &config.otlp_endpoint {
+            Some(endpoint) => {
+                let exporter = opentelemetry_otlp::SpanExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(endpoint)
+                    .build()?;
+                Some(opentelemetry_sdk::trace::SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(opentelemetry_sdk::Resource::builder().with_service_name(env!("CARGO_PKG_NAME")).build())
+                    .build())
+            }
+            None => None,
+        };
+        let otel = provider.as_ref().map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))));
+        registry.with(otel).try_init()?;
+        Ok(Telemetry { provider })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        if config.otlp_endpoint.is_some() {
+            return Err(TelemetryError::OtlpDisabled);
+        }
+        registry.try_init()?;
+        Ok(Telemetry {})
+    }
+}
+
+/// Formats events and the close of spans, which carries their fields and timings
+fn fmt_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'s> LookupSpan<'s>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_span_events(FmtSpan::CLOSE);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// Runs the request in a span carrying its ID, route and method, and records the status and
+/// latency before the span closes. The ID is the [CorrelationId] of problem responses as well.
+pub async fn trace_requests(mut req: Request, next: Next) -> Response {
+    let request_id = CorrelationId::from_headers(req.headers());
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id.0,
+        method = %req.method(),
+        path = req.uri().path(),
+        route = Empty,
+        status = Empty,
+        latency_ms = Empty,
+    );
+    if let Some(route) = req.extensions().get::<MatchedPath>() {
+        span.record("route", route.as_str());
+    }
+    req.extensions_mut().insert(request_id.clone());
+
+    let start = Instant::now();
+    let mut res = next.run(req).instrument(span.clone()).await;
+    span.record("status", res.status().as_u16());
+    span.record("latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+
+    if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+        res.headers_mut().insert(REQUEST_ID, value);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_requests() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let _guard = tracing_subscriber::registry()
+            .with(fmt_layer(LogFormat::Json, move || writer.clone()))
+            .set_default();
+
+        let app = Router::new()
+            .route("/items/:id", get(|Extension(id): Extension<CorrelationId>| async move {
+                tracing::info!("handling");
+                id.0
+            }))
+            .layer(middleware::from_fn(trace_requests));
+
+        let res = app.clone().oneshot(Request::get("/items/1").header("x-request-id", "req-1").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[REQUEST_ID], "req-1");
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        assert_eq!(body, "req-1");
+
+        let res = app.oneshot(Request::get("/items").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let generated = res.headers()[REQUEST_ID].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+
+        let event = &lines[0];
+        assert_eq!(event["fields"]["message"], "handling");
+        assert_eq!(event["span"]["request_id"], "req-1");
+
+        let closed: Vec<_> = lines.iter().filter(|line| line["fields"]["message"] == "close").collect();
+        assert_eq!(closed.len(), 2);
+        let span = &closed[0]["span"];
+        assert_eq!(span["route"], "/items/:id");
+        assert_eq!(span["method"], "GET");
+        assert_eq!(span["status"], 200);
+        assert!(span["latency_ms"].as_f64().is_some());
+        assert_eq!(closed[1]["span"]["status"], 404);
+        assert_eq!(closed[1]["span"]["request_id"], generated);
+    }
+}
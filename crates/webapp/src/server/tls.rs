@@ -0,0 +1,380 @@
+//! TLS termination with rustls
+//!
+//! [TlsServer] serves a router over HTTPS, HTTP/1.1 and, unless disabled, HTTP/2 negotiated
+//! through ALPN. Certificates are loaded from PEM files and reloaded when they change on disk, so a
+//! renewed certificate is picked up by new connections without a restart. A renewal that fails to
+//! load keeps the previous certificate in use.
+//!
+//! [redirect_to_https] answers plain HTTP with a permanent redirect to the HTTPS listener.
+use std::{
+    fs::File,
+    future::Future,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, uri::Authority, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use thiserror::Error;
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_rustls::TlsAcceptor;
+use tower::Layer;
+use super::lifecycle::Lifecycle;
+
+/// Connections that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after the listener failed to accept, as axum::serve does
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("couldn't read {path}: {source}")]
+    Io{path: PathBuf, source: std::io::Error},
+
+    #[error("{path} contains no {kind}")]
+    Missing{path: PathBuf, kind: &'static str},
+
+    #[error("unusable private key {path}: {source}")]
+    Key{path: PathBuf, source: rustls::Error},
+
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+
+    #[error("couldn't watch certificate files: {0}")]
+    Watch(#[from] notify::Error),
+}
+
+/// Certificate currently served, replaced when the files change
+#[derive(Debug)]
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Result<CertResolver, TlsError> {
+        let (cert, key) = (cert.into(), key.into());
+        let current = RwLock::new(Arc::new(load_certified_key(&cert, &key)?));
+        Ok(CertResolver { cert, key, current })
+    }
+
+    /// Reloads the certificate and key, the current ones stay in use on failure
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certified = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(certified);
+        Ok(())
+    }
+
+    /// Reloads whenever the certificate or key file changes. Their directories are watched,
+    /// as renewals usually replace the files instead of writing to them.
+    pub fn watch(self: &Arc<Self>) -> Result<RecommendedWatcher, TlsError> {
+        let resolver = Arc::downgrade(self);
+        let files = [self.cert.clone(), self.key.clone()];
+        let file_names: Vec<_> = files.iter().filter_map(|path| path.file_name().map(ToOwned::to_owned)).collect();
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else { return };
+            let relevant = !event.kind.is_access() && event.paths.iter()
+                .any(|path| path.file_name().is_some_and(|name| file_names.iter().any(|f| f == name)));
+            if let (true, Some(resolver)) = (relevant, resolver.upgrade()) {
+                // a renewal writes the certificate and key one after the other, the pair only
+                // matches once both are written, so failures before that are expected
+                if let Err(err) = resolver.reload() {
+                    tracing::warn!("keeping the current certificate, reload failed: {err}");
+                }
+            }
+        })?;
+
+        for file in &files {
+            let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        Ok(watcher)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let open = |path: &Path| File::open(path).map(BufReader::new).map_err(|source| TlsError::Io { path: path.to_owned(), source });
+
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|source| TlsError::Io { path: cert.to_owned(), source })?;
+    if certs.is_empty() {
+        return Err(TlsError::Missing { path: cert.to_owned(), kind: "certificate" });
+    }
+
+    let private_key = rustls_pemfile::private_key(&mut open(key)?)
+        .map_err(|source| TlsError::Io { path: key.to_owned(), source })?
+        .ok_or_else(|| TlsError::Missing { path: key.to_owned(), kind: "private key" })?;
+    let signing_key = ring::sign::any_supported_type(&private_key)
+        .map_err(|source| TlsError::Key { path: key.to_owned(), source })?;
+
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified.keys_match().map_err(|source| TlsError::Key { path: key.to_owned(), source })?;
+    Ok(certified)
+}
+
+/// HTTPS server
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+    http2: bool,
+    resolver: Arc<CertResolver>,
+    // kept alive for as long as the server runs
+    _watcher: RecommendedWatcher,
+}
+
+impl TlsServer {
+    /// Loads the certificate and key, and watches them for changes
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>, http2: bool) -> Result<TlsServer, TlsError> {
+        let resolver = Arc::new(CertResolver::load(cert, key)?);
+        let watcher = resolver.watch()?;
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = match http2 {
+            true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            false => vec![b"http/1.1".to_vec()],
+        };
+
+        Ok(TlsServer {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            http2,
+            resolver,
+            _watcher: watcher,
+        })
+    }
+
+    pub fn resolver(&self) -> &Arc<CertResolver> {
+        &self.resolver
+    }
+
+    /// Serves `app` until `signal` completes or shutdown began otherwise, then drains connections
+    /// for at most the drain timeout of `lifecycle`
+    pub async fn serve<F>(&self, listener: TcpListener, app: Router, lifecycle: &Lifecycle, signal: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        if !self.http2 {
+            builder = builder.http1_only();
+        }
+        // every connection task holds a sender, the channel closes once all of them finished
+        let (open, mut closed) = mpsc::channel::<()>(1);
+
+        let accept = async {
+            let stop = lifecycle.shutdown_requested();
+            tokio::pin!(stop);
+            loop {
+                let (tcp, remote) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        // the client gave up before it was accepted, nothing wrong with the listener
+                        Err(err) if is_connection_error(&err) => continue,
+                        Err(err) => {
+                            // e.g. out of file descriptors, retrying right away would spin
+                            tracing::warn!("couldn't accept connection: {err}");
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    },
+                    _ = &mut stop => break,
+                };
+
+                let acceptor = self.acceptor.clone();
+                let builder = builder.clone();
+                // what axum::serve with connect info provides, for the rate limiter
+                let service = TowerToHyperService::new(Extension(ConnectInfo(remote)).layer(app.clone()));
+                let stop = lifecycle.shutdown_requested();
+                let open = open.clone();
+
+                tokio::spawn(async move {
+                    let _open = open;
+                    let Ok(Ok(stream)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await else { return };
+
+                    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                    tokio::pin!(conn);
+                    tokio::select! {
+                        _ = conn.as_mut() => return,
+                        _ = stop => conn.as_mut().graceful_shutdown(),
+                    }
+                    let _ = conn.await;
+                });
+            }
+            drop(listener);
+            drop(open);
+            closed.recv().await;
+            Ok(())
+        };
+
+        lifecycle.drain(accept, signal).await
+    }
+}
+
+/// Errors of a single connection rather than the listener
+fn is_connection_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Redirects every request to the same path on `https_port` (`Router::new().fallback(...)`)
+pub async fn redirect_to_https(https_port: u16, req: Request) -> Response {
+    let host = req.headers().get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "missing Host header").into_response();
+    };
+
+    let authority = match https_port {
+        443 => host.host().to_owned(),
+        port => format!("{}:{port}", host.host()),
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    match Uri::builder().scheme("https").authority(authority).path_and_query(path).build() {
+        Ok(uri) => Redirect::permanent(&uri.to_string()).into_response(),
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+/// Router answering plain HTTP requests with redirects to `https_addr`
+pub fn redirect_router(https_addr: SocketAddr) -> Router {
+    let port = https_addr.port();
+    Router::new().fallback(move |req: Request| redirect_to_https(port, req))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get};
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tower::ServiceExt;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+    fn connector(alpn: &[&[u8]]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        let mut ca = BufReader::new(File::open(format!("{FIXTURES}/ca.pem")).unwrap());
+        roots.add_parsable_certificates(rustls_pemfile::certs(&mut ca).map(Result::unwrap));
+
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{FIXTURES}/{name}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert, fixture("cert1.pem")).unwrap();
+        std::fs::write(&key, fixture("key1.pem")).unwrap();
+
+        let server = TlsServer::new(&cert, &key, true).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let lifecycle = Lifecycle::new(Duration::from_secs(1));
+        let app = Router::new().route("/", get(|| async { "secure" }));
+
+        let client = {
+            let lifecycle = lifecycle.clone();
+            async move {
+                let connect = |alpn: &'static [&'static [u8]]| async move {
+                    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+                    connector(alpn).connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap()
+                };
+
+                let h2 = connect(&[b"h2", b"http/1.1"]).await;
+                assert_eq!(h2.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+                let mut http1 = connect(&[b"http/1.1"]).await;
+                let served = http1.get_ref().1.peer_certificates().unwrap()[0].clone();
+                http1.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n").await.unwrap();
+                let mut response = String::new();
+                http1.read_to_string(&mut response).await.unwrap();
+                assert!(response.starts_with("HTTP/1.1 200 OK"));
+                assert!(response.ends_with("secure"));
+
+                // a renewed certificate is served to new connections
+                std::fs::write(&key, fixture("key2.pem")).unwrap();
+                std::fs::write(&cert, fixture("cert2.pem")).unwrap();
+                let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+                loop {
+                    let conn = connect(&[b"http/1.1"]).await;
+                    if conn.get_ref().1.peer_certificates().unwrap()[0] != served {
+                        break;
+                    }
+                    assert!(tokio::time::Instant::now() < deadline, "certificate was not reloaded");
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+
+                lifecycle.begin_shutdown();
+                drop(h2);
+            }
+        };
+
+        let (served, ()) = tokio::join!(server.serve(listener, app, &lifecycle, std::future::pending()), client);
+        served.unwrap();
+    }
+
+    #[test]
+    fn test_mismatched_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert, fixture("cert1.pem")).unwrap();
+        std::fs::write(&key, fixture("key1.pem")).unwrap();
+        let resolver = CertResolver::load(&cert, &key).unwrap();
+
+        std::fs::write(&key, fixture("key2.pem")).unwrap();
+        assert!(matches!(resolver.reload(), Err(TlsError::Key { .. })));
+        assert!(matches!(CertResolver::load(&cert, dir.path().join("missing.pem")), Err(TlsError::Io { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_redirect() {
+        let app = redirect_router("0.0.0.0:8443".parse().unwrap());
+        let res = app.clone().oneshot(Request::get("/media/a.mp4?t=1").header("host", "example.com:8080").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[header::LOCATION], "https://example.com:8443/media/a.mp4?t=1");
+
+        let res = app.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
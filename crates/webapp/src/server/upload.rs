@@ -0,0 +1,588 @@
+//! File uploads into the assets directory
+//!
+//! Two ways to upload are supported, both end up as a file in [UploadConfig::dir], which is
+//! served under `/static`:
+//! - `POST /` with a `multipart/form-data` body holding a `file` field
+//! - tus-style resumable uploads: `POST /resumable` with `Upload-Length` and `Upload-Metadata`
+//!   creates an upload, `PATCH /resumable/{id}` appends chunks at `Upload-Offset`,
+//!   `HEAD /resumable/{id}` reports the offset to resume from and `DELETE` cancels it
+//!
+//! Uploads are written to [UploadConfig::staging_dir] first and only linked into place once complete
+//! and verified, so a half written file is never served. An `Upload-Checksum: sha256 <base64>` header
+//! verifies the multipart file or the PATCH chunk it was sent with.
+//!
+//! Resumable upload state is kept in memory, uploads in progress don't survive a restart. Uploads
+//! idle for longer than [UploadConfig::session_ttl] are dropped along with their staged file, as
+//! are staged files left behind by a previous run.
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, OriginalUri, Path as UrlPath, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::StreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{fs::{self, File, OpenOptions}, io::AsyncWriteExt};
+use super::error::AppError;
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_CHECKSUM: HeaderName = HeaderName::from_static("upload-checksum");
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Directory completed uploads are placed in
+    pub dir: PathBuf,
+    /// Directory uploads are written to until complete, must be on the same filesystem as `dir`
+    pub staging_dir: PathBuf,
+    /// Largest accepted file in bytes
+    pub max_size: u64,
+    /// Accepted content types, checked against both the declared type and the file extension
+    pub allowed_types: Vec<String>,
+    /// How long a resumable upload may go without a PATCH before it is dropped
+    pub session_ttl: Duration,
+}
+
+impl UploadConfig {
+    pub fn new(dir: impl Into<PathBuf>, staging_dir: impl Into<PathBuf>) -> UploadConfig {
+        UploadConfig {
+            dir: dir.into(),
+            staging_dir: staging_dir.into(),
+            max_size: 512 * 1024 * 1024,
+            allowed_types: ["video/mp4", "video/webm", "audio/mpeg", "image/png", "image/jpeg", "image/gif", "image/webp"]
+                .map(String::from)
+                .to_vec(),
+            session_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Resumable upload in progress
+#[derive(Debug)]
+struct Session {
+    file_name: String,
+    length: u64,
+    offset: u64,
+    // a PATCH is writing, concurrent PATCHes would corrupt the offset
+    busy: bool,
+    last_used: Instant,
+}
+
+/// Marks a resumable upload as being written until dropped, also when the PATCH is aborted
+struct Busy {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    id: String,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&self.id) {
+            session.busy = false;
+            session.last_used = Instant::now();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Uploads {
+    config: Arc<UploadConfig>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Uploaded {
+    file_name: String,
+    size: u64,
+    location: String,
+}
+
+impl Uploads {
+    pub fn new(config: UploadConfig) -> Uploads {
+        Uploads { config: Arc::new(config), sessions: Default::default() }
+    }
+
+    /// Upload routes, meant to be nested (`Router::nest("/uploads", uploads.router())`)
+    pub fn router<S>(self) -> Router<S> {
+        // multipart encoding adds a little on top of the file itself
+        let body_limit = usize::try_from(self.config.max_size).unwrap_or(usize::MAX).saturating_add(64 * 1024);
+
+        Router::new()
+            .route("/", post(multipart_upload))
+            .route("/resumable", post(create_resumable))
+            .route("/resumable/:id", axum::routing::head(resumable_offset).patch(append_resumable).delete(cancel_resumable))
+            .layer(DefaultBodyLimit::max(body_limit))
+            .with_state(self)
+    }
+
+    fn staging_path(&self, id: &str) -> PathBuf {
+        self.config.staging_dir.join(format!("{id}.part"))
+    }
+
+    /// Drops resumable uploads idle for longer than the session TTL and deletes staged files no
+    /// upload owns anymore, returns how many files were removed
+    async fn sweep(&self) -> usize {
+        let ttl = self.config.session_ttl;
+        let live: Vec<PathBuf> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|_, session| session.busy || session.last_used.elapsed() < ttl);
+            sessions.keys().map(|id| self.staging_path(id)).collect()
+        };
+
+        let Ok(mut entries) = fs::read_dir(&self.config.staging_dir).await else { return 0 };
+        let mut removed = 0;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "part") || live.contains(&path) {
+                continue;
+            }
+            // multipart uploads stage their file too, only touch files older than any upload could be
+            let stale = entry.metadata().await.ok()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= ttl);
+            if stale && fs::remove_file(&path).await.is_ok() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Validates the file name and content type of a new upload, returns the sanitized file name
+    fn accept(&self, file_name: Option<&str>, content_type: Option<&str>) -> Result<String, AppError> {
+        let file_name = file_name.and_then(sanitize_file_name).ok_or(AppError::RequestPayload)?;
+        let guessed = mime_guess::from_path(&file_name).first_or_octet_stream();
+
+        for content_type in [content_type.unwrap_or(guessed.as_ref()), guessed.as_ref()] {
+            let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+            if !self.config.allowed_types.iter().any(|allowed| allowed.eq_ignore_ascii_case(&essence)) {
+                return Err(AppError::UnsupportedMediaType { content_type: essence });
+            }
+        }
+
+        if self.config.dir.join(&file_name).exists() {
+            return Err(AppError::Conflict { detail: format!("{file_name} already exists") });
+        }
+        Ok(file_name)
+    }
+
+    /// Moves a complete upload into place without replacing an existing file
+    async fn finalize(&self, staging: &Path, file_name: &str) -> Result<Uploaded, AppError> {
+        let target = self.config.dir.join(file_name);
+
+        // linking fails if the target exists, a rename would silently replace it
+        let linked = fs::hard_link(staging, &target).await;
+        let _ = fs::remove_file(staging).await;
+        linked.map_err(|err| match err.kind() {
+            std::io::ErrorKind::AlreadyExists => AppError::Conflict { detail: format!("{file_name} already exists") },
+            _ => io_error(err),
+        })?;
+
+        let size = fs::metadata(&target).await.map_err(io_error)?.len();
+        Ok(Uploaded { location: format!("/static/{file_name}"), file_name: file_name.to_owned(), size })
+    }
+}
+
+fn io_error(err: std::io::Error) -> AppError {
+    AppError::OopsError { err: err.to_string() }
+}
+
+/// Keeps the last path segment and refuses names that would be hidden or need escaping
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'));
+    valid.then(|| name.to_owned())
+}
+
+/// Expected SHA-256 digest of an `Upload-Checksum` header
+fn expected_checksum(headers: &HeaderMap) -> Result<Option<Vec<u8>>, AppError> {
+    let Some(value) = headers.get(UPLOAD_CHECKSUM) else { return Ok(None) };
+    let (algorithm, digest) = value.to_str().ok()
+        .and_then(|v| v.split_once(' '))
+        .ok_or(AppError::RequestPayload)?;
+
+    if !algorithm.eq_ignore_ascii_case("sha256") {
+        return Err(AppError::OopsWithDetails { code: StatusCode::BAD_REQUEST, detail: format!("unsupported checksum algorithm {algorithm}") });
+    }
+    STANDARD.decode(digest.trim()).map(Some).map_err(|_| AppError::RequestPayload)
+}
+
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn tus_headers(offset: u64, length: u64) -> [(HeaderName, HeaderValue); 4] {
+    [
+        (TUS_RESUMABLE, HeaderValue::from_static("1.0.0")),
+        (UPLOAD_OFFSET, HeaderValue::from(offset)),
+        (UPLOAD_LENGTH, HeaderValue::from(length)),
+        (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+    ]
+}
+
+async fn multipart_upload(State(uploads): State<Uploads>, headers: HeaderMap, mut multipart: Multipart) -> Result<Response, AppError> {
+    let checksum = expected_checksum(&headers)?;
+
+    while let Some(mut field) = multipart.next_field().await.map_err(|_| AppError::RequestPayload)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let file_name = uploads.accept(field.file_name(), field.content_type())?;
+        fs::create_dir_all(&uploads.config.staging_dir).await.map_err(io_error)?;
+        let staging = uploads.staging_path(&uuid::Uuid::new_v4().to_string());
+
+        let written = async {
+            let mut file = File::create(&staging).await.map_err(io_error)?;
+            let mut hasher = Sha256::new();
+            let mut size = 0u64;
+
+            while let Some(chunk) = field.chunk().await.map_err(|_| AppError::RequestPayload)? {
+                size += chunk.len() as u64;
+                if size > uploads.config.max_size {
+                    return Err(AppError::PayloadTooLarge { limit: uploads.config.max_size });
+                }
+                hasher.update(&chunk);
+                file.write_all(&chunk).await.map_err(io_error)?;
+            }
+            file.sync_all().await.map_err(io_error)?;
+
+            match checksum {
+                Some(expected) if hasher.finalize().as_slice() != expected => Err(AppError::ChecksumMismatch),
+                _ => Ok(()),
+            }
+        }.await;
+
+        if let Err(err) = written {
+            let _ = fs::remove_file(&staging).await;
+            return Err(err);
+        }
+
+        let uploaded = uploads.finalize(&staging, &file_name).await?;
+        let location = HeaderValue::from_str(&uploaded.location).map_err(|err| AppError::OopsError { err: err.to_string() })?;
+        return Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(uploaded)).into_response());
+    }
+
+    Err(AppError::RequestPayload)
+}
+
+/// Parses tus `Upload-Metadata`: comma separated `key base64(value)` pairs
+fn parse_metadata(headers: &HeaderMap) -> HashMap<String, String> {
+    let Some(metadata) = headers.get(UPLOAD_METADATA).and_then(|v| v.to_str().ok()) else { return HashMap::new() };
+
+    metadata.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next()?.to_owned();
+            let value = parts.next().map_or(Some(Vec::new()), |v| STANDARD.decode(v.trim()).ok())?;
+            Some((key, String::from_utf8(value).ok()?))
+        })
+        .collect()
+}
+
+async fn create_resumable(State(uploads): State<Uploads>, OriginalUri(uri): OriginalUri, headers: HeaderMap) -> Result<Response, AppError> {
+    let length = header_u64(&headers, &UPLOAD_LENGTH).ok_or(AppError::RequestPayload)?;
+    if length > uploads.config.max_size {
+        return Err(AppError::PayloadTooLarge { limit: uploads.config.max_size });
+    }
+
+    let metadata = parse_metadata(&headers);
+    let file_name = uploads.accept(metadata.get("filename").map(String::as_str), metadata.get("filetype").map(String::as_str))?;
+
+    // new uploads are the only way the sessions grow, so abandoned ones are swept here
+    uploads.sweep().await;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    fs::create_dir_all(&uploads.config.staging_dir).await.map_err(io_error)?;
+    File::create(uploads.staging_path(&id)).await.map_err(io_error)?;
+    uploads.sessions.lock().unwrap().insert(id.clone(), Session { file_name, length, offset: 0, busy: false, last_used: Instant::now() });
+
+    let location = HeaderValue::from_str(&format!("{}/{id}", uri.path().trim_end_matches('/')))
+        .map_err(|err| AppError::OopsError { err: err.to_string() })?;
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)], tus_headers(0, length)).into_response())
+}
+
+async fn resumable_offset(State(uploads): State<Uploads>, UrlPath(id): UrlPath<String>) -> Result<Response, AppError> {
+    let sessions = uploads.sessions.lock().unwrap();
+    let session = sessions.get(&id).ok_or(AppError::NotFound)?;
+    Ok((StatusCode::OK, tus_headers(session.offset, session.length)).into_response())
+}
+
+async fn append_resumable(State(uploads): State<Uploads>, UrlPath(id): UrlPath<String>, headers: HeaderMap, body: Body) -> Result<Response, AppError> {
+    if headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) != Some(OFFSET_OCTET_STREAM) {
+        return Err(AppError::UnsupportedMediaType { content_type: OFFSET_OCTET_STREAM.to_owned() });
+    }
+    let offset = header_u64(&headers, &UPLOAD_OFFSET).ok_or(AppError::RequestPayload)?;
+    let checksum = expected_checksum(&headers)?;
+
+    let (file_name, length) = {
+        let mut sessions = uploads.sessions.lock().unwrap();
+        let session = sessions.get_mut(&id).ok_or(AppError::NotFound)?;
+        if session.busy || session.offset != offset {
+            return Err(AppError::Conflict { detail: format!("upload is at offset {}", session.offset) });
+        }
+        session.busy = true;
+        (session.file_name.clone(), session.length)
+    };
+    let busy = Busy { sessions: uploads.sessions.clone(), id: id.clone() };
+
+    let staging = uploads.staging_path(&id);
+    let appended = append_chunk(&staging, body, offset, length, checksum).await;
+
+    let offset = {
+        let mut sessions = uploads.sessions.lock().unwrap();
+        let session = sessions.get_mut(&id).ok_or(AppError::NotFound)?;
+        session.offset = appended?;
+        let offset = session.offset;
+        if offset == length {
+            sessions.remove(&id);
+        }
+        offset
+    };
+    drop(busy);
+
+    if offset < length {
+        return Ok((StatusCode::NO_CONTENT, tus_headers(offset, length)).into_response());
+    }
+
+    uploads.finalize(&staging, &file_name).await?;
+    Ok((StatusCode::NO_CONTENT, tus_headers(length, length)).into_response())
+}
+
+/// Appends a PATCH body to the staged file, returns the new offset.
+///
+/// A rejected chunk is truncated away again, so the client can resend it from the same offset.
+async fn append_chunk(staging: &Path, body: Body, offset: u64, length: u64, checksum: Option<Vec<u8>>) -> Result<u64, AppError> {
+    let file = OpenOptions::new().write(true).open(staging).await.map_err(io_error)?;
+    file.set_len(offset).await.map_err(io_error)?;
+    let mut file = OpenOptions::new().append(true).open(staging).await.map_err(io_error)?;
+
+    let mut hasher = Sha256::new();
+    let mut written = offset;
+    let mut stream = body.into_data_stream();
+
+    let result = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|_| AppError::RequestPayload)?;
+            written += chunk.len() as u64;
+            if written > length {
+                return Err(AppError::PayloadTooLarge { limit: length });
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await.map_err(io_error)?;
+        }
+        file.sync_all().await.map_err(io_error)?;
+
+        match checksum {
+            Some(expected) if hasher.finalize().as_slice() != expected => Err(AppError::ChecksumMismatch),
+            _ => Ok(written),
+        }
+    }.await;
+
+    if result.is_err() {
+        let _ = file.set_len(offset).await;
+    }
+    result
+}
+
+async fn cancel_resumable(State(uploads): State<Uploads>, UrlPath(id): UrlPath<String>) -> Result<Response, AppError> {
+    {
+        let mut sessions = uploads.sessions.lock().unwrap();
+        let session = sessions.get(&id).ok_or(AppError::NotFound)?;
+        // the PATCH in flight still needs the session and its staged file
+        if session.busy {
+            return Err(AppError::Conflict { detail: "upload is being written".into() });
+        }
+        sessions.remove(&id);
+    }
+    let _ = fs::remove_file(uploads.staging_path(&id)).await;
+    Ok((StatusCode::NO_CONTENT, [(TUS_RESUMABLE, HeaderValue::from_static("1.0.0"))]).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Request;
+    use tower::ServiceExt;
+
+    fn uploads(dir: &Path) -> (Uploads, Router) {
+        uploads_with_ttl(dir, Duration::from_secs(60))
+    }
+
+    fn uploads_with_ttl(dir: &Path, session_ttl: Duration) -> (Uploads, Router) {
+        let mut config = UploadConfig::new(dir.join("assets"), dir.join("staging"));
+        config.max_size = 16;
+        config.session_ttl = session_ttl;
+        std::fs::create_dir_all(&config.dir).unwrap();
+        let uploads = Uploads::new(config);
+        (uploads.clone(), Router::new().nest("/uploads", uploads.router()))
+    }
+
+    fn checksum(data: &[u8]) -> String {
+        format!("sha256 {}", STANDARD.encode(Sha256::digest(data)))
+    }
+
+    fn multipart(file_name: &str, content_type: &str, data: &str) -> Request {
+        let body = format!("--x\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\nContent-Type: {content_type}\r\n\r\n{data}\r\n--x--\r\n");
+        Request::post("/uploads")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=x")
+            .header(UPLOAD_CHECKSUM, checksum(data.as_bytes()))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_multipart() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, app) = uploads(dir.path());
+
+        let res = app.clone().oneshot(multipart("clip.mp4", "video/mp4", "0123456789")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()[header::LOCATION], "/static/clip.mp4");
+        assert_eq!(std::fs::read(dir.path().join("assets/clip.mp4")).unwrap(), b"0123456789");
+
+        let res = app.clone().oneshot(multipart("clip.mp4", "video/mp4", "0123456789")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = app.clone().oneshot(multipart("page.html", "video/mp4", "<html>")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let res = app.oneshot(multipart("big.mp4", "video/mp4", "0123456789abcdefg")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.path().join("assets/big.mp4").exists());
+    }
+
+    #[tokio::test]
+    async fn test_resumable() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, app) = uploads(dir.path());
+        let metadata = format!("filename {},filetype {}", STANDARD.encode("song.mp3"), STANDARD.encode("audio/mpeg"));
+
+        let res = app.clone().oneshot(Request::post("/uploads/resumable")
+            .header(UPLOAD_LENGTH, "10")
+            .header(UPLOAD_METADATA, metadata)
+            .body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()[header::LOCATION].to_str().unwrap().to_owned();
+
+        let patch = |offset: &str, data: &'static str, checksum: String| Request::patch(&location)
+            .header(header::CONTENT_TYPE, OFFSET_OCTET_STREAM)
+            .header(UPLOAD_OFFSET, offset)
+            .header(UPLOAD_CHECKSUM, checksum)
+            .body(Body::from(data)).unwrap();
+
+        let res = app.clone().oneshot(patch("0", "01234", checksum(b"01234"))).await.unwrap();
+        assert_eq!(res.headers()[UPLOAD_OFFSET], "5");
+
+        // corrupted chunk is rejected and the offset stays put
+        let res = app.clone().oneshot(patch("5", "56789", checksum(b"other"))).await.unwrap();
+        assert_eq!(res.status().as_u16(), 460);
+        let res = app.clone().oneshot(Request::head(&location).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.headers()[UPLOAD_OFFSET], "5");
+
+        let res = app.clone().oneshot(patch("0", "01234", checksum(b"01234"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = app.clone().oneshot(patch("5", "56789", checksum(b"56789"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read(dir.path().join("assets/song.mp3")).unwrap(), b"0123456789");
+
+        let res = app.oneshot(Request::head(&location).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn create(metadata_name: &str) -> Request {
+        Request::post("/uploads/resumable")
+            .header(UPLOAD_LENGTH, "10")
+            .header(UPLOAD_METADATA, format!("filename {},filetype {}", STANDARD.encode(metadata_name), STANDARD.encode("audio/mpeg")))
+            .body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cancel_while_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (uploads, app) = uploads(dir.path());
+
+        let res = app.clone().oneshot(create("song.mp3")).await.unwrap();
+        let location = res.headers()[header::LOCATION].to_str().unwrap().to_owned();
+        let id = location.rsplit('/').next().unwrap().to_owned();
+
+        // as if a PATCH were in flight
+        uploads.sessions.lock().unwrap().get_mut(&id).unwrap().busy = true;
+        let res = app.clone().oneshot(Request::delete(&location).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert!(uploads.sessions.lock().unwrap().contains_key(&id));
+        assert!(uploads.staging_path(&id).exists());
+
+        uploads.sessions.lock().unwrap().get_mut(&id).unwrap().busy = false;
+        let res = app.oneshot(Request::delete(&location).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!uploads.staging_path(&id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_aborted_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let (uploads, app) = uploads(dir.path());
+
+        let res = app.clone().oneshot(create("song.mp3")).await.unwrap();
+        let location = res.headers()[header::LOCATION].to_str().unwrap().to_owned();
+        let id = location.rsplit('/').next().unwrap().to_owned();
+
+        // the client sends part of the chunk and goes away
+        let body = futures_util::stream::iter([Ok::<_, std::io::Error>(axum::body::Bytes::from_static(b"012"))])
+            .chain(futures_util::stream::pending());
+        let mut patch = Box::pin(app.clone().oneshot(Request::patch(&location)
+            .header(header::CONTENT_TYPE, OFFSET_OCTET_STREAM)
+            .header(UPLOAD_OFFSET, "0")
+            .body(Body::from_stream(body)).unwrap()));
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut patch).await.is_err());
+        assert!(uploads.sessions.lock().unwrap()[&id].busy);
+        drop(patch);
+        assert!(!uploads.sessions.lock().unwrap()[&id].busy);
+
+        let res = app.clone().oneshot(Request::head(&location).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.headers()[UPLOAD_OFFSET], "0");
+
+        let res = app.clone().oneshot(Request::patch(&location)
+            .header(header::CONTENT_TYPE, OFFSET_OCTET_STREAM)
+            .header(UPLOAD_OFFSET, "0")
+            .body(Body::from("01234")).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[UPLOAD_OFFSET], "5");
+        assert_eq!(std::fs::read(uploads.staging_path(&id)).unwrap(), b"01234");
+
+        let res = app.oneshot(Request::delete(&location).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!uploads.staging_path(&id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_abandoned() {
+        let dir = tempfile::tempdir().unwrap();
+        let (uploads, app) = uploads_with_ttl(dir.path(), Duration::ZERO);
+        std::fs::create_dir_all(dir.path().join("staging")).unwrap();
+        std::fs::write(dir.path().join("staging/left-behind.part"), "012").unwrap();
+
+        let res = app.clone().oneshot(create("first.mp3")).await.unwrap();
+        let first = res.headers()[header::LOCATION].to_str().unwrap().to_owned();
+        assert!(!dir.path().join("staging/left-behind.part").exists());
+
+        // the idle first upload is dropped when the next one is created
+        app.clone().oneshot(create("second.mp3")).await.unwrap();
+        let res = app.oneshot(Request::head(&first).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(uploads.sessions.lock().unwrap().len(), 1);
+        assert_eq!(std::fs::read_dir(dir.path().join("staging")).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+//! Template engine in development and production flavours
+//!
+//! [Views] hands out the minijinja [Environment] templates are rendered with:
+//! - [Views::development] loads templates from disk and watches the directory, changed templates are
+//!   picked up by the next render without restarting the server
+//! - [Views::embedded] compiles templates embedded into the binary (`include_dir!`) up front, so a
+//!   syntax error fails startup instead of the first request rendering the broken template
+//!
+//! Filters, functions and globals are registered through a configure callback, it runs again
+//! whenever the development environment is rebuilt.
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock},
+};
+use include_dir::Dir;
+use minijinja::Environment;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+type Configure = dyn Fn(&mut Environment<'static>) + Send + Sync;
+
+/// How templates are loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateMode {
+    /// Read from disk and reloaded on change
+    #[serde(alias = "dev")]
+    Development,
+    /// Embedded into the binary and compiled at startup
+    #[serde(alias = "prod", alias = "production")]
+    Embedded,
+}
+
+impl FromStr for TemplateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dev" | "development" => Ok(TemplateMode::Development),
+            "embedded" | "prod" | "production" => Ok(TemplateMode::Embedded),
+            other => Err(format!("unknown template mode {other:?}, expected development or embedded")),
+        }
+    }
+}
+
+impl Default for TemplateMode {
+    /// Debug builds reload templates, release builds embed them
+    fn default() -> Self {
+        if cfg!(debug_assertions) { TemplateMode::Development } else { TemplateMode::Embedded }
+    }
+}
+
+struct Reloading {
+    dir: PathBuf,
+    configure: Arc<Configure>,
+    env: RwLock<Arc<Environment<'static>>>,
+    // set by the watcher, the environment is rebuilt by the next caller of Views::env
+    stale: Arc<AtomicBool>,
+    _watcher: Mutex<RecommendedWatcher>,
+}
+
+#[derive(Clone)]
+enum Inner {
+    Reloading(Arc<Reloading>),
+    Embedded(Arc<Environment<'static>>),
+}
+
+/// Shared template engine, cheap to clone
+#[derive(Clone)]
+pub struct Views {
+    inner: Inner,
+}
+
+impl Views {
+    /// Loads templates from `dir` and reloads them when anything in it changes
+    pub fn development<F>(dir: impl Into<PathBuf>, configure: F) -> notify::Result<Views>
+    where
+        F: Fn(&mut Environment<'static>) + Send + Sync + 'static,
+    {
+        let dir = dir.into();
+        let configure: Arc<Configure> = Arc::new(configure);
+        let stale = Arc::new(AtomicBool::new(false));
+
+        let flag = stale.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if event.is_ok_and(|event| !event.kind.is_access()) {
+                flag.store(true, Ordering::Release);
+            }
+        })?;
+        watcher.watch(&dir, RecursiveMode::Recursive)?;
+
+        let env = RwLock::new(Arc::new(from_dir(&dir, configure.as_ref())));
+        Ok(Views { inner: Inner::Reloading(Arc::new(Reloading { dir, configure, env, stale, _watcher: Mutex::new(watcher) })) })
+    }
+
+    /// Compiles every template in `dir`, fails on the first template with a syntax error
+    pub fn embedded<F>(dir: &'static Dir<'static>, configure: F) -> Result<Views, minijinja::Error>
+    where
+        F: Fn(&mut Environment<'static>),
+    {
+        let mut env = Environment::new();
+        configure(&mut env);
+
+        let mut pending = vec![dir];
+        while let Some(dir) = pending.pop() {
+            pending.extend(dir.dirs());
+            for file in dir.files() {
+                let name = file.path().to_string_lossy().replace('\\', "/");
+                let source = file.contents_utf8().ok_or_else(|| {
+                    minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, format!("template {name} is not valid UTF-8"))
+                })?;
+                env.add_template_owned(name, source)?;
+            }
+        }
+        Ok(Views { inner: Inner::Embedded(Arc::new(env)) })
+    }
+
+    /// Environment to render with, reflects template changes on disk in development mode
+    pub fn env(&self) -> Arc<Environment<'static>> {
+        match &self.inner {
+            Inner::Embedded(env) => env.clone(),
+            Inner::Reloading(reloading) => {
+                if reloading.stale.swap(false, Ordering::AcqRel) {
+                    let env = Arc::new(from_dir(&reloading.dir, reloading.configure.as_ref()));
+                    *reloading.env.write().unwrap() = env.clone();
+                    return env;
+                }
+                reloading.env.read().unwrap().clone()
+            }
+        }
+    }
+
+    pub fn mode(&self) -> TemplateMode {
+        match self.inner {
+            Inner::Reloading(_) => TemplateMode::Development,
+            Inner::Embedded(_) => TemplateMode::Embedded,
+        }
+    }
+}
+
+fn from_dir(dir: &Path, configure: &Configure) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_loader(minijinja::path_loader(dir));
+    configure(&mut env);
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use include_dir::{DirEntry, File};
+    use minijinja::context;
+
+    fn reloading(views: &Views) -> &Reloading {
+        match &views.inner {
+            Inner::Reloading(reloading) => reloading,
+            Inner::Embedded(_) => panic!("views are embedded"),
+        }
+    }
+
+    #[test]
+    fn test_development_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("page.html"), "v1 {{ name | shout }}").unwrap();
+
+        let views = Views::development(dir.path(), |env| env.add_filter("shout", |s: String| s.to_uppercase())).unwrap();
+        let render = || views.env().get_template("page.html").unwrap().render(context! { name => "hi" }).unwrap();
+        assert_eq!(render(), "v1 HI");
+
+        // marked stale by hand, the watcher is covered by test_development_watch
+        std::fs::write(dir.path().join("page.html"), "v2 {{ name | shout }}").unwrap();
+        reloading(&views).stale.store(true, Ordering::Release);
+        assert_eq!(render(), "v2 HI");
+    }
+
+    #[test]
+    fn test_development_watch() {
+        let dir = tempfile::tempdir().unwrap();
+        let views = Views::development(dir.path(), |_| {}).unwrap();
+        assert!(!reloading(&views).stale.load(Ordering::Acquire));
+
+        // renamed into place so the template is never seen half written
+        std::fs::write(dir.path().join("page.tmp"), "new").unwrap();
+        std::fs::rename(dir.path().join("page.tmp"), dir.path().join("page.html")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !reloading(&views).stale.load(Ordering::Acquire) {
+            assert!(Instant::now() < deadline, "change was not noticed");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_embedded() {
+        static VALID: Dir = Dir::new("", &[
+            DirEntry::File(File::new("base.html", b"<p>{% block body %}{% endblock %}</p>")),
+            DirEntry::Dir(Dir::new("pages", &[DirEntry::File(File::new("pages/home.html", b"{% extends \"base.html\" %}{% block body %}home{% endblock %}"))])),
+        ]);
+        static BROKEN: Dir = Dir::new("", &[DirEntry::File(File::new("broken.html", b"{% if %}"))]);
+
+        let views = Views::embedded(&VALID, |_| {}).unwrap();
+        assert_eq!(views.mode(), TemplateMode::Embedded);
+        assert_eq!(views.env().get_template("pages/home.html").unwrap().render(()).unwrap(), "<p>home</p>");
+
+        let err = Views::embedded(&BROKEN, |_| {}).err().expect("syntax error is reported at startup");
+        assert_eq!(err.kind(), minijinja::ErrorKind::SyntaxError);
+        assert_eq!(err.name(), Some("broken.html"));
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!("Production".parse(), Ok(TemplateMode::Embedded));
+        assert_eq!("dev".parse(), Ok(TemplateMode::Development));
+        assert!("live".parse::<TemplateMode>().is_err());
+    }
+}
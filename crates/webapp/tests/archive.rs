@@ -0,0 +1,58 @@
+use std::{fs, path::Path};
+use webapp::archive::{import_dir_to, ArchiveFormat, ArchiveReader, ArchiveRecord, ArchiveWriter};
+
+fn fixtures_dir() -> &'static Path {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/whois"))
+}
+
+async fn import(format: ArchiveFormat) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut writer = ArchiveWriter::new(&mut out, format);
+    let report = import_dir_to(fixtures_dir(), 2, &mut writer).await.unwrap();
+    drop(writer);
+
+    assert_eq!(report.imported, fs::read_dir(fixtures_dir()).unwrap().count());
+    assert!(report.failures.is_empty());
+    out
+}
+
+fn sorted(records: impl Iterator<Item = ArchiveRecord>) -> Vec<String> {
+    let mut records: Vec<_> = records.map(|r| format!("{}{:?}{}", r.source, r.information, r.raw)).collect();
+    records.sort();
+    records
+}
+
+#[tokio::test]
+async fn test_round_trip() {
+    let jsonl = import(ArchiveFormat::JsonLines).await;
+    let csv = import(ArchiveFormat::Csv).await;
+
+    let from_jsonl = sorted(ArchiveReader::new(jsonl.as_slice(), ArchiveFormat::JsonLines).map(Result::unwrap));
+    let from_csv = sorted(ArchiveReader::new(csv.as_slice(), ArchiveFormat::Csv).map(Result::unwrap));
+
+    assert_eq!(from_jsonl.len(), fs::read_dir(fixtures_dir()).unwrap().count());
+    assert_eq!(from_jsonl, from_csv);
+}
+
+#[tokio::test]
+async fn test_import_failures() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("good.txt"), "Domain Name: SIMPAIX.NET\n").unwrap();
+    // values that don't convert are skipped, but the file has to be text
+    fs::write(dir.path().join("bad.txt"), b"Domain Name: \xff\xfe\n").unwrap();
+    fs::write(dir.path().join("ignored.json"), "{}").unwrap();
+
+    let mut writer = ArchiveWriter::new(Vec::new(), ArchiveFormat::JsonLines);
+    let report = import_dir_to(dir.path(), 4, &mut writer).await.unwrap();
+
+    assert_eq!(report.imported, 1);
+    assert_eq!(report.failures.len(), 1);
+    assert!(report.failures[0].path.ends_with("bad.txt"));
+}
+
+#[test]
+fn test_format_from_path() {
+    assert_eq!(ArchiveFormat::from_path(Path::new("dump.jsonl")).unwrap(), ArchiveFormat::JsonLines);
+    assert_eq!(ArchiveFormat::from_path(Path::new("dump.csv")).unwrap(), ArchiveFormat::Csv);
+    assert!(ArchiveFormat::from_path(Path::new("dump.txt")).is_err());
+}
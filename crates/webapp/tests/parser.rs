@@ -0,0 +1,188 @@
+//! Property tests and registry fixtures for the WHOIS parser.
+//!
+//! Fixtures under `tests/fixtures/whois` are real registry responses with anonymized contact data.
+use std::{fs, path::PathBuf};
+use proptest::prelude::*;
+use webapp::parser::{merge::{MergePolicy, WhoisSource}, normalize::normalize, Parser, StreamParser, WhoisInformation};
+
+fn fixtures() -> Vec<(String, String)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/whois");
+    let mut fixtures: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read_to_string(&path).unwrap()))
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+fn fixture(name: &str) -> WhoisInformation {
+    let (_, raw) = fixtures().into_iter().find(|(n, _)| n == name).unwrap();
+    Parser::new().parse(raw).unwrap()
+}
+
+// total bytes of all string fields, used to assert the parser never allocates more than it was given
+fn string_bytes(info: &WhoisInformation) -> usize {
+    [
+        &info.domain_name, &info.registry_domain_id, &info.registrar_whois_server, &info.registrar_url,
+        &info.registrar, &info.registrar_iana_id, &info.registrar_abuse_email_contact,
+        &info.registrar_abuse_phone_contact, &info.registrant_organization,
+    ].iter().map(|v| v.as_ref().map_or(0, String::len)).sum::<usize>()
+        + info.domain_status.iter().flatten().map(String::len).sum::<usize>()
+        + info.name_servers.iter().flat_map(|ns| ns.hosts()).map(str::len).sum::<usize>()
+}
+
+#[test]
+fn test_fixtures_parse() {
+    for (name, raw) in fixtures() {
+        let info = Parser::new().parse(raw.clone()).unwrap_or_else(|e| panic!("{name}: {e}"));
+        let normalized = Parser::new().parse(normalize(&raw).body).unwrap_or_else(|e| panic!("{name}: {e}"));
+
+        // boilerplate never carries record data
+        assert_eq!(format!("{info:?}"), format!("{normalized:?}"), "{name}");
+    }
+}
+
+#[test]
+fn test_fixture_verisign() {
+    let info = fixture("verisign_thin.txt");
+    assert_eq!(info.domain_name.as_deref(), Some("EXAMPLE-SHOP.NET"));
+    assert_eq!(info.registrar_iana_id.as_deref(), Some("1068"));
+    assert_eq!(info.registry_expirity_date.unwrap().to_rfc3339(), "2026-07-14T17:02:11+00:00");
+    assert_eq!(info.dnssec.unwrap().signed, Some(false));
+}
+
+#[test]
+fn test_fixture_dnssec() {
+    let dnssec = fixture("pir_org.txt").dnssec.unwrap();
+    assert_eq!(dnssec.signed, Some(true));
+    assert_eq!(dnssec.ds_records[0].key_tag, 31589);
+
+    let iana = fixture("iana_referral.txt");
+    assert_eq!(iana.dnssec.unwrap().ds_records[0].algorithm, 13);
+
+    let name_servers = iana.name_servers.unwrap();
+    assert_eq!(name_servers.hosts().collect::<Vec<_>>(), ["a.gtld-servers.net", "b.gtld-servers.net"]);
+    assert_eq!(name_servers[0].glue.len(), 2);
+}
+
+// a valid record with an empty and a vendor formatted name server line and a broken DS record
+#[test]
+fn test_fixture_broken_name_server() {
+    let info = fixture("broken_name_server.txt");
+    assert_eq!(info.domain_name.as_deref(), Some("EXAMPLE-TOOLS.ORG"));
+    assert_eq!(info.name_servers.unwrap().hosts().collect::<Vec<_>>(), ["ns1.example-dns.org", "ns2.example-dns.org", "ns3.example-dns.org"]);
+
+    let dnssec = info.dnssec.unwrap();
+    assert_eq!(dnssec.signed, Some(true));
+    assert_eq!(dnssec.ds_records.len(), 1);
+}
+
+#[test]
+fn test_fixture_registrar() {
+    let info = fixture("registrar_thick.txt");
+    assert_eq!(info.registrar.as_deref(), Some("Example Registrar, LLC"));
+    assert_eq!(info.name_servers.as_ref().map(|ns| ns.len()), Some(2));
+    assert!(info.creation_date.is_some());
+    assert_eq!(info.registry_expirity_date, fixture("verisign_thin.txt").registry_expirity_date, "the registrar's name for the expiry");
+    assert!(info.uses_privacy_proxy(&webapp::parser::risk::RiskConfig::default().privacy_proxy_keywords));
+}
+
+#[test]
+fn test_fixture_merge() {
+    let merged = MergePolicy::default().merge(&[
+        WhoisSource::new("whois.verisign-grs.com", fixture("verisign_thin.txt")),
+        WhoisSource::new("whois.registrar.example", fixture("registrar_thick.txt")),
+    ]);
+
+    assert_eq!(merged.information.domain_name.as_deref(), Some("EXAMPLE-SHOP.NET"));
+    assert_eq!(merged.information.registrant_organization.as_deref(), Some("Domains By Proxy, LLC"));
+    assert_eq!(merged.provenance["registry_expirity_date"], "whois.verisign-grs.com");
+
+    // http vs https, and the registrar lists an extra status last
+    let conflicts: Vec<_> = merged.conflicts.iter().map(|c| c.field).collect();
+    assert_eq!(conflicts, ["registrar_url", "domain_status"]);
+}
+
+#[test]
+fn test_fixture_notice() {
+    let (_, raw) = fixtures().into_iter().find(|(n, _)| n == "cctld_comments.txt").unwrap();
+    let normalized = normalize(&raw);
+    assert!(normalized.notice.starts_with("Copyright (c) 2024"));
+    assert!(normalized.body.starts_with("Domain: example.de"));
+}
+
+#[test]
+fn test_stream_line_limit() {
+    let mut stream = StreamParser::new();
+    let line = vec![b'a'; StreamParser::MAX_LINE_LEN];
+    stream.feed(&line).unwrap();
+    assert!(stream.feed(b"a").is_err());
+}
+
+// whois-like lines: known and unknown keys, URLs, comments and trailers
+fn whois_line() -> impl Strategy<Value = String> {
+    prop_oneof![
+        ("(Domain Name|Registrar|Name Server|DNSSEC|Domain Status|[A-Za-z ]{1,20})", "[ -~]{0,40}")
+            .prop_map(|(k, v)| format!("{k}: {v}")),
+        "[%#][ -~]{0,40}",
+        "(For more information, visit https://icann\\.org/epp|NOTICE: [ -~]{0,40})",
+        Just(">>> Last update of WHOIS database: 2024-05-01T12:34:56Z <<<".to_owned()),
+        "\\PC{0,40}",
+    ]
+}
+
+fn whois_response() -> impl Strategy<Value = String> {
+    (prop::collection::vec(whois_line(), 0..40), prop::bool::ANY)
+        .prop_map(|(lines, crlf)| lines.join(if crlf { "\r\n" } else { "\n" }))
+}
+
+proptest! {
+    #[test]
+    fn prop_parse_never_panics(raw in "\\PC*") {
+        let _ = Parser::new().parse(raw.clone());
+        let _ = Parser::new().parse_borrowed(&raw);
+        let _ = normalize(&raw);
+    }
+
+    #[test]
+    fn prop_stream_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..2048), chunk in 1usize..64) {
+        let mut stream = StreamParser::new();
+        let fed: Result<(), _> = bytes.chunks(chunk).try_for_each(|c| stream.feed(c));
+        if fed.is_ok() {
+            let _ = stream.finish();
+        }
+    }
+
+    #[test]
+    fn prop_stream_matches_parse(raw in whois_response(), chunk in 1usize..64) {
+        let parsed = Parser::new().parse(raw.clone());
+
+        let mut stream = StreamParser::new();
+        let streamed = raw.as_bytes().chunks(chunk)
+            .try_for_each(|c| stream.feed(c))
+            .and_then(|_| stream.finish());
+
+        match (parsed, streamed) {
+            (Ok(parsed), Ok(streamed)) => prop_assert_eq!(format!("{parsed:?}"), format!("{streamed:?}")),
+            (parsed, streamed) => prop_assert_eq!(parsed.is_ok(), streamed.is_ok()),
+        }
+    }
+
+    #[test]
+    fn prop_parse_bounded(raw in whois_response()) {
+        if let Ok(info) = Parser::new().parse(raw.clone()) {
+            prop_assert!(string_bytes(&info) <= raw.len());
+        }
+    }
+
+    #[test]
+    fn prop_normalize_stable(raw in whois_response()) {
+        let normalized = normalize(&raw);
+        prop_assert!(normalized.body.len() + normalized.notice.len() <= raw.len());
+        prop_assert!(!normalized.body.contains('\r'));
+
+        let again = normalize(&normalized.body);
+        prop_assert_eq!(&again.body, &normalized.body);
+        prop_assert!(again.notice.is_empty());
+    }
+}